@@ -0,0 +1,125 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use synacor::util::get_file_as_byte_vec;
+use synacor::vm::Vm;
+
+const R0: u16 = 32768;
+const R1: u16 = 32769;
+const R2: u16 = 32770;
+const R3: u16 = 32771;
+const R7: u16 = 32775;
+const DEC: u16 = 32767; // Adding this subtracts one
+
+fn program(words: &[u16]) -> Vec<u8> {
+    words.iter().flat_map(|w| vec![(w & 0xff) as u8, (w >> 8) as u8]).collect()
+}
+
+/// Tight ADD/MULT/MOD loop counting R0 down from `n`.
+fn arithmetic(n: u16) -> Vec<u8> {
+    program(&[
+        1, R0, n,          // 0:  set R0 n
+        9, R1, R1, R0,     // 3:  add R1 R1 R0
+        10, R2, R1, 3,     // 7:  mult R2 R1 3
+        11, R3, R2, 7,     // 11: mod R3 R2 7
+        9, R0, R0, DEC,    // 15: add R0 R0 -1
+        7, R0, 3,          // 19: jt R0 3
+        0,                 // 22: halt
+    ])
+}
+
+/// The game's teleporter check: a modified Ackermann function in R0/R1/R7.
+fn recursion(m: u16, n: u16, r7: u16) -> Vec<u8> {
+    program(&[
+        1, R0, m,          // 0:  set R0 m
+        1, R1, n,          // 3:  set R1 n
+        1, R7, r7,         // 6:  set R7 r7
+        17, 12,            // 9:  call 12
+        0,                 // 11: halt
+        7, R0, 20,         // 12: jt R0 20
+        9, R0, R1, 1,      // 15: add R0 R1 1
+        18,                // 19: ret
+        7, R1, 33,         // 20: jt R1 33
+        9, R0, R0, DEC,    // 23: add R0 R0 -1
+        1, R1, R7,         // 27: set R1 R7
+        17, 12,            // 30: call 12
+        18,                // 32: ret
+        2, R0,             // 33: push R0
+        9, R1, R1, DEC,    // 35: add R1 R1 -1
+        17, 12,            // 39: call 12
+        1, R1, R0,         // 41: set R1 R0
+        3, R0,             // 44: pop R0
+        9, R0, R0, DEC,    // 46: add R0 R0 -1
+        17, 12,            // 50: call 12
+        18,                // 52: ret
+    ])
+}
+
+/// Fills `n` words above address 1000 with WMEM, then sums them with RMEM.
+fn memory(n: u16) -> Vec<u8> {
+    program(&[
+        1, R0, 0,          // 0:  set R0 0
+        9, R1, R0, 1000,   // 3:  add R1 R0 1000
+        16, R1, R0,        // 7:  wmem R1 R0
+        9, R0, R0, 1,      // 10: add R0 R0 1
+        4, R2, R0, n,      // 14: eq R2 R0 n
+        8, R2, 3,          // 18: jf R2 3
+        1, R0, 0,          // 21: set R0 0
+        1, R3, 0,          // 24: set R3 0
+        9, R1, R0, 1000,   // 27: add R1 R0 1000
+        15, R2, R1,        // 31: rmem R2 R1
+        9, R3, R3, R2,     // 34: add R3 R3 R2
+        9, R0, R0, 1,      // 38: add R0 R0 1
+        4, R2, R0, n,      // 42: eq R2 R0 n
+        8, R2, 27,         // 46: jf R2 27
+        0,                 // 49: halt
+    ])
+}
+
+/// Runs until the VM halts or waits for input, returning the step count.
+fn count_steps(vm: &mut Vm) -> u64 {
+    let mut steps = 0;
+    vm.reset();
+    while !vm.is_stopped() && !vm.needs_input() {
+        vm.step();
+        steps += 1;
+    }
+    steps
+}
+
+/// Times `code` from a reset VM with every dispatcher.  `execute_once`
+/// decodes each instruction afresh, as the VM did before the decode cache,
+/// so it's the baseline `decoded` is compared against; the cache is meant
+/// to manage 100M+ instructions a second.
+fn bench_workload(c: &mut Criterion, name: &str, code: Vec<u8>) {
+    let mut vm = Vm::new(code, 32768);
+    vm.set_capture_output(true);
+    let steps = count_steps(&mut vm);
+
+    let mut group = c.benchmark_group(name);
+    group.throughput(Throughput::Elements(steps));
+    group.bench_function(BenchmarkId::new("execute_once", steps), |b| b.iter(|| {
+        vm.reset();
+        while !vm.is_stopped() && !vm.needs_input() {
+            vm.execute_once();
+        }
+    }));
+    group.bench_function(BenchmarkId::new("decoded", steps), |b| b.iter(|| {
+        vm.reset();
+        vm.run_decoded(usize::MAX);
+    }));
+    #[cfg(feature = "superblocks")]
+    group.bench_function(BenchmarkId::new("superblocks", steps), |b| b.iter(|| {
+        vm.reset();
+        vm.run_superblocks(usize::MAX);
+    }));
+    group.finish();
+}
+
+fn bench_vm(c: &mut Criterion) {
+    bench_workload(c, "arithmetic", arithmetic(20000));
+    bench_workload(c, "recursion", recursion(3, 3, 1));
+    bench_workload(c, "memory", memory(8192));
+    bench_workload(c, "self_test", get_file_as_byte_vec("challenge.bin"));
+}
+
+criterion_group!(benches, bench_vm);
+criterion_main!(benches);
@@ -0,0 +1,22 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use synacor::vm::{ExecutionPolicy, Vm, MAX_VAL};
+
+// Runs the bytes after the first as a program under the policy the first
+// picks, stopping once it halts or waits on IN with nothing to read.
+fuzz_target!(|data: &[u8]| {
+    let (policy, program) = match data.split_first() {
+        Some((p, program)) => ([ExecutionPolicy::Strict, ExecutionPolicy::Lenient, ExecutionPolicy::Compat][*p as usize % 3], program),
+        None => return,
+    };
+    let mut vm = Vm::new(program.to_vec(), MAX_VAL);
+    vm.set_policy(policy);
+    vm.set_capture_output(true);
+    vm.insert_buffer("take tablet\nuse tablet\n".to_string());
+    for _ in 0..10_000 {
+        if vm.is_stopped() || vm.needs_input() {
+            break;
+        }
+        vm.execute_once();
+    }
+});
@@ -0,0 +1,22 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use synacor::vm::{Instruction, MAX_VAL};
+
+// Parses from every address of the bytes as words, checking `parse` and
+// `at` agree and that whatever is valid survives Display and FromStr.
+fuzz_target!(|data: &[u8]| {
+    let words: Vec<u16> = data.chunks(2).map(|b| b[0] as u16 | (*b.get(1).unwrap_or(&0) as u16) << 8).collect();
+    let mut padded = words.clone();
+    padded.extend(&[0, 0, 0]); // `parse` reads past short instructions
+    for pc in 0..words.len() {
+        let parsed = Instruction::parse(&padded, pc).ok();
+        let at = Instruction::at(&words, pc);
+        match at {
+            Some(i) => assert_eq!(parsed, Some(i)),
+            None => assert!(parsed.is_none_or(|i| pc + i.operator.size() > words.len())),
+        }
+        if let Some(i) = at.filter(|i| i.words()[1..].iter().all(|&w| (w as usize) < MAX_VAL + 8)) {
+            assert_eq!(i.to_string().parse::<Instruction>(), Ok(i));
+        }
+    }
+});
@@ -0,0 +1,159 @@
+use log::{trace, debug, info, warn, error};
+use super::{Cfg, EdgeKind, Target};
+use crate::vm::InstructionCode;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+#[allow(dead_code)]
+fn _get_rid_of_log_unused_import_warnings() {
+    trace!("Example trace.");
+    debug!("Example debug.");
+    info!("Example info.");
+    warn!("Example warn.");
+    error!("Example error.");
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FunctionInfo {
+    pub entry: usize,
+    pub end: usize,  // One past the highest word of any block
+    pub size: usize, // Words of code, not counting gaps between blocks
+    pub returns: bool,
+    pub callers: Vec<usize>,
+    pub callees: Vec<usize>,
+    pub indirect_calls: usize, // CALLs through a register
+    pub recursive: bool,       // Can reach itself through callees
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CallGraph {
+    pub functions: Vec<FunctionInfo>,
+}
+
+impl CallGraph {
+    pub fn new(cfg: &Cfg) -> CallGraph {
+        let mut callees: BTreeMap<usize, BTreeSet<usize>> = BTreeMap::new();
+        let mut callers: BTreeMap<usize, BTreeSet<usize>> = BTreeMap::new();
+        let mut functions = Vec::new();
+
+        for f in &cfg.functions {
+            let mut indirect_calls = 0;
+            for &start in &f.blocks {
+                for e in cfg.successors(start).filter(|e| e.kind == EdgeKind::Call) {
+                    match e.to {
+                        Target::Address(to) => {
+                            callees.entry(f.entry).or_default().insert(to);
+                            callers.entry(to).or_default().insert(f.entry);
+                        },
+                        Target::Unknown => indirect_calls += 1,
+                    }
+                }
+            }
+            let blocks = f.blocks.iter().map(|s| &cfg.blocks[s]);
+            functions.push(FunctionInfo {
+                entry: f.entry,
+                end: blocks.clone().map(|b| b.end).max().unwrap_or(f.entry),
+                size: blocks.clone().map(|b| b.end - b.start).sum(),
+                returns: blocks.clone().any(|b| b.last().operator == InstructionCode::RET),
+                callers: Vec::new(),
+                callees: Vec::new(),
+                indirect_calls,
+                recursive: false,
+            });
+        }
+
+        for info in functions.iter_mut() {
+            info.callers = callers.get(&info.entry).map(|s| s.iter().cloned().collect()).unwrap_or_default();
+            info.callees = callees.get(&info.entry).map(|s| s.iter().cloned().collect()).unwrap_or_default();
+            info.recursive = reaches(&callees, info.entry, info.entry);
+        }
+        debug!("Call graph has {} functions, {} recursive.",
+               functions.len(), functions.iter().filter(|f| f.recursive).count());
+        CallGraph { functions }
+    }
+
+    pub fn function(&self, entry: usize) -> Option<&FunctionInfo> {
+        self.functions.iter().find(|f| f.entry == entry)
+    }
+
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::new();
+        writeln!(dot, "digraph calls {{").unwrap();
+        writeln!(dot, "    node [shape=box, fontname=monospace];").unwrap();
+        for f in &self.functions {
+            let style = if f.recursive { ", color=red" } else { "" };
+            writeln!(dot, "    f{} [label=\"fn_{}\\n{} words\"{}];", f.entry, f.entry, f.size, style).unwrap();
+        }
+        for f in &self.functions {
+            for callee in &f.callees {
+                writeln!(dot, "    f{} -> f{};", f.entry, callee).unwrap();
+            }
+        }
+        writeln!(dot, "}}").unwrap();
+        dot
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+}
+
+/// Whether `to` is reachable from `from` by following at least one call.
+fn reaches(callees: &BTreeMap<usize, BTreeSet<usize>>, from: usize, to: usize) -> bool {
+    let mut seen = BTreeSet::new();
+    let mut todo: Vec<usize> = callees.get(&from).into_iter().flatten().cloned().collect();
+    while let Some(f) = todo.pop() {
+        if f == to {
+            return true;
+        }
+        if seen.insert(f) {
+            todo.extend(callees.get(&f).into_iter().flatten());
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::get_file_as_byte_vec;
+    use crate::vm::Vm;
+
+    #[test]
+    fn test_call_graph() {
+        let code = vec![
+            17, 5,         // 0: call 5
+            17, 32768,     // 2: call R0
+            0,             // 4: halt
+            8, 32768, 11,  // 5: jf R0 11
+            17, 5,         // 8: call 5
+            18,            // 10: ret
+            17, 14,        // 11: call 14
+            18,            // 13: ret
+            18,            // 14: ret
+        ];
+
+        let graph = CallGraph::new(&Cfg::build(&code));
+
+        let main = graph.function(0).unwrap();
+        assert_eq!((main.callees.clone(), main.indirect_calls, main.recursive, main.returns), (vec![5], 1, false, false));
+        let f = graph.function(5).unwrap();
+        assert_eq!((f.callers.clone(), f.callees.clone(), f.recursive), (vec![0, 5], vec![5, 14], true));
+        assert_eq!((f.end, f.size), (14, 9));
+        assert_eq!(graph.function(14).unwrap().callers, vec![5]);
+        assert!(graph.to_dot().contains("f5 -> f5;"));
+    }
+
+    #[test]
+    fn test_teleporter_is_recursive() {
+        // The check only exists in memory once the self-test has decrypted it
+        let mut vm = Vm::new(get_file_as_byte_vec("challenge.bin"), 32768);
+        vm.set_capture_output(true);
+        vm.run_until_input(10_000_000);
+
+        let graph = CallGraph::new(&Cfg::build(vm.memory()));
+
+        assert!(graph.function(6027).unwrap().recursive);
+    }
+}
@@ -0,0 +1,329 @@
+use log::{trace, debug, info, warn, error};
+use crate::vm::{Instruction, InstructionCode, MAX_VAL};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+pub mod callgraph;
+
+#[allow(dead_code)]
+fn _get_rid_of_log_unused_import_warnings() {
+    trace!("Example trace.");
+    debug!("Example debug.");
+    info!("Example info.");
+    warn!("Example warn.");
+    error!("Example error.");
+}
+
+/// A straight run of instructions entered only at `start`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BasicBlock {
+    pub start: usize,
+    pub end: usize, // One past the last word of the last instruction
+    pub instructions: Vec<(usize, Instruction)>,
+}
+
+impl BasicBlock {
+    pub fn last(&self) -> &Instruction {
+        &self.instructions.last().unwrap().1
+    }
+}
+
+/// Returns the literal address an operand names, or None for a register.
+pub fn literal(operand: u16) -> Option<usize> {
+    if (operand as usize) < MAX_VAL {
+        Some(operand as usize)
+    } else {
+        None
+    }
+}
+
+/// Whether `op` transfers control, so nothing after it shares its block.
+pub fn ends_block(op: InstructionCode) -> bool {
+    matches!(op,
+        InstructionCode::HALT | InstructionCode::JMP | InstructionCode::JT |
+        InstructionCode::JF | InstructionCode::CALL | InstructionCode::RET)
+}
+
+/// Whether execution can continue with the next instruction in memory.
+pub fn falls_through(op: InstructionCode) -> bool {
+    !matches!(op, InstructionCode::HALT | InstructionCode::JMP | InstructionCode::RET)
+}
+
+/// Literal jump or call target of `i`, if it has one.
+pub fn branch_target(i: &Instruction) -> Option<usize> {
+    match i.operator {
+        InstructionCode::JMP | InstructionCode::CALL => literal(i.operands.0),
+        InstructionCode::JT | InstructionCode::JF => literal(i.operands.1),
+        _ => None,
+    }
+}
+
+/// Literal CALL targets found by decoding memory front to back the way the
+/// disassembler does, plus address 0.
+pub fn call_targets(memory: &[u16]) -> Vec<usize> {
+    let mut targets = vec![0];
+    let mut pc = 0;
+    while pc < memory.len() {
+        match Instruction::at(memory, pc) {
+            Some(i) => {
+                if let (InstructionCode::CALL, Some(t)) = (i.operator, literal(i.operands.0)) {
+                    targets.push(t);
+                }
+                pc += i.operator.size();
+            },
+            None => pc += 1,
+        }
+    }
+    targets.sort_unstable();
+    targets.dedup();
+    targets
+}
+
+/// Splits every instruction reachable from `entries` into basic blocks,
+/// sorted by start address.  Register-indirect jumps are not followed.
+pub fn find_blocks(memory: &[u16], entries: &[usize]) -> Vec<BasicBlock> {
+    let mut leaders: BTreeSet<usize> = entries.iter().cloned().collect();
+    let mut reached: BTreeSet<usize> = BTreeSet::new();
+    let mut todo: Vec<usize> = entries.to_vec();
+
+    while let Some(pc) = todo.pop() {
+        if pc >= memory.len() || !reached.insert(pc) {
+            continue;
+        }
+        let i = match Instruction::at(memory, pc) {
+            Some(i) => i,
+            None => continue,
+        };
+        if let Some(target) = branch_target(&i) {
+            leaders.insert(target);
+            todo.push(target);
+        }
+        if falls_through(i.operator) {
+            let next = pc + i.operator.size();
+            if ends_block(i.operator) {
+                leaders.insert(next);
+            }
+            todo.push(next);
+        }
+    }
+
+    let mut blocks = Vec::new();
+    for &start in leaders.iter().filter(|l| reached.contains(l)) {
+        let mut block = BasicBlock {
+            start,
+            end: start,
+            instructions: Vec::new(),
+        };
+        let mut pc = start;
+        while let Some(i) = Instruction::at(memory, pc) {
+            block.instructions.push((pc, i));
+            pc += i.operator.size();
+            if ends_block(i.operator) || leaders.contains(&pc) {
+                break;
+            }
+        }
+        block.end = pc;
+        if !block.instructions.is_empty() {
+            blocks.push(block);
+        }
+    }
+    debug!("Found {} basic blocks.", blocks.len());
+    blocks
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Target {
+    Address(usize),
+    Unknown, // Jump or call through a register
+}
+
+impl Target {
+    fn of(operand: u16) -> Target {
+        match literal(operand) {
+            Some(addr) => Target::Address(addr),
+            None => Target::Unknown,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EdgeKind {
+    Jump,
+    Taken,       // JT/JF condition held
+    Fallthrough, // Includes JT/JF not taken and returning from a CALL
+    Call,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Edge {
+    pub from: usize, // Block start
+    pub to: Target,
+    pub kind: EdgeKind,
+}
+
+/// A CALL target (or the program entry) and the blocks reachable from it
+/// without following calls.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Function {
+    pub entry: usize,
+    pub blocks: Vec<usize>, // Block starts, sorted
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Cfg {
+    pub blocks: BTreeMap<usize, BasicBlock>,
+    pub edges: Vec<Edge>,
+    pub functions: Vec<Function>,
+}
+
+fn block_edges(block: &BasicBlock) -> Vec<Edge> {
+    let i = block.last();
+    let edge = |to, kind| Edge { from: block.start, to, kind };
+    let fallthrough = edge(Target::Address(block.end), EdgeKind::Fallthrough);
+    match i.operator {
+        InstructionCode::JMP => vec![edge(Target::of(i.operands.0), EdgeKind::Jump)],
+        InstructionCode::JT | InstructionCode::JF => vec![edge(Target::of(i.operands.1), EdgeKind::Taken), fallthrough],
+        InstructionCode::CALL => vec![edge(Target::of(i.operands.0), EdgeKind::Call), fallthrough],
+        InstructionCode::RET | InstructionCode::HALT => vec![],
+        _ => vec![fallthrough],
+    }
+}
+
+impl Cfg {
+    /// Analyses `memory` starting from address 0 and every literal CALL target.
+    pub fn build(memory: &[u16]) -> Cfg {
+        let entries = call_targets(memory);
+        let blocks: BTreeMap<usize, BasicBlock> = find_blocks(memory, &entries).into_iter()
+            .map(|b| (b.start, b))
+            .collect();
+        let edges: Vec<Edge> = blocks.values().flat_map(block_edges).collect();
+
+        let mut cfg = Cfg {
+            blocks,
+            edges,
+            functions: Vec::new(),
+        };
+        cfg.functions = entries.iter()
+            .filter(|e| cfg.blocks.contains_key(e))
+            .map(|&entry| Function { entry, blocks: cfg.body(entry) })
+            .collect();
+        debug!("Found {} functions.", cfg.functions.len());
+        cfg
+    }
+
+    pub fn successors(&self, start: usize) -> impl Iterator<Item = &Edge> {
+        self.edges.iter().filter(move |e| e.from == start)
+    }
+
+    fn body(&self, entry: usize) -> Vec<usize> {
+        let mut seen = BTreeSet::new();
+        let mut todo = vec![entry];
+        while let Some(start) = todo.pop() {
+            if !self.blocks.contains_key(&start) || !seen.insert(start) {
+                continue;
+            }
+            for e in self.successors(start).filter(|e| e.kind != EdgeKind::Call) {
+                if let Target::Address(to) = e.to {
+                    todo.push(to);
+                }
+            }
+        }
+        seen.into_iter().collect()
+    }
+
+    pub fn function(&self, entry: usize) -> Option<&Function> {
+        self.functions.iter().find(|f| f.entry == entry)
+    }
+
+    /// Graphviz source for one function.  Calls and register-indirect
+    /// targets are drawn as separate ellipses.
+    pub fn function_dot(&self, f: &Function) -> String {
+        let mut dot = String::new();
+        writeln!(dot, "digraph fn_{} {{", f.entry).unwrap();
+        writeln!(dot, "    node [shape=box, fontname=monospace];").unwrap();
+        for start in &f.blocks {
+            let block = &self.blocks[start];
+            let mut label = String::new();
+            for (pc, i) in &block.instructions {
+                write!(label, "{}: {}\\l", pc, i).unwrap();
+            }
+            writeln!(dot, "    b{} [label=\"{}\"];", start, label.replace('"', "\\\"")).unwrap();
+            for e in self.successors(*start) {
+                let to = match (e.kind, e.to) {
+                    (EdgeKind::Call, Target::Address(a)) => {
+                        writeln!(dot, "    call{}_{} [shape=ellipse, label=\"call {}\"];", start, a, a).unwrap();
+                        format!("call{}_{}", start, a)
+                    },
+                    (_, Target::Address(a)) => format!("b{}", a),
+                    (_, Target::Unknown) => {
+                        writeln!(dot, "    unknown{} [shape=ellipse, style=dashed, label=\"?\"];", start).unwrap();
+                        format!("unknown{}", start)
+                    },
+                };
+                let style = match e.kind {
+                    EdgeKind::Jump => "",
+                    EdgeKind::Taken => " [color=green]",
+                    EdgeKind::Fallthrough => " [color=gray]",
+                    EdgeKind::Call => " [style=dashed]",
+                };
+                writeln!(dot, "    b{} -> {}{};", start, to, style).unwrap();
+            }
+        }
+        writeln!(dot, "}}").unwrap();
+        dot
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_blocks() {
+        let code = vec![
+            1, 32768, 3,   // 0:  set R0 3
+            9, 32768, 32768, 32767, // 3: add R0 R0 -1
+            7, 32768, 3,   // 7:  jt R0 3
+            17, 14,        // 10: call 14
+            0,             // 12: halt
+            99,            // 13: data
+            19, 65,        // 14: out 'A'
+            18,            // 16: ret
+        ];
+
+        let blocks = find_blocks(&code, &[0]);
+        let spans: Vec<(usize, usize)> = blocks.iter().map(|b| (b.start, b.end)).collect();
+
+        assert_eq!(spans, vec![(0, 3), (3, 10), (10, 12), (12, 13), (14, 17)]);
+        assert_eq!(blocks[1].last().operator, InstructionCode::JT);
+
+        // Instructions cut off by the end of memory aren't decoded
+        assert!(find_blocks(&[9, 32768], &[0]).is_empty());
+        assert_eq!(call_targets(&[21, 17]), vec![0]);
+    }
+
+    #[test]
+    fn test_cfg() {
+        let code = vec![
+            17, 5,         // 0: call 5
+            6, 32768,      // 2: jmp R0
+            0,             // 4: halt
+            8, 32768, 9,   // 5: jf R0 9
+            18,            // 8: ret
+            19, 65,        // 9: out 'A'
+            18,            // 11: ret
+        ];
+
+        let cfg = Cfg::build(&code);
+
+        let entries: Vec<usize> = cfg.functions.iter().map(|f| f.entry).collect();
+        assert_eq!(entries, vec![0, 5]);
+        assert_eq!(cfg.function(0).unwrap().blocks, vec![0, 2]);
+        assert_eq!(cfg.function(5).unwrap().blocks, vec![5, 8, 9]);
+        assert_eq!(cfg.successors(2).collect::<Vec<_>>(), vec![&Edge { from: 2, to: Target::Unknown, kind: EdgeKind::Jump }]);
+
+        let dot = cfg.function_dot(cfg.function(0).unwrap());
+        assert!(dot.contains("b0 -> call0_5 [style=dashed];"));
+        assert!(dot.contains("b2 -> unknown2;"));
+    }
+}
@@ -0,0 +1,311 @@
+use log::{trace, debug, info, warn, error};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use termion::event::Key;
+
+#[allow(dead_code)]
+fn _get_rid_of_log_unused_import_warnings() {
+    trace!("Example trace.");
+    debug!("Example debug.");
+    info!("Example info.");
+    warn!("Example warn.");
+    error!("Example error.");
+}
+
+/// Lines of history kept in memory and loaded from the file.
+const MAX_HISTORY: usize = 1000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Edit {
+    Changed,
+    Submit(String),
+    Unhandled, // Left to the console, e.g. Tab, Esc and Ctrl-C
+}
+
+/// The console's prompt line: cursor movement, history with Up/Down and
+/// Ctrl-R reverse search, and completion from whatever the console offers.
+#[derive(Debug, Default)]
+pub struct LineEditor {
+    line: Vec<char>,
+    cursor: usize,
+    history: Vec<String>,
+    browsing: Option<usize>, // Which history entry Up/Down is on
+    draft: String,           // The line as it was before browsing
+    search: Option<String>,  // The Ctrl-R query
+    history_file: Option<PathBuf>,
+}
+
+impl LineEditor {
+    pub fn new() -> LineEditor {
+        LineEditor::default()
+    }
+
+    /// Loads history from `path` and appends each new line to it.
+    pub fn with_history_file(path: PathBuf) -> LineEditor {
+        let mut history: Vec<String> = std::fs::read_to_string(&path).unwrap_or_default()
+            .lines().map(String::from).collect();
+        history.drain(..history.len().saturating_sub(MAX_HISTORY));
+        LineEditor { history, history_file: Some(path), ..LineEditor::default() }
+    }
+
+    pub fn line(&self) -> String {
+        self.line.iter().collect()
+    }
+
+    pub fn history(&self) -> &[String] {
+        &self.history
+    }
+
+    pub fn searching(&self) -> bool {
+        self.search.is_some()
+    }
+
+    /// The prompt as shown, and the cursor's column in it.
+    pub fn prompt(&self) -> (String, usize) {
+        match &self.search {
+            Some(query) => {
+                let text = format!("(reverse-i-search)`{}': {}", query, self.line());
+                let cursor = text.chars().count();
+                (text, cursor)
+            },
+            None => (format!("> {}", self.line()), 2 + self.cursor),
+        }
+    }
+
+    pub fn handle(&mut self, key: Key) -> Edit {
+        if self.search.is_some() {
+            if let Some(edit) = self.handle_search(key) {
+                return edit;
+            }
+        }
+        match key {
+            Key::Char('\n') => {
+                // Completion leaves a space on the end
+                let line = self.line().trim_end().to_string();
+                self.set_line("");
+                self.browsing = None;
+                self.remember(&line);
+                return Edit::Submit(line);
+            },
+            Key::Char('\t') => return Edit::Unhandled,
+            Key::Char(c) => {
+                self.line.insert(self.cursor, c);
+                self.cursor += 1;
+            },
+            Key::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.line.remove(self.cursor);
+            },
+            Key::Delete | Key::Ctrl('d') if self.cursor < self.line.len() => {
+                self.line.remove(self.cursor);
+            },
+            Key::Left | Key::Ctrl('b') => self.cursor = self.cursor.saturating_sub(1),
+            Key::Right | Key::Ctrl('f') => self.cursor = (self.cursor + 1).min(self.line.len()),
+            Key::Home => self.cursor = 0,
+            Key::End | Key::Ctrl('e') => self.cursor = self.line.len(),
+            Key::Ctrl('w') | Key::Alt('\x7f') => {
+                let start = self.word_start();
+                self.line.drain(start..self.cursor);
+                self.cursor = start;
+            },
+            Key::Ctrl('u') => {
+                self.line.drain(..self.cursor);
+                self.cursor = 0;
+            },
+            Key::Ctrl('k') => {
+                self.line.truncate(self.cursor);
+            },
+            Key::Up | Key::Ctrl('p') => self.browse(true),
+            Key::Down | Key::Ctrl('n') => self.browse(false),
+            Key::Ctrl('r') => {
+                self.draft = self.line();
+                self.search = Some(String::new());
+            },
+            Key::Backspace | Key::Delete | Key::Ctrl('d') => {},
+            _ => return Edit::Unhandled,
+        }
+        Edit::Changed
+    }
+
+    /// Keys while searching: typing narrows the search, Ctrl-R looks
+    /// further back, Esc or Ctrl-G gives up, and anything else keeps the
+    /// match and carries on as usual.
+    fn handle_search(&mut self, key: Key) -> Option<Edit> {
+        let query = self.search.as_mut().unwrap();
+        let from = match key {
+            Key::Char(c) if c != '\n' && c != '\t' => {
+                query.push(c);
+                self.history.len()
+            },
+            Key::Backspace => {
+                query.pop();
+                self.history.len()
+            },
+            Key::Ctrl('r') => self.browsing.unwrap_or(self.history.len()),
+            Key::Esc | Key::Ctrl('g') => {
+                self.search = None;
+                let draft = self.draft.clone();
+                self.set_line(&draft);
+                self.browsing = None;
+                return Some(Edit::Changed);
+            },
+            _ => {
+                self.search = None;
+                return None;
+            },
+        };
+        let query = self.search.clone().unwrap();
+        if let Some(k) = self.history[..from].iter().rposition(|h| h.contains(&query)) {
+            self.browsing = Some(k);
+            let line = self.history[k].clone();
+            self.set_line(&line);
+        }
+        Some(Edit::Changed)
+    }
+
+    fn browse(&mut self, back: bool) {
+        let k = match (self.browsing, back) {
+            (None, true) if !self.history.is_empty() => {
+                self.draft = self.line();
+                self.history.len() - 1
+            },
+            (Some(k), true) => k.saturating_sub(1),
+            (Some(k), false) if k + 1 < self.history.len() => k + 1,
+            (Some(_), false) => {
+                self.browsing = None;
+                let draft = std::mem::take(&mut self.draft);
+                self.set_line(&draft);
+                return;
+            },
+            _ => return,
+        };
+        self.browsing = Some(k);
+        let line = self.history[k].clone();
+        self.set_line(&line);
+    }
+
+    fn set_line(&mut self, line: &str) {
+        self.line = line.chars().collect();
+        self.cursor = self.line.len();
+    }
+
+    fn word_start(&self) -> usize {
+        let mut start = self.cursor;
+        while start > 0 && self.line[start - 1] == ' ' {
+            start -= 1;
+        }
+        while start > 0 && self.line[start - 1] != ' ' {
+            start -= 1;
+        }
+        start
+    }
+
+    fn remember(&mut self, line: &str) {
+        if line.trim().is_empty() || self.history.last().map(|h| h.as_str()) == Some(line) {
+            return;
+        }
+        self.history.push(line.to_string());
+        if self.history.len() > MAX_HISTORY {
+            self.history.remove(0);
+        }
+        if let Some(path) = &self.history_file {
+            let appended = OpenOptions::new().create(true).append(true).open(path)
+                .and_then(|mut f| writeln!(f, "{}", line));
+            if let Err(e) = appended {
+                warn!("Could not save history to {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    /// Completes the text from `start` to the cursor from `candidates`.
+    /// With one match it's filled in; with several, as much as they share,
+    /// and they're returned so they can be shown.
+    pub fn complete(&mut self, start: usize, candidates: &[String]) -> Vec<String> {
+        let start = start.min(self.cursor);
+        let prefix: String = self.line[start..self.cursor].iter().collect();
+        let matches: Vec<String> = candidates.iter().filter(|c| c.starts_with(&prefix)).cloned().collect();
+        let completion = match matches.len() {
+            0 => return matches,
+            1 if matches[0].ends_with('/') => matches[0].clone(), // A directory; more to come
+            1 => format!("{} ", matches[0]),
+            _ => common_prefix(&matches),
+        };
+        let rest: Vec<char> = self.line.drain(self.cursor..).collect();
+        self.line.truncate(start);
+        self.line.extend(completion.chars());
+        self.cursor = self.line.len();
+        self.line.extend(rest);
+        if matches.len() == 1 { Vec::new() } else { matches }
+    }
+
+    /// The text before the cursor, which completion works on.
+    pub fn before_cursor(&self) -> String {
+        self.line[..self.cursor].iter().collect()
+    }
+}
+
+fn common_prefix(words: &[String]) -> String {
+    let first = &words[0];
+    let len = words[1..].iter()
+        .map(|w| first.chars().zip(w.chars()).take_while(|(a, b)| a == b).count())
+        .min()
+        .unwrap_or_else(|| first.chars().count());
+    first.chars().take(len).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn typed(editor: &mut LineEditor, text: &str) {
+        for c in text.chars() {
+            editor.handle(Key::Char(c));
+        }
+    }
+
+    #[test]
+    fn test_editing_and_history() {
+        let mut e = LineEditor::new();
+        typed(&mut e, "go nrth");
+        e.handle(Key::Left);
+        e.handle(Key::Left);
+        e.handle(Key::Left);
+        typed(&mut e, "o");
+        assert_eq!(e.prompt(), ("> go north".to_string(), 7));
+        e.handle(Key::End);
+        e.handle(Key::Ctrl('w'));
+        typed(&mut e, "nort");
+        e.handle(Key::Backspace);
+        assert_eq!(e.handle(Key::Char('\n')), Edit::Submit("go nor".to_string()));
+
+        typed(&mut e, "look");
+        e.handle(Key::Char('\n'));
+        typed(&mut e, "dra");
+        e.handle(Key::Up);
+        e.handle(Key::Up);
+        assert_eq!(e.line(), "go nor");
+        e.handle(Key::Down);
+        e.handle(Key::Down);
+        assert_eq!(e.line(), "dra");
+
+        e.handle(Key::Ctrl('r'));
+        typed(&mut e, "or");
+        assert_eq!(e.prompt().0, "(reverse-i-search)`or': go nor");
+        e.handle(Key::Esc);
+        assert_eq!((e.searching(), e.line()), (false, "dra".to_string()));
+    }
+
+    #[test]
+    fn test_complete() {
+        let mut e = LineEditor::new();
+        let candidates: Vec<String> = ["!break", "!run", "!reset"].iter().map(|s| s.to_string()).collect();
+        typed(&mut e, "!r");
+        assert_eq!(e.complete(0, &candidates), vec!["!run".to_string(), "!reset".to_string()]);
+        typed(&mut e, "u");
+        assert!(e.complete(0, &candidates).is_empty());
+        assert_eq!(e.line(), "!run ");
+        assert_eq!(e.handle(Key::Char('\n')), Edit::Submit("!run".to_string()));
+        assert_eq!(common_prefix(&["teleporter".to_string(), "tablet".to_string()]), "t");
+    }
+}
@@ -1,20 +1,32 @@
+pub mod editor;
+pub mod pane;
+
 use log::{trace, debug, info, warn, error};
-use crate::vm::Vm;
+use crate::controller::{self, Command, VmController};
+use crate::vm::{ExecutionPolicy, Vm};
+use crate::cfg::Cfg;
+use crate::cfg::callgraph::CallGraph;
+use crate::explorer::{Explorer, Map};
+use crate::game::{GameState, OutputParser};
+use crate::game::autopilot::Autopilot;
+use crate::script::Scripting;
+use crate::symbols::Symbols;
+use crate::transcript::Transcript;
 use crate::util::{get_file_as_byte_vec};
+use crate::util::event::{Event, Events};
+use editor::{Edit, LineEditor};
+use pane::OutputPane;
 use regex::Regex;
 use std::{error::Error};
-use crossbeam;
+use std::collections::BTreeSet;
 use termion::event::Key;
-use termion::input::TermRead;
 use termion::raw::IntoRawMode;
-use termion::cursor::DetectCursorPos;
 use termion::{color};
-use std::io::{Write, stdout, stdin};
-use std::cmp;
+use std::io::{Write, stdout};
+use std::path::PathBuf;
 // use ctrlc;
 // use std::sync::atomic::{AtomicBool, Ordering};
 // use std::sync::Arc;
-// use crate::util::event::{Event, Events};
 
 #[allow(dead_code)]
 fn _get_rid_of_log_unused_import_warnings() {
@@ -25,15 +37,37 @@ fn _get_rid_of_log_unused_import_warnings() {
     error!("Example error.");
 }
 
+/// Terminal row the game's output starts on, below the history.
+const VM_OUTPUT_ROW: u16 = 10;
+
+/// Lines of history shown above the game's output.
+const HISTORY_ROWS: usize = 6;
+
+/// Kept in the home directory.
+const HISTORY_FILE: &str = ".synacor_history";
+
+const COMMANDS: [&str; 17] = [
+    "!run", "!pause", "!step", "!regs", "!reset", "!break", "!quit",
+    "!take-all", "!use-all", "!symbols", "!functions", "!goto", "!script", "!eval",
+    "!find", "!save-output", "!clear",
+];
+
+const VERBS: [&str; 7] = ["look", "go", "take", "drop", "use", "inv", "help"];
+
 #[allow(dead_code)]
 pub struct Console {
     vm: Vm,
     running: bool,
     input: String,
     output: String,
-    history: Vec<String>,
-    vm_input: String,
-    vm_output: String,
+    editor: LineEditor,
+    pane: OutputPane, // The game's output
+    map: Option<Map>,
+    game: GameState, // The latest room and inventory, for completion
+    parser: OutputParser,
+    symbols: Symbols,
+    controller: Option<VmController>, // Has the VM while it runs in the background
+    breakpoints: BTreeSet<usize>,
     // events: Events,
 }
 
@@ -41,40 +75,58 @@ impl Console
 {
     pub fn new(input_file: String, memsize: usize) -> Console {
         // let _stdout = io::stdout().into_raw_mode().unwrap();
+        let mut vm = Vm::new(get_file_as_byte_vec(&input_file), memsize);
+        // Printing would land wherever the cursor is; the pane shows it instead
+        vm.set_capture_output(true);
         Console {
-            vm: Vm::new(
-                get_file_as_byte_vec(&input_file),
-                memsize,
-            ),
+            vm,
             running: true,
             input: String::new(),
             output: String::new(),
-            history: Vec::new(),
-            vm_input: String::new(),
-            vm_output: String::new(),
+            editor: match std::env::var_os("HOME") {
+                Some(home) => LineEditor::with_history_file(PathBuf::from(home).join(HISTORY_FILE)),
+                None => LineEditor::new(),
+            },
+            pane: OutputPane::new(),
+            map: None,
+            game: GameState::default(),
+            parser: OutputParser::new(),
+            symbols: Symbols::default(),
+            controller: None,
+            breakpoints: BTreeSet::new(),
             // events: Events::new(),
         }
     }
 
-    pub fn cprint(&mut self, message: &str) {
-        self.output = message.to_string();
+    pub fn set_symbols(&mut self, symbols: Symbols) {
+        self.symbols = symbols;
     }
 
-    #[allow(dead_code)]
-    pub fn vm_print(&mut self, message: &str) {
-        self.vm_output = message.to_string();
+    pub fn set_policy(&mut self, policy: ExecutionPolicy) {
+        self.vm.set_policy(policy);
     }
 
-    #[allow(dead_code)]
-    pub fn vm_get_input(&mut self) -> String {
-        self.vm_input.clone()
+    /// Records the session's game input and output from here on.
+    pub fn start_recording(&mut self) {
+        self.stop_vm();
+        self.vm.start_recording();
+    }
+
+    /// The session so far, once the VM is back from the controller.
+    pub fn finish_recording(&mut self) -> Option<Transcript> {
+        self.stop_vm();
+        self.vm.stop_recording().map(|entries| Transcript { program: String::new(), entries })
+    }
+
+    pub fn cprint(&mut self, message: &str) {
+        self.output = message.to_string();
     }
 
 
     pub fn run(&mut self) -> Result<(), Box<dyn Error>> {
 
-        let stdin = stdin();
         let mut stdout = stdout().into_raw_mode().unwrap();
+        let events = Events::new();
 
         write!(stdout,
                "{}{}{}> ",
@@ -84,110 +136,311 @@ impl Console
         ).unwrap();
         stdout.flush().unwrap();
 
-        for input in stdin.keys() {
-            match input.unwrap() {
-                Key::Char('\n') => {
-                    if !self.maybe_parse_input() {
-                        self.history.push(self.input.drain(..).collect());
-                    }
-                    if self.history.len() > 0 {
-                        for entry in 0..cmp::min(6, self.history.len()) {
-                            write!(stdout,
-                               "{}{}{}{}{}",
-                               termion::cursor::Goto(1, 3+entry as u16),
-                               termion::clear::CurrentLine,
-                               color::Fg(color::Yellow),
-                               self.history[(self.history.len() as i16 - entry as i16 - 1) as usize],
-                               color::Fg(color::Reset)
-                            ).unwrap();
-                        }
+        loop {
+            let key = match events.next()? {
+                Event::Input(key) => key,
+                Event::Tick => {
+                    if self.poll_vm() {
+                        self.draw_vm_output(&mut stdout);
+                        self.draw_status(&mut stdout);
                     }
-
-                    write!(stdout,
-                       "{}{}{}> ",
-                       termion::cursor::Goto(1, 1),
-                       termion::clear::CurrentLine,
-                       color::Fg(color::White)
-                    ).unwrap();
-
-                    self.cprint("");
-                },
-                Key::Char(c) => {
-                    print!("{}", c);
-                    self.input.push(c);
+                    continue;
                 },
-                Key::Backspace => {
-                    let (x1,y1) = stdout.cursor_pos()?;
-                    self.input.pop();
-                    write!(stdout,
-                       "{}{}",
-                       termion::cursor::Goto(x1-1,y1),
-                       color::Fg(color::White)
-                    ).unwrap();
-                },
-                Key::Ctrl('a') => {
-                    self.cprint("Pausing...");
-                    self.vm.pause();
-                },
-                Key::Ctrl('c') => {
-                    self.cprint("Time to quit.");
-                    break;
-                },
-                Key::Ctrl(c) => {
-                    self.cprint(&format!("Caught a CTRL-{}", c));
-                },
-                Key::Esc => {
-                    self.cprint("ESC!");
-                    break;
+            };
+            match self.editor.handle(key) {
+                Edit::Submit(line) => {
+                    self.input = line;
+                    self.maybe_parse_input();
+                    self.input.clear();
+                    self.draw_vm_output(&mut stdout);
+                    self.draw_history(&mut stdout);
+                    if !self.running {
+                        break;
+                    }
                 },
-                _ => {
-                    // self.cprint("");
+                Edit::Changed => {},
+                Edit::Unhandled => match key {
+                    Key::Char('\t') => self.complete(),
+                    Key::PageUp | Key::PageDown => {
+                        let rows = self.pane_rows();
+                        if key == Key::PageUp {
+                            self.pane.page_up(rows);
+                        } else {
+                            self.pane.page_down(rows);
+                        }
+                        self.draw_vm_output(&mut stdout);
+                    },
+                    Key::Ctrl('a') => {
+                        self.cprint("Pausing...");
+                        if let Some(c) = &self.controller {
+                            c.send(Command::Pause);
+                        }
+                    },
+                    Key::Ctrl('c') => {
+                        self.cprint("Time to quit.");
+                        break;
+                    },
+                    Key::Ctrl(c) => {
+                        self.cprint(&format!("Caught a CTRL-{}", c));
+                    },
+                    Key::Esc => {
+                        self.cprint("ESC!");
+                        break;
+                    },
+                    _ => {},
                 },
             }
 
-            let (x,y) = stdout.cursor_pos()?;
+            self.draw_status(&mut stdout);
+        }
+        write!(stdout, "{}", termion::cursor::Show).unwrap();
+        Ok(())
+    }
+
+    /// The prompt and the `==` line under it, leaving the cursor on the prompt.
+    fn draw_status(&self, stdout: &mut impl Write) {
+        let (prompt, cursor) = self.editor.prompt();
+        write!(stdout,
+           "{}{}{}== {}{}{}{}{}{}",
+           termion::cursor::Goto(1, 2),
+           termion::clear::CurrentLine,
+           color::Fg(color::Cyan),
+           self.output,
+           termion::cursor::Goto(1, 1),
+           termion::clear::CurrentLine,
+           color::Fg(color::White),
+           prompt,
+           termion::cursor::Goto(1 + cursor as u16, 1)
+        ).unwrap();
+        stdout.flush().unwrap();
+    }
 
+    /// The last few lines entered, newest first, under the status line.
+    fn draw_history(&self, stdout: &mut impl Write) {
+        for (row, line) in self.editor.history().iter().rev().take(HISTORY_ROWS).enumerate() {
             write!(stdout,
-               "{}{}{}== {}",
-               termion::cursor::Goto(1, 2),
+               "{}{}{}{}{}",
+               termion::cursor::Goto(1, 3 + row as u16),
                termion::clear::CurrentLine,
-               color::Fg(color::Cyan),
-               self.output
+               color::Fg(color::Yellow),
+               line,
+               color::Fg(color::Reset)
             ).unwrap();
+        }
+    }
 
-            write!(stdout,
-               "{}{}",
-               termion::cursor::Goto(x,y),
-               color::Fg(color::White)
-            ).unwrap();
+    /// Rows the game's output gets, leaving the bottom one for its bar.
+    fn pane_rows(&self) -> usize {
+        let (_, height) = termion::terminal_size().unwrap_or((80, 24));
+        height.saturating_sub(VM_OUTPUT_ROW).max(1) as usize
+    }
 
-            stdout.flush().unwrap();
+    /// The game's output below the history, with matches for the last
+    /// `!find` highlighted, and a bar saying where the view is.
+    fn draw_vm_output(&self, stdout: &mut impl Write) {
+        let (width, _) = termion::terminal_size().unwrap_or((80, 24));
+        let rows = self.pane_rows();
+        let (top, lines) = self.pane.visible(rows);
+        for k in 0..rows {
+            write!(stdout, "{}{}", termion::cursor::Goto(1, VM_OUTPUT_ROW + k as u16), termion::clear::CurrentLine).unwrap();
+            if let Some(line) = lines.get(k) {
+                let mut line: String = line.chars().take(width as usize).collect();
+                if let Some(q) = self.pane.query() {
+                    line = line.replace(q, &format!("{}{}{}", termion::style::Invert, q, termion::style::NoInvert));
+                }
+                write!(stdout, "{}", line).unwrap();
+            }
+        }
+        let search = match self.pane.query() {
+            Some(q) => format!(", finding {:?}", q),
+            None => String::new(),
+        };
+        let bar = format!("-- lines {}-{} of {}{} -- PgUp/PgDn, !find <text>, !save-output <file>",
+            top + 1, top + lines.len(), self.pane.len(), search);
+        write!(stdout,
+           "{}{}{}{}{}",
+           termion::cursor::Goto(1, VM_OUTPUT_ROW + rows as u16),
+           termion::clear::CurrentLine,
+           color::Fg(color::Cyan),
+           bar.chars().take(width as usize).collect::<String>(),
+           color::Fg(color::Reset)
+        ).unwrap();
+    }
+
+    /// Takes in what the controller has reported.  Returns true if there
+    /// was anything.
+    fn poll_vm(&mut self) -> bool {
+        let mut any = false;
+        while let Some(event) = self.controller.as_ref().and_then(|c| c.try_event()) {
+            any = true;
+            match event {
+                controller::Event::Output(text) => self.take_in_output(&text),
+                controller::Event::Stopped(reason) => self.cprint(&format!("Stopped: {:?}", reason)),
+                controller::Event::NeedsInput => self.cprint("Waiting for input."),
+                controller::Event::Snapshot(s) => {
+                    self.cprint(&format!("pc {} registers {:?} stack {:?}", s.pc(), s.registers(), s.stack()))
+                },
+            }
+        }
+        any
+    }
+
+    /// Adds game output to the pane, noting rooms and inventory on the way.
+    fn take_in_output(&mut self, text: &str) {
+        self.pane.push(text);
+        for state in self.parser.feed(text) {
+            if state.room.is_some() {
+                self.game.room = state.room;
+                self.game.items = state.items;
+                self.game.exits = state.exits;
+            }
+            if !state.inventory.is_empty() {
+                self.game.inventory = state.inventory;
+            }
+        }
+    }
+
+    /// Tab: commands or game verbs first, then what fits after them.
+    fn complete(&mut self) {
+        let before = self.editor.before_cursor();
+        let (start, candidates) = self.candidates(&before);
+        let matches = self.editor.complete(start, &candidates);
+        if !matches.is_empty() {
+            self.cprint(&matches.join("  "));
+        }
+    }
+
+    /// Where the word being completed starts, and what it might be.
+    fn candidates(&self, before: &str) -> (usize, Vec<String>) {
+        let (verb, start) = match before.find(' ') {
+            Some(k) => (&before[..k], k + 1),
+            None => {
+                let words: &[&str] = if before.starts_with('!') { &COMMANDS } else { &VERBS };
+                return (0, words.iter().map(|w| w.to_string()).collect());
+            },
+        };
+        let candidates = match verb {
+            "!break" => self.symbols.names().map(String::from).collect(),
+            "!goto" => self.map.iter().flat_map(|m| m.rooms.iter().map(|r| r.name.clone())).collect(),
+            "!symbols" | "!script" | "!save-output" => files(&before[start..]),
+            "go" => self.game.exits.clone(),
+            "take" | "look" => self.game.items.clone(),
+            "drop" | "use" => self.game.inventory.iter().chain(&self.game.items).cloned().collect(),
+            _ => Vec::new(),
+        };
+        (start, candidates)
+    }
+
+    /// Hands the VM to a controller so it can run in the background.
+    fn start_vm(&mut self) -> &VmController {
+        if self.controller.is_none() {
+            let vm = std::mem::replace(&mut self.vm, Vm::from_words(&[]));
+            let controller = VmController::new(vm);
+            for bp in &self.breakpoints {
+                controller.send(Command::SetBreakpoint(*bp));
+            }
+            self.controller = Some(controller);
+        }
+        self.controller.as_ref().unwrap()
+    }
+
+    /// Takes the VM back from the controller, if it has it.
+    fn stop_vm(&mut self) {
+        if let Some(controller) = self.controller.take() {
+            self.vm = controller.shutdown();
         }
-        write!(stdout, "{}", termion::cursor::Show).unwrap();
-        Ok(())
     }
 
     fn maybe_parse_input(&mut self) -> bool {
-        if self.input == "!run".to_string() {
-            crossbeam::scope(|scope| {
-                scope.spawn(move || {
-                    self.vm.execute_until_done();
-                });
-            });
-            return true;
-        } else if self.input == "!reset".to_string() {
+        if self.input == "!run" {
+            self.start_vm().send(Command::Run);
+            return true;
+        } else if self.input == "!pause" {
+            if let Some(c) = &self.controller {
+                c.send(Command::Pause);
+            }
+            return true;
+        } else if let Some(n) = self.input.strip_prefix("!step") {
+            let n = n.trim().parse().unwrap_or(1);
+            self.start_vm().send(Command::Step(n));
+            return true;
+        } else if self.input == "!regs" {
+            self.start_vm().send(Command::Snapshot);
+            return true;
+        } else if self.input == "!reset" {
             self.reset_vm();
             return true;
         } else if self.input.contains("!break") {
             self.add_breakpoint();
             return true;
-        } else if self.input == "!quit".to_string() {
+        } else if self.input == "!quit" {
             self.running = false;
             return true;
+        } else if self.input == "!take-all" {
+            self.stop_vm();
+            let result = Autopilot::new(&mut self.vm).take_all();
+            match result {
+                Ok(items) => self.cprint(&format!("Took {:?}", items)),
+                Err(e) => self.cprint(&format!("Stopped: {}", e)),
+            }
+            return true;
+        } else if self.input == "!use-all" {
+            self.stop_vm();
+            let result = Autopilot::new(&mut self.vm).use_all();
+            match result {
+                Ok(items) => self.cprint(&format!("Used {:?}", items)),
+                Err(e) => self.cprint(&format!("Stopped: {}", e)),
+            }
+            return true;
+        } else if let Some(file) = self.input.strip_prefix("!symbols ") {
+            let file = file.trim().to_string();
+            self.load_symbols(&file);
+            return true;
+        } else if let Some(file) = self.input.strip_prefix("!script ") {
+            let file = file.trim().to_string();
+            match std::fs::read_to_string(&file) {
+                Ok(source) => self.run_script(&source),
+                Err(e) => self.cprint(&format!("Could not load {}: {}", file, e)),
+            }
+            return true;
+        } else if let Some(source) = self.input.strip_prefix("!eval ") {
+            let source = source.to_string();
+            self.run_script(&source);
+            return true;
+        } else if self.input == "!find" || self.input.starts_with("!find ") {
+            let query = self.input["!find".len()..].trim().to_string();
+            let rows = self.pane_rows();
+            match self.pane.find(Some(&query), rows) {
+                Some(line) => self.cprint(&format!("Found on line {}; !find again for the one before.", line + 1)),
+                None => self.cprint("Not found."),
+            }
+            return true;
+        } else if let Some(file) = self.input.strip_prefix("!save-output ") {
+            let file = file.trim().to_string();
+            match self.pane.save(&file) {
+                Ok(()) => self.cprint(&format!("Saved {} lines to {}.", self.pane.len(), file)),
+                Err(e) => self.cprint(&format!("Could not save {}: {}", file, e)),
+            }
+            return true;
+        } else if self.input == "!clear" {
+            self.pane.clear();
+            return true;
+        } else if self.input == "!functions" {
+            self.functions();
+            return true;
+        } else if let Some(room) = self.input.strip_prefix("!goto ") {
+            let room = room.trim().to_string();
+            self.goto(&room);
+            return true;
+        }
+        self.pane.scroll_to_bottom();
+        if let Some(c) = &self.controller {
+            c.send(Command::Input(format!("{}\n", self.input)));
+            c.send(Command::Run);
         } else {
-            self.vm.insert_buffer(self.input.clone());
+            self.vm.insert_buffer(format!("{}\n", self.input));
         }
-        return false;
+        false
     }
 
     // fn handle_input(&mut self) -> Result<(), Box<dyn Error>>  {
@@ -218,24 +471,111 @@ impl Console
     //     Ok(())
     // }
 
-    #[allow(dead_code)]
-    fn run_vm(&mut self) {
-        self.vm.execute_until_done();
-    }
-
     #[allow(dead_code)]
     fn reset_vm(&mut self) {
+        self.stop_vm();
         self.vm.reset();
     }
 
+    /// Walks to `room`, exploring the map from wherever the game is the
+    /// first time it's needed.
+    fn goto(&mut self, room: &str) {
+        self.stop_vm();
+        let map = match self.map.take() {
+            Some(map) => map,
+            None => Explorer::new(&mut self.vm, 500).explore(),
+        };
+        let result = Autopilot::new(&mut self.vm).goto(&map, room);
+        self.map = Some(map);
+        match result {
+            Ok(state) => self.cprint(&format!("Arrived at {}", state.room.unwrap_or_default())),
+            Err(e) => self.cprint(&format!("Stopped: {}", e)),
+        }
+    }
+
+    fn load_symbols(&mut self, file: &str) {
+        match Symbols::load(file) {
+            Ok(symbols) => {
+                self.cprint(&format!("Loaded {} symbols.", symbols.len()));
+                self.symbols = symbols;
+            },
+            Err(e) => self.cprint(&format!("Could not load {}: {}", file, e)),
+        }
+    }
+
+    /// Runs a Rhai script against the VM, with the console's breakpoints.
+    /// What it prints goes with the game's output; its value or error goes
+    /// on the status line.
+    fn run_script(&mut self, source: &str) {
+        self.stop_vm();
+        let vm = std::mem::replace(&mut self.vm, Vm::from_words(&[]));
+        let breakpoints = std::mem::take(&mut self.breakpoints);
+        let mut script = Scripting::new(vm, self.symbols.clone(), breakpoints);
+        let result = script.eval(source);
+        let (log, output) = script.take_output();
+        self.take_in_output(&output);
+        for line in log {
+            self.pane.push(&format!("{}\n", line));
+        }
+        let (vm, breakpoints) = script.finish();
+        self.vm = vm;
+        self.breakpoints = breakpoints;
+        match result {
+            Ok(value) => self.cprint(&format!("Script: {}", value)),
+            Err(e) => self.cprint(&format!("Script failed: {}", e)),
+        }
+    }
+
+    /// Lists functions in live memory, so code decrypted at runtime shows up.
+    /// Recursive ones are marked with a star.
+    fn functions(&mut self) {
+        self.stop_vm();
+        let graph = CallGraph::new(&Cfg::build(self.vm.memory()));
+        let list: Vec<String> = graph.functions.iter()
+            .map(|f| format!("{}{}[{}]", self.symbols.locate(f.entry), if f.recursive { "*" } else { "" }, f.size))
+            .collect();
+        self.cprint(&format!("{} functions: {}", list.len(), list.join(" ")));
+    }
+
+    /// `!break <address>` or `!break <symbol>`.
     fn add_breakpoint(&mut self) {
-        let re = Regex::new(r"!break (\d+)").unwrap();
-        let cap = re.captures(&self.input).unwrap();
+        let re = Regex::new(r"!break (\S+)").unwrap();
+        let target = match re.captures(&self.input) {
+            Some(cap) => cap[1].to_string(),
+            None => return,
+        };
 
-        let bp = cap[1].parse::<usize>().unwrap();
+        let bp = match self.symbols.resolve(&target) {
+            Some(bp) => bp,
+            None => {
+                self.cprint(&format!("No symbol named {}.", target));
+                return;
+            }
+        };
 
-        self.vm.add_breakpoint(bp);
+        self.breakpoints.insert(bp);
+        if let Some(c) = &self.controller {
+            c.send(Command::SetBreakpoint(bp));
+        }
 
-        debug!("Added breakpoint @ {}", bp);
+        debug!("Added breakpoint @ {} ({})", bp, target);
     }
-}
\ No newline at end of file
+}
+
+/// Paths in the directory `partial` is in, directories with a trailing `/`.
+fn files(partial: &str) -> Vec<String> {
+    let dir = match partial.rfind('/') {
+        Some(k) => &partial[..k + 1],
+        None => "",
+    };
+    let entries = match std::fs::read_dir(if dir.is_empty() { "." } else { dir }) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    entries.flatten()
+        .map(|e| {
+            let slash = if e.path().is_dir() { "/" } else { "" };
+            format!("{}{}{}", dir, e.file_name().to_string_lossy(), slash)
+        })
+        .collect()
+}
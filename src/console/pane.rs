@@ -0,0 +1,149 @@
+use log::{trace, debug, info, warn, error};
+use std::io;
+
+#[allow(dead_code)]
+fn _get_rid_of_log_unused_import_warnings() {
+    trace!("Example trace.");
+    debug!("Example debug.");
+    info!("Example info.");
+    warn!("Example warn.");
+    error!("Example error.");
+}
+
+/// Lines kept before the oldest are dropped.
+const SCROLLBACK: usize = 10_000;
+
+/// The game's output as lines, with a view that can scroll back through
+/// them and search.  The last line is the one still being written.
+#[derive(Debug, Clone)]
+pub struct OutputPane {
+    lines: Vec<String>,
+    scroll: usize, // Lines the view is above the bottom
+    query: Option<String>,
+    dropped: usize, // Lines lost off the top, for the saved transcript's sake
+}
+
+impl Default for OutputPane {
+    fn default() -> OutputPane {
+        OutputPane::new()
+    }
+}
+
+impl OutputPane {
+    pub fn new() -> OutputPane {
+        OutputPane { lines: vec![String::new()], scroll: 0, query: None, dropped: 0 }
+    }
+
+    /// Adds output, keeping the view where it is if it's scrolled back.
+    pub fn push(&mut self, text: &str) {
+        let mut parts = text.split('\n');
+        self.lines.last_mut().unwrap().push_str(parts.next().unwrap_or_default());
+        for part in parts {
+            self.lines.push(part.to_string());
+            if self.scroll > 0 {
+                self.scroll += 1;
+            }
+        }
+        let excess = self.lines.len().saturating_sub(SCROLLBACK);
+        if excess > 0 {
+            self.lines.drain(..excess);
+            self.dropped += excess;
+            self.scroll = self.scroll.min(self.lines.len() - 1);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.lines.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lines.len() == 1 && self.lines[0].is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        *self = OutputPane::new();
+    }
+
+    pub fn query(&self) -> Option<&str> {
+        self.query.as_deref()
+    }
+
+    /// Index of the first line shown in a view `rows` high.
+    fn top(&self, rows: usize) -> usize {
+        (self.lines.len() - self.scroll).saturating_sub(rows)
+    }
+
+    /// The lines in a view `rows` high, and the index of the first.
+    pub fn visible(&self, rows: usize) -> (usize, &[String]) {
+        let top = self.top(rows);
+        (top, &self.lines[top..self.lines.len() - self.scroll])
+    }
+
+    pub fn page_up(&mut self, rows: usize) {
+        let most = self.lines.len().saturating_sub(rows);
+        self.scroll = (self.scroll + rows.max(1)).min(most);
+    }
+
+    pub fn page_down(&mut self, rows: usize) {
+        self.scroll = self.scroll.saturating_sub(rows.max(1));
+    }
+
+    pub fn scroll_to_bottom(&mut self) {
+        self.scroll = 0;
+    }
+
+    /// Scrolls back to the next line above the view's top that holds
+    /// `query`, putting it at the top.  With no query, looks for the last
+    /// one again.  Returns the line's index.
+    pub fn find(&mut self, query: Option<&str>, rows: usize) -> Option<usize> {
+        if let Some(q) = query.filter(|q| !q.is_empty()) {
+            self.query = Some(q.to_string());
+        }
+        let q = self.query.clone()?;
+        let top = self.top(rows);
+        let found = self.lines[..top].iter().rposition(|l| l.contains(&q))
+            .or_else(|| self.lines[top..].iter().rposition(|l| l.contains(&q)).map(|k| top + k))?;
+        // On the top row, unless it's too near the bottom for that
+        self.scroll = self.lines.len().saturating_sub(found + rows);
+        Some(found)
+    }
+
+    pub fn transcript(&self) -> String {
+        self.lines.join("\n")
+    }
+
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        if self.dropped > 0 {
+            warn!("The first {} lines of output were dropped from the scrollback", self.dropped);
+        }
+        std::fs::write(path, self.transcript())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scroll_and_find() {
+        let mut pane = OutputPane::new();
+        pane.push("one\ntwo\nthr");
+        pane.push("ee\nfour\n");
+        assert_eq!(pane.visible(2), (3, &["four".to_string(), String::new()][..]));
+
+        pane.page_up(2);
+        assert_eq!(pane.visible(2).1, &["two".to_string(), "three".to_string()][..]);
+        // New output leaves a scrolled view alone
+        pane.push("five\n");
+        assert_eq!(pane.visible(2).1, &["two".to_string(), "three".to_string()][..]);
+        pane.page_down(10);
+        assert_eq!(pane.visible(1).1, &[String::new()][..]);
+
+        assert_eq!(pane.find(Some("o"), 2), Some(3)); // four
+        assert_eq!(pane.visible(2).1[0], "four");
+        assert_eq!(pane.find(None, 2), Some(1)); // two
+        assert_eq!(pane.find(None, 2), Some(0)); // one
+        assert_eq!(pane.find(Some("nowhere"), 2), None);
+        assert_eq!(pane.transcript(), "one\ntwo\nthree\nfour\nfive\n");
+    }
+}
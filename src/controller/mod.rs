@@ -0,0 +1,241 @@
+use log::{trace, debug, info, warn, error};
+use crate::vm::{Snapshot, Vm};
+pub use crate::vm::StopReason;
+use std::collections::BTreeSet;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::thread;
+use std::time::Duration;
+
+#[allow(dead_code)]
+fn _get_rid_of_log_unused_import_warnings() {
+    trace!("Example trace.");
+    debug!("Example debug.");
+    info!("Example info.");
+    warn!("Example warn.");
+    error!("Example error.");
+}
+
+/// Steps run between looking for new commands.
+const SLICE: usize = 10_000;
+
+#[derive(Debug, Clone)]
+pub enum Command {
+    Run,
+    Pause,
+    Step(usize),
+    Input(String), // Queued for IN; doesn't start running by itself
+    Snapshot,
+    SetBreakpoint(usize),
+    ClearBreakpoint(usize),
+    Reset,
+    Quit,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    Output(String),
+    Stopped(StopReason),
+    NeedsInput,
+    Snapshot(Box<Snapshot>),
+}
+
+/// Owns a VM on a worker thread, driven by `Command`s and reporting back
+/// with `Event`s, so whoever holds it can pause, inspect or feed input while
+/// the VM runs.
+pub struct VmController {
+    commands: Sender<Command>,
+    events: Receiver<Event>,
+    worker: Option<thread::JoinHandle<Vm>>,
+}
+
+impl VmController {
+    pub fn new(mut vm: Vm) -> VmController {
+        let (commands, command_rx) = channel();
+        let (event_tx, events) = channel();
+        vm.set_capture_output(true);
+        let worker = thread::spawn(move || {
+            let mut worker = Worker { vm, breakpoints: BTreeSet::new(), running: false, events: event_tx };
+            worker.serve(command_rx);
+            worker.vm
+        });
+        VmController { commands, events, worker: Some(worker) }
+    }
+
+    /// Queues a command; does nothing once the worker has quit.
+    pub fn send(&self, command: Command) {
+        self.commands.send(command).ok();
+    }
+
+    pub fn try_event(&self) -> Option<Event> {
+        self.events.try_recv().ok()
+    }
+
+    /// Waits up to `timeout` for the next event.
+    pub fn next_event(&self, timeout: Duration) -> Option<Event> {
+        match self.events.recv_timeout(timeout) {
+            Ok(event) => Some(event),
+            Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => None,
+        }
+    }
+
+    /// Stops the worker and hands back the VM as it was left.
+    pub fn shutdown(mut self) -> Vm {
+        self.send(Command::Quit);
+        self.worker.take().unwrap().join().unwrap()
+    }
+}
+
+impl Drop for VmController {
+    fn drop(&mut self) {
+        if let Some(worker) = self.worker.take() {
+            self.commands.send(Command::Quit).ok();
+            worker.join().ok();
+        }
+    }
+}
+
+struct Worker {
+    vm: Vm,
+    breakpoints: BTreeSet<usize>,
+    running: bool,
+    events: Sender<Event>,
+}
+
+impl Worker {
+    /// Handles commands until told to quit, running the VM in slices in
+    /// between while it's meant to be running.
+    fn serve(&mut self, commands: Receiver<Command>) {
+        loop {
+            let command = if self.running {
+                match commands.try_recv() {
+                    Ok(command) => Some(command),
+                    Err(TryRecvError::Empty) => None,
+                    Err(TryRecvError::Disconnected) => return,
+                }
+            } else {
+                match commands.recv() {
+                    Ok(command) => Some(command),
+                    Err(_) => return,
+                }
+            };
+            match command {
+                Some(Command::Quit) => return,
+                Some(command) => self.handle(command),
+                None => {
+                    self.run(SLICE);
+                },
+            }
+        }
+    }
+
+    fn handle(&mut self, command: Command) {
+        trace!("Controller command {:?}", command);
+        match command {
+            Command::Run => self.running = true,
+            Command::Pause => {
+                if self.running {
+                    self.running = false;
+                    self.emit(Event::Stopped(StopReason::Paused));
+                }
+            },
+            Command::Step(n) => {
+                self.running = false;
+                if !self.run(n) {
+                    self.emit(Event::Stopped(StopReason::Stepped));
+                }
+            },
+            Command::Input(line) => self.vm.insert_buffer(line),
+            Command::Snapshot => {
+                let snapshot = self.vm.snapshot();
+                self.emit(Event::Snapshot(Box::new(snapshot)));
+            },
+            Command::SetBreakpoint(addr) => {
+                self.breakpoints.insert(addr);
+            },
+            Command::ClearBreakpoint(addr) => {
+                self.breakpoints.remove(&addr);
+            },
+            Command::Reset => {
+                self.vm.reset();
+                self.running = false;
+            },
+            Command::Quit => {},
+        }
+    }
+
+    /// Runs up to `steps` instructions.  Returns true if it stopped early,
+    /// having said why.
+    fn run(&mut self, steps: usize) -> bool {
+        let stop = match self.vm.run_until(&self.breakpoints, steps).0 {
+            StopReason::Stepped => None,
+            StopReason::Input => Some(Event::NeedsInput),
+            reason => Some(Event::Stopped(reason)),
+        };
+        let output = self.vm.take_output();
+        if !output.is_empty() {
+            self.emit(Event::Output(output));
+        }
+        match stop {
+            Some(event) => {
+                self.running = false;
+                self.emit(event);
+                true
+            },
+            None => false,
+        }
+    }
+
+    fn emit(&self, event: Event) {
+        // Nobody listening is fine; the controller may be shutting down
+        self.events.send(event).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WAIT: Duration = Duration::from_secs(5);
+
+    fn program() -> Vm {
+        Vm::from_words(&[
+            21,             // 0: noop
+            19, 'A' as u16, // 1: out 'A'
+            20, 32768,      // 3: in R0
+            6, 0,           // 5: jmp 0
+        ])
+    }
+
+    #[test]
+    fn test_breakpoints_and_input() {
+        let c = VmController::new(program());
+        c.send(Command::SetBreakpoint(1));
+        c.send(Command::Run);
+        assert_eq!(c.next_event(WAIT), Some(Event::Stopped(StopReason::Breakpoint(1))));
+
+        c.send(Command::ClearBreakpoint(1));
+        c.send(Command::Run);
+        assert_eq!(c.next_event(WAIT), Some(Event::Output("A".to_string())));
+        assert_eq!(c.next_event(WAIT), Some(Event::NeedsInput));
+
+        c.send(Command::Input("x".to_string()));
+        c.send(Command::Step(2));
+        assert_eq!(c.next_event(WAIT), Some(Event::Stopped(StopReason::Stepped)));
+        c.send(Command::Snapshot);
+        match c.next_event(WAIT) {
+            Some(Event::Snapshot(s)) => assert_eq!((s.pc(), s.registers()[0]), (0, 'x' as u16)),
+            e => panic!("Expected a snapshot, got {:?}", e),
+        }
+        assert_eq!(c.shutdown().registers()[0], 'x' as u16);
+    }
+
+    #[test]
+    fn test_pause() {
+        // Spins forever without any input
+        let c = VmController::new(Vm::from_words(&[6, 0]));
+        c.send(Command::Run);
+        c.send(Command::Pause);
+        assert_eq!(c.next_event(WAIT), Some(Event::Stopped(StopReason::Paused)));
+        assert_eq!(c.try_event(), None);
+    }
+}
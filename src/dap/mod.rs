@@ -0,0 +1,575 @@
+use log::{trace, debug, info, warn, error};
+use crate::symbols::Symbols;
+use crate::util::get_file_as_byte_vec;
+use crate::vm::{ExecutionPolicy, Instruction, InstructionCode, StopReason, Vm, MAX_VAL};
+use serde_json::{json, Value};
+use std::collections::BTreeSet;
+use std::io::{self, BufRead, Write};
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::thread;
+
+#[allow(dead_code)]
+fn _get_rid_of_log_unused_import_warnings() {
+    trace!("Example trace.");
+    debug!("Example debug.");
+    info!("Example info.");
+    warn!("Example warn.");
+    error!("Example error.");
+}
+
+/// Steps run between looking for new requests.
+const SLICE: usize = 10_000;
+
+/// The VM is the only thread.
+const THREAD: i64 = 1;
+
+const REGISTERS_REF: i64 = 1;
+const STACK_REF: i64 = 2;
+
+/// Reads one `Content-Length` framed message.  None at end of input.
+pub fn read_message(input: &mut impl BufRead) -> io::Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() && length.is_some() {
+            break;
+        }
+        if let Some(n) = line.strip_prefix("Content-Length:") {
+            length = n.trim().parse::<usize>().ok();
+        }
+    }
+    let mut body = vec![0; length.unwrap()];
+    input.read_exact(&mut body)?;
+    serde_json::from_slice(&body).map(Some).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn write_message(out: &mut impl Write, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    write!(out, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    out.flush()
+}
+
+/// What the VM is doing between requests.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Running {
+    Continue,
+    Step,
+    Over { depth: usize, ret: usize }, // Until the CALL at hand returns
+    Out { depth: usize },              // Until a RET takes the stack below `depth`
+}
+
+/// Debug Adapter Protocol server: one VM, launched from a binary with an
+/// optional script of game input.
+pub struct DapServer<W: Write> {
+    out: W,
+    seq: i64,
+    vm: Option<Vm>,
+    symbols: Symbols,
+    policy: ExecutionPolicy, // For launches that don't name one
+    function_breakpoints: BTreeSet<usize>,
+    instruction_breakpoints: BTreeSet<usize>,
+    running: Option<Running>,
+    stop_on_entry: bool,
+    done: bool,
+}
+
+/// Serves DAP on stdin and stdout until the client disconnects.
+pub fn serve_stdio(symbols: Symbols, policy: ExecutionPolicy) -> io::Result<()> {
+    let (tx, rx) = channel();
+    thread::spawn(move || {
+        let stdin = io::stdin();
+        let mut input = stdin.lock();
+        while let Ok(Some(message)) = read_message(&mut input) {
+            if tx.send(message).is_err() {
+                break;
+            }
+        }
+    });
+    DapServer::new(io::stdout(), symbols, policy).serve(rx)
+}
+
+impl<W: Write> DapServer<W> {
+    pub fn new(out: W, symbols: Symbols, policy: ExecutionPolicy) -> DapServer<W> {
+        DapServer {
+            out,
+            seq: 0,
+            vm: None,
+            symbols,
+            policy,
+            function_breakpoints: BTreeSet::new(),
+            instruction_breakpoints: BTreeSet::new(),
+            running: None,
+            stop_on_entry: false,
+            done: false,
+        }
+    }
+
+    /// Handles requests as they come, running the VM in between.
+    pub fn serve(&mut self, requests: Receiver<Value>) -> io::Result<()> {
+        while !self.done {
+            let request = if self.running.is_some() {
+                match requests.try_recv() {
+                    Ok(request) => Some(request),
+                    Err(TryRecvError::Empty) => None,
+                    Err(TryRecvError::Disconnected) => break,
+                }
+            } else {
+                match requests.recv() {
+                    Ok(request) => Some(request),
+                    Err(_) => break,
+                }
+            };
+            match request {
+                Some(request) => self.handle(&request)?,
+                None => self.run(SLICE)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn send(&mut self, mut message: Value) -> io::Result<()> {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        trace!("DAP -> {}", message);
+        write_message(&mut self.out, &message)
+    }
+
+    fn respond(&mut self, request: &Value, body: Value) -> io::Result<()> {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": true,
+            "body": body,
+        }))
+    }
+
+    fn fail(&mut self, request: &Value, message: &str) -> io::Result<()> {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": false,
+            "message": message,
+        }))
+    }
+
+    fn event(&mut self, event: &str, body: Value) -> io::Result<()> {
+        self.send(json!({ "type": "event", "event": event, "body": body }))
+    }
+
+    fn handle(&mut self, request: &Value) -> io::Result<()> {
+        trace!("DAP <- {}", request);
+        let command = request["command"].as_str().unwrap_or_default().to_string();
+        let args = &request["arguments"];
+        if self.vm.is_none() && !["initialize", "launch", "disconnect", "terminate"].contains(&command.as_str()) {
+            return self.fail(request, "Not launched");
+        }
+        match command.as_str() {
+            "initialize" => {
+                self.respond(request, json!({
+                    "supportsConfigurationDoneRequest": true,
+                    "supportsFunctionBreakpoints": true,
+                    "supportsInstructionBreakpoints": true,
+                    "supportsDisassembleRequest": true,
+                    "supportsTerminateRequest": true,
+                }))?;
+                self.event("initialized", json!({}))
+            },
+            "launch" => match self.launch(args) {
+                Ok(()) => self.respond(request, json!({})),
+                Err(e) => self.fail(request, &e),
+            },
+            "setFunctionBreakpoints" => {
+                let mut results = Vec::new();
+                self.function_breakpoints.clear();
+                for b in args["breakpoints"].as_array().cloned().unwrap_or_default() {
+                    let name = b["name"].as_str().unwrap_or_default();
+                    results.push(match self.symbols.resolve(name).filter(|&a| a < MAX_VAL) {
+                        Some(addr) => {
+                            self.function_breakpoints.insert(addr);
+                            json!({ "verified": true, "instructionReference": addr.to_string() })
+                        },
+                        None => json!({ "verified": false, "message": format!("No address or symbol {}", name) }),
+                    });
+                }
+                self.respond(request, json!({ "breakpoints": results }))
+            },
+            "setInstructionBreakpoints" => {
+                let mut results = Vec::new();
+                self.instruction_breakpoints.clear();
+                for b in args["breakpoints"].as_array().cloned().unwrap_or_default() {
+                    let base = b["instructionReference"].as_str().and_then(|r| self.symbols.resolve(r));
+                    let addr = base.map(|a| a as i64 + b["offset"].as_i64().unwrap_or(0));
+                    results.push(match addr.filter(|&a| a >= 0 && (a as usize) < MAX_VAL) {
+                        Some(addr) => {
+                            self.instruction_breakpoints.insert(addr as usize);
+                            json!({ "verified": true, "instructionReference": addr.to_string() })
+                        },
+                        None => json!({ "verified": false, "message": "Bad instruction reference" }),
+                    });
+                }
+                self.respond(request, json!({ "breakpoints": results }))
+            },
+            "setBreakpoints" => {
+                let results: Vec<Value> = args["breakpoints"].as_array().cloned().unwrap_or_default().iter()
+                    .map(|_| json!({ "verified": false, "message": "No source; use function or instruction breakpoints" }))
+                    .collect();
+                self.respond(request, json!({ "breakpoints": results }))
+            },
+            "setExceptionBreakpoints" => self.respond(request, json!({})),
+            "configurationDone" => {
+                self.respond(request, json!({}))?;
+                if self.stop_on_entry {
+                    self.stopped("entry", "Stopped on entry")
+                } else {
+                    self.resume(Running::Continue);
+                    Ok(())
+                }
+            },
+            "threads" => self.respond(request, json!({ "threads": [{ "id": THREAD, "name": "vm" }] })),
+            "stackTrace" => {
+                let frames = self.stack_frames();
+                let total = frames.len();
+                self.respond(request, json!({ "stackFrames": frames, "totalFrames": total }))
+            },
+            "scopes" => self.respond(request, json!({ "scopes": [
+                { "name": "Registers", "variablesReference": REGISTERS_REF, "expensive": false },
+                { "name": "Stack", "variablesReference": STACK_REF, "expensive": false },
+            ]})),
+            "variables" => {
+                let variables = self.variables(args["variablesReference"].as_i64().unwrap_or(0));
+                self.respond(request, json!({ "variables": variables }))
+            },
+            "continue" => {
+                self.resume(Running::Continue);
+                self.respond(request, json!({ "allThreadsContinued": true }))
+            },
+            "stepIn" => {
+                self.resume(Running::Step);
+                self.respond(request, json!({}))
+            },
+            "next" => {
+                let vm = self.vm.as_ref().unwrap();
+                let running = match Instruction::at(vm.memory(), vm.pc()) {
+                    Some(i) if i.operator == InstructionCode::CALL => Running::Over { depth: vm.stack().len(), ret: vm.pc() + 2 },
+                    _ => Running::Step,
+                };
+                self.resume(running);
+                self.respond(request, json!({}))
+            },
+            "stepOut" => {
+                let depth = self.vm.as_ref().unwrap().stack().len();
+                self.resume(Running::Out { depth });
+                self.respond(request, json!({}))
+            },
+            "pause" => {
+                self.respond(request, json!({}))?;
+                if self.running.is_some() {
+                    self.flush_output()?;
+                    self.stopped("pause", "Paused")?;
+                }
+                Ok(())
+            },
+            "disassemble" => {
+                let instructions = self.disassemble(args);
+                self.respond(request, json!({ "instructions": instructions }))
+            },
+            "evaluate" => {
+                let expression = args["expression"].as_str().unwrap_or_default().to_string();
+                if args["context"] == "repl" {
+                    self.vm.as_mut().unwrap().insert_buffer(format!("{}\n", expression));
+                    self.respond(request, json!({ "result": "(sent to the game)", "variablesReference": 0 }))
+                } else {
+                    match self.evaluate(&expression) {
+                        Some(result) => self.respond(request, json!({ "result": result, "variablesReference": 0 })),
+                        None => self.fail(request, "Not a register, address or symbol"),
+                    }
+                }
+            },
+            "disconnect" | "terminate" => {
+                self.done = true;
+                self.respond(request, json!({}))
+            },
+            _ => self.fail(request, &format!("Unsupported request {}", command)),
+        }
+    }
+
+    /// `program` (default challenge.bin), `inputScript` of game input,
+    /// `symbols` to add to any given up front, `policy` in place of the
+    /// server's, and `stopOnEntry`.
+    fn launch(&mut self, args: &Value) -> Result<(), String> {
+        let program = args["program"].as_str().unwrap_or("challenge.bin");
+        if !std::path::Path::new(program).exists() {
+            return Err(format!("No such program {}", program));
+        }
+        let policy = match args["policy"].as_str() {
+            Some(p) => p.parse()?,
+            None => self.policy,
+        };
+        let mut vm = Vm::new(get_file_as_byte_vec(program), MAX_VAL);
+        vm.set_policy(policy);
+        vm.set_capture_output(true);
+        if let Some(script) = args["inputScript"].as_str() {
+            let mut input = std::fs::read_to_string(script).map_err(|e| format!("{}: {}", script, e))?;
+            if !input.ends_with('\n') {
+                input.push('\n');
+            }
+            vm.insert_buffer(input);
+        }
+        if let Some(file) = args["symbols"].as_str() {
+            self.symbols.extend(Symbols::load(file).map_err(|e| format!("{}: {}", file, e))?);
+        }
+        self.stop_on_entry = args["stopOnEntry"].as_bool().unwrap_or(false);
+        self.vm = Some(vm);
+        Ok(())
+    }
+
+    fn resume(&mut self, running: Running) {
+        self.running = Some(running);
+    }
+
+    fn stopped(&mut self, reason: &str, description: &str) -> io::Result<()> {
+        self.running = None;
+        self.event("stopped", json!({
+            "reason": reason,
+            "description": description,
+            "threadId": THREAD,
+            "allThreadsStopped": true,
+        }))
+    }
+
+    fn flush_output(&mut self) -> io::Result<()> {
+        let output = self.vm.as_mut().unwrap().take_output();
+        if output.is_empty() {
+            return Ok(());
+        }
+        self.event("output", json!({ "category": "stdout", "output": output }))
+    }
+
+    /// Runs up to `steps` instructions of whatever `running` says.
+    fn run(&mut self, steps: usize) -> io::Result<()> {
+        let running = match self.running {
+            Some(running) => running,
+            None => return Ok(()),
+        };
+        let breakpoints: BTreeSet<usize> = self.function_breakpoints.union(&self.instruction_breakpoints).cloned().collect();
+        let vm = self.vm.as_mut().unwrap();
+        let mut stop = None;
+        let mut left = steps;
+        // Stepping goes an instruction at a time to see when it's done
+        while stop.is_none() && left > 0 {
+            let i = Instruction::at(vm.memory(), vm.pc());
+            let (reason, ran) = vm.run_until(&breakpoints, if running == Running::Continue { left } else { 1 });
+            left -= ran;
+            stop = match reason {
+                StopReason::Input => Some(("pause", "Waiting for input; type it in the debug console".to_string())),
+                StopReason::Breakpoint(pc) => Some(("breakpoint", format!("Breakpoint at {}", self.symbols.locate(pc)))),
+                StopReason::Violation(pc, v) => Some(("exception", format!("Stopped at {}: {}", pc, v))),
+                StopReason::Halted => break,
+                StopReason::Paused | StopReason::Stepped => {
+                    let done = match running {
+                        Running::Continue => false,
+                        Running::Step => true,
+                        Running::Over { depth, ret } => vm.pc() == ret && vm.stack().len() == depth,
+                        Running::Out { depth } => i.is_some_and(|i| i.operator == InstructionCode::RET) && vm.stack().len() < depth,
+                    };
+                    if done { Some(("step", "Stepped".to_string())) } else { None }
+                },
+            };
+        }
+        self.flush_output()?;
+        let vm = self.vm.as_mut().unwrap();
+        if vm.is_stopped() && vm.violation().is_none() {
+            self.running = None;
+            self.event("exited", json!({ "exitCode": 0 }))?;
+            return self.event("terminated", json!({}));
+        }
+        match stop {
+            Some((reason, description)) => self.stopped(reason, &description),
+            None => Ok(()),
+        }
+    }
+
+    /// pc, then a frame for each stack entry that looks like a return
+    /// address: one just past a CALL.
+    fn stack_frames(&self) -> Vec<Value> {
+        let vm = self.vm.as_ref().unwrap();
+        let frame = |id: usize, addr: usize| json!({
+            "id": id,
+            "name": self.symbols.locate(addr),
+            "line": 0,
+            "column": 0,
+            "instructionPointerReference": addr.to_string(),
+        });
+        let mut frames = vec![frame(0, vm.pc())];
+        for (k, &v) in vm.stack().iter().enumerate().rev() {
+            let v = v as usize;
+            if v >= 2 && v < vm.memory().len() && vm.memory()[v - 2] == InstructionCode::CALL as u16 {
+                frames.push(frame(k + 1, v - 2));
+            }
+        }
+        frames
+    }
+
+    fn variables(&self, reference: i64) -> Vec<Value> {
+        let vm = self.vm.as_ref().unwrap();
+        let variable = |name: String, value: String| json!({ "name": name, "value": value, "variablesReference": 0 });
+        match reference {
+            REGISTERS_REF => {
+                let mut variables = vec![variable("pc".to_string(), format!("{} ({})", vm.pc(), self.symbols.locate(vm.pc())))];
+                for (r, v) in vm.registers().iter().enumerate() {
+                    variables.push(variable(format!("R{}", r), v.to_string()));
+                }
+                variables
+            },
+            STACK_REF => vm.stack().iter().enumerate().rev()
+                .map(|(k, v)| variable(format!("[{}]", k), v.to_string()))
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// `instructionCount` instructions around `memoryReference` + `offset`,
+    /// starting `instructionOffset` instructions away, which may be before.
+    fn disassemble(&self, args: &Value) -> Vec<Value> {
+        let memory = self.vm.as_ref().unwrap().memory();
+        let base = args["memoryReference"].as_str().and_then(|r| self.symbols.resolve(r)).unwrap_or(0) as i64
+            + args["offset"].as_i64().unwrap_or(0);
+        let base = base.clamp(0, memory.len() as i64 - 1) as usize;
+        let count = args["instructionCount"].as_u64().unwrap_or(0) as usize;
+        let skip = args["instructionOffset"].as_i64().unwrap_or(0).clamp(-(memory.len() as i64), memory.len() as i64);
+
+        let mut addrs = Vec::new();
+        let mut a = base;
+        if skip < 0 {
+            let wanted = (-skip) as usize;
+            addrs = Instruction::before(memory, base, wanted);
+            // Words that don't decode into pc become single-word data lines
+            let first = addrs.first().cloned().unwrap_or(base);
+            let missing = wanted - addrs.len();
+            let mut data: Vec<usize> = (first.saturating_sub(missing)..first).collect();
+            data.append(&mut addrs);
+            addrs = data;
+        } else {
+            for _ in 0..skip {
+                if a >= memory.len() {
+                    break;
+                }
+                a += Instruction::at(memory, a).map(|i| i.operator.size()).unwrap_or(1);
+            }
+        }
+        while addrs.len() < count && a < memory.len() {
+            addrs.push(a);
+            a += Instruction::at(memory, a).map(|i| i.operator.size()).unwrap_or(1);
+        }
+
+        addrs.into_iter().take(count)
+            .map(|a| {
+                let (text, size) = match Instruction::at(memory, a) {
+                    Some(i) => (self.symbols.instruction_str(&i), i.operator.size()),
+                    None => (format!(".word {}", memory[a]), 1),
+                };
+                let bytes: String = memory[a..a + size].iter().map(|w| format!("{:04x}", w)).collect();
+                let mut instruction = json!({ "address": a.to_string(), "instruction": text, "instructionBytes": bytes });
+                if let Some(name) = self.symbols.name(a) {
+                    instruction["symbol"] = json!(name);
+                }
+                instruction
+            })
+            .collect()
+    }
+
+    /// A register's value, or the word at an address or symbol.
+    fn evaluate(&self, expression: &str) -> Option<String> {
+        let vm = self.vm.as_ref().unwrap();
+        let expression = expression.trim();
+        if expression.eq_ignore_ascii_case("pc") {
+            return Some(vm.pc().to_string());
+        }
+        if let Some(r) = expression.strip_prefix('R').or_else(|| expression.strip_prefix('r')).and_then(|r| r.parse::<usize>().ok()) {
+            return vm.registers().get(r).map(|v| v.to_string());
+        }
+        let addr = self.symbols.resolve(expression).filter(|&a| a < vm.memory().len())?;
+        Some(format!("mem[{}] = {}", addr, vm.memory()[addr]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages(out: &[u8]) -> Vec<Value> {
+        let mut input = io::Cursor::new(out);
+        let mut messages = Vec::new();
+        while let Some(m) = read_message(&mut input).unwrap() {
+            messages.push(m);
+        }
+        messages
+    }
+
+    #[test]
+    fn test_framing() {
+        let mut out = Vec::new();
+        write_message(&mut out, &json!({ "command": "threads" })).unwrap();
+        assert!(out.starts_with(b"Content-Length: 21\r\n\r\n"));
+        assert_eq!(messages(&out), vec![json!({ "command": "threads" })]);
+    }
+
+    #[test]
+    fn test_session() {
+        let mut dap = DapServer::new(Vec::new(), Symbols::default(), ExecutionPolicy::Strict);
+        let mut seq = 0;
+        let mut request = |dap: &mut DapServer<Vec<u8>>, command: &str, arguments: Value| {
+            seq += 1;
+            dap.handle(&json!({ "seq": seq, "type": "request", "command": command, "arguments": arguments })).unwrap();
+        };
+        request(&mut dap, "initialize", json!({}));
+        request(&mut dap, "launch", json!({ "program": "challenge.bin", "symbols": "symbols.json" }));
+        request(&mut dap, "setFunctionBreakpoints", json!({ "breakpoints": [{ "name": "print_string" }] }));
+        request(&mut dap, "configurationDone", json!({}));
+        while dap.running.is_some() {
+            dap.run(SLICE).unwrap();
+        }
+        request(&mut dap, "stackTrace", json!({ "threadId": THREAD }));
+        request(&mut dap, "variables", json!({ "variablesReference": REGISTERS_REF }));
+        request(&mut dap, "disassemble", json!({ "memoryReference": "print_string", "instructionOffset": -1, "instructionCount": 3 }));
+
+        let out = messages(&dap.out);
+        assert!(out.iter().all(|m| m["success"] != json!(false)), "{:?}", out);
+        let stopped = out.iter().find(|m| m["event"] == "stopped").unwrap();
+        assert_eq!(stopped["body"]["reason"], "breakpoint");
+        let n = out.len();
+        assert_eq!(out[n - 3]["body"]["stackFrames"][0]["name"], "print_string");
+        assert_eq!(out[n - 2]["body"]["variables"][0]["value"], "1458 (print_string)");
+        let instructions = out[n - 1]["body"]["instructions"].as_array().unwrap();
+        assert_eq!((instructions.len(), instructions[1]["address"].as_str()), (3, Some("1458")));
+        assert_eq!(instructions[1]["instruction"], "PUSH R0");
+
+        // Offsets far outside memory are clamped rather than overflowing or spinning
+        request(&mut dap, "disassemble", json!({ "memoryReference": "0", "instructionOffset": i64::MIN, "instructionCount": 2 }));
+        request(&mut dap, "disassemble", json!({ "memoryReference": "0", "instructionOffset": -4611686018427387904i64, "instructionCount": 2 }));
+        request(&mut dap, "disassemble", json!({ "memoryReference": "0", "instructionOffset": i64::MAX, "instructionCount": 2 }));
+        let out = messages(&dap.out);
+        let n = out.len();
+        let counts: Vec<_> = out[n - 3..].iter().map(|m| m["body"]["instructions"].as_array().map(Vec::len)).collect();
+        assert_eq!(counts, vec![Some(2), Some(2), Some(0)]);
+    }
+
+    #[test]
+    fn test_launch_policy() {
+        let mut dap = DapServer::new(Vec::new(), Symbols::default(), ExecutionPolicy::Strict);
+        dap.handle(&json!({ "seq": 1, "type": "request", "command": "launch", "arguments": {} })).unwrap();
+        assert_eq!(dap.vm.as_ref().map(Vm::policy), Some(ExecutionPolicy::Strict));
+        dap.handle(&json!({ "seq": 2, "type": "request", "command": "launch", "arguments": { "policy": "compat" } })).unwrap();
+        assert_eq!(dap.vm.as_ref().map(Vm::policy), Some(ExecutionPolicy::Compat));
+        dap.handle(&json!({ "seq": 3, "type": "request", "command": "launch", "arguments": { "policy": "sloppy" } })).unwrap();
+
+        let out = messages(&dap.out);
+        assert_eq!(out.iter().map(|m| m["success"].as_bool()).collect::<Vec<_>>(), vec![Some(true), Some(true), Some(false)]);
+    }
+}
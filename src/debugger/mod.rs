@@ -0,0 +1,358 @@
+use log::{trace, debug, info, warn, error};
+use crate::controller::{self, Command, StopReason, VmController};
+use crate::symbols::Symbols;
+use crate::util::event::{Config, Event, Events};
+use crate::vm::{Instruction, Snapshot, Vm, MAX_VAL};
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::io;
+use std::time::Duration;
+use termion::event::Key;
+use termion::raw::IntoRawMode;
+use termion::screen::AlternateScreen;
+use tui::backend::{Backend, TermionBackend};
+use tui::layout::{Constraint, Direction, Layout, Rect};
+use tui::style::{Color, Modifier, Style};
+use tui::text::{Span, Spans};
+use tui::widgets::{Block, Borders, Paragraph};
+use tui::{Frame, Terminal};
+
+#[allow(dead_code)]
+fn _get_rid_of_log_unused_import_warnings() {
+    trace!("Example trace.");
+    debug!("Example debug.");
+    info!("Example info.");
+    warn!("Example warn.");
+    error!("Example error.");
+}
+
+/// Game output kept for the output pane, in bytes.
+const MAX_OUTPUT: usize = 64 * 1024;
+
+/// Instructions shown above pc in the disassembly pane.
+const LINES_BEFORE: usize = 6;
+
+/// Full-screen debugger: game output, disassembly around pc, registers,
+/// stack, a memory dump and a command line, while the VM runs on its own
+/// thread.
+pub struct Debugger {
+    controller: VmController,
+    snapshot: Box<Snapshot>,
+    breakpoints: BTreeSet<usize>,
+    output: String,
+    status: String,
+    symbols: Symbols,
+    command: String,
+    message: String,
+    memory_at: Option<usize>, // Follows pc when None
+    scroll: usize,            // Output lines scrolled back
+}
+
+impl Debugger {
+    pub fn new(vm: Vm, symbols: Symbols) -> Debugger {
+        Debugger {
+            snapshot: Box::new(vm.snapshot()),
+            controller: VmController::new(vm),
+            breakpoints: BTreeSet::new(),
+            output: String::new(),
+            status: "Paused".to_string(),
+            symbols,
+            command: String::new(),
+            message: "Type game input, or !help".to_string(),
+            memory_at: None,
+            scroll: 0,
+        }
+    }
+
+    pub fn run(&mut self) -> Result<(), Box<dyn Error>> {
+        let stdout = AlternateScreen::from(io::stdout().into_raw_mode()?);
+        let mut terminal = Terminal::new(TermionBackend::new(stdout))?;
+        let events = Events::with_config(Config {
+            exit_key: Key::Ctrl('c'),
+            tick_rate: Duration::from_millis(100),
+        });
+
+        loop {
+            self.poll();
+            terminal.draw(|f| self.draw(f))?;
+            match events.next()? {
+                Event::Input(key) => {
+                    if !self.handle_key(key) {
+                        break;
+                    }
+                },
+                Event::Tick => {},
+            }
+            self.controller.send(Command::Snapshot);
+        }
+        Ok(())
+    }
+
+    /// Takes in whatever the controller has reported since last time.
+    fn poll(&mut self) {
+        while let Some(event) = self.controller.try_event() {
+            match event {
+                controller::Event::Output(text) => {
+                    self.output.push_str(&text);
+                    if self.output.len() > MAX_OUTPUT {
+                        let mut cut = self.output.len() - MAX_OUTPUT;
+                        while !self.output.is_char_boundary(cut) {
+                            cut += 1;
+                        }
+                        self.output.drain(..cut);
+                    }
+                },
+                controller::Event::Stopped(reason) => {
+                    self.status = match reason {
+                        StopReason::Paused | StopReason::Stepped => "Paused".to_string(),
+                        StopReason::Breakpoint(pc) => format!("Breakpoint at {}", self.symbols.locate(pc)),
+                        StopReason::Violation(pc, v) => format!("Stopped at {}: {}", pc, v),
+                        StopReason::Halted => "Halted".to_string(),
+                        StopReason::Input => "Waiting for input".to_string(),
+                    };
+                },
+                controller::Event::NeedsInput => self.status = "Waiting for input".to_string(),
+                controller::Event::Snapshot(snapshot) => self.snapshot = snapshot,
+            }
+        }
+    }
+
+    fn resume(&mut self) {
+        self.controller.send(Command::Run);
+        self.status = "Running".to_string();
+    }
+
+    /// Returns false to quit.
+    fn handle_key(&mut self, key: Key) -> bool {
+        match key {
+            Key::Char('\n') => {
+                let command: String = self.command.drain(..).collect();
+                return self.execute(command.trim());
+            },
+            Key::Char(c) => self.command.push(c),
+            Key::Backspace => {
+                self.command.pop();
+            },
+            Key::Ctrl('a') => self.pause(),
+            Key::F(5) => self.resume(),
+            Key::F(10) => self.step(1),
+            Key::PageUp => self.scroll += 10,
+            Key::PageDown => self.scroll = self.scroll.saturating_sub(10),
+            Key::Ctrl('c') | Key::Esc => return false,
+            _ => {},
+        }
+        true
+    }
+
+    /// Runs a `!` command, or sends anything else to the game.
+    fn execute(&mut self, command: &str) -> bool {
+        let mut words = command.split_whitespace();
+        let first = words.next();
+        let arg = words.next().map(String::from);
+        match first {
+            Some("!run") | Some("!continue") => self.resume(),
+            Some("!pause") => self.pause(),
+            Some("!step") => {
+                let n = arg.and_then(|n| n.parse().ok()).unwrap_or(1);
+                self.step(n);
+            },
+            Some("!break") => {
+                if let Some(addr) = self.address(arg) {
+                    self.breakpoints.insert(addr);
+                    self.controller.send(Command::SetBreakpoint(addr));
+                    self.message = format!("Breakpoint at {}", self.symbols.locate(addr));
+                }
+            },
+            Some("!delete") => {
+                if let Some(addr) = self.address(arg) {
+                    self.breakpoints.remove(&addr);
+                    self.controller.send(Command::ClearBreakpoint(addr));
+                    self.message = format!("Deleted breakpoint at {}", self.symbols.locate(addr));
+                }
+            },
+            Some("!mem") => {
+                self.memory_at = match arg {
+                    Some(word) if word == "pc" => None,
+                    word => match self.address(word) {
+                        Some(addr) => Some(addr),
+                        None => return true,
+                    },
+                };
+            },
+            Some("!reset") => {
+                self.controller.send(Command::Reset);
+                self.output.clear();
+                self.status = "Paused".to_string();
+            },
+            Some("!quit") => return false,
+            Some("!help") => {
+                self.message = "!run !pause !step [n] !break/!delete <addr|symbol> !mem <addr|symbol|pc> !reset !quit; F5 run, F10 step, ^A pause".to_string();
+            },
+            Some(word) if word.starts_with('!') => self.message = format!("Unknown command {}", word),
+            _ => {
+                self.output.push_str(&format!("{}\n", command));
+                self.controller.send(Command::Input(format!("{}\n", command)));
+                self.resume();
+                self.scroll = 0;
+            },
+        }
+        true
+    }
+
+    fn address(&mut self, word: Option<String>) -> Option<usize> {
+        let word = word.unwrap_or_default();
+        let addr = self.symbols.resolve(&word).filter(|&a| a < MAX_VAL);
+        if addr.is_none() {
+            self.message = format!("No address or symbol {:?}", word);
+        }
+        addr
+    }
+
+    fn pause(&mut self) {
+        self.controller.send(Command::Pause);
+    }
+
+    fn step(&mut self, n: usize) {
+        self.controller.send(Command::Step(n));
+    }
+
+    fn draw<B: Backend>(&self, f: &mut Frame<B>) {
+        let s = &self.snapshot;
+        let rows = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Min(8), Constraint::Length(14), Constraint::Length(3)].as_ref())
+            .split(f.size());
+        let top = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Min(40), Constraint::Length(30)].as_ref())
+            .split(rows[0]);
+        let side = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Length(11), Constraint::Min(3)].as_ref())
+            .split(top[1]);
+        let middle = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Percentage(50), Constraint::Percentage(50)].as_ref())
+            .split(rows[1]);
+
+        self.draw_output(f, top[0]);
+        self.draw_registers(f, s, side[0]);
+        self.draw_stack(f, s, side[1]);
+        self.draw_disassembly(f, s, middle[0]);
+        self.draw_memory(f, s, middle[1]);
+
+        let title = format!(" {} | {} ", self.status, self.message);
+        let line = Paragraph::new(format!("> {}", self.command))
+            .block(Block::default().borders(Borders::ALL).title(title));
+        f.render_widget(line, rows[2]);
+        f.set_cursor(rows[2].x + 3 + self.command.chars().count() as u16, rows[2].y + 1);
+    }
+
+    fn draw_output<B: Backend>(&self, f: &mut Frame<B>, area: Rect) {
+        let height = area.height.saturating_sub(2) as usize;
+        let lines = wrap(&self.output, area.width.saturating_sub(2) as usize);
+        let end = lines.len().saturating_sub(self.scroll);
+        let text: Vec<Spans> = lines[end.saturating_sub(height)..end].iter()
+            .map(|l| Spans::from(l.clone()))
+            .collect();
+        let title = if self.scroll > 0 { format!(" Output (-{}) ", self.scroll) } else { " Output ".to_string() };
+        f.render_widget(Paragraph::new(text).block(Block::default().borders(Borders::ALL).title(title)), area);
+    }
+
+    fn draw_registers<B: Backend>(&self, f: &mut Frame<B>, s: &Snapshot, area: Rect) {
+        let mut text = vec![Spans::from(format!("pc {:5} {}", s.pc(), self.symbols.locate(s.pc())))];
+        for (r, v) in s.registers().iter().enumerate() {
+            text.push(Spans::from(format!("R{} {:5} {:04x}", r, v, v)));
+        }
+        f.render_widget(Paragraph::new(text).block(Block::default().borders(Borders::ALL).title(" Registers ")), area);
+    }
+
+    fn draw_stack<B: Backend>(&self, f: &mut Frame<B>, s: &Snapshot, area: Rect) {
+        let stack = s.stack();
+        let text: Vec<Spans> = stack.iter().enumerate().rev()
+            .take(area.height.saturating_sub(2) as usize)
+            .map(|(k, v)| Spans::from(format!("{:3} {:5} {}", k, v, self.symbols.name(*v as usize).unwrap_or(""))))
+            .collect();
+        let title = format!(" Stack ({}) ", stack.len());
+        f.render_widget(Paragraph::new(text).block(Block::default().borders(Borders::ALL).title(title)), area);
+    }
+
+    fn draw_disassembly<B: Backend>(&self, f: &mut Frame<B>, s: &Snapshot, area: Rect) {
+        let memory = s.memory();
+        let pc = s.pc();
+        let height = area.height.saturating_sub(2) as usize;
+        let mut addrs = Instruction::before(memory, pc, LINES_BEFORE);
+        let mut a = pc;
+        while addrs.len() < height && a < memory.len() {
+            addrs.push(a);
+            a += Instruction::at(memory, a).map(|i| i.operator.size()).unwrap_or(1);
+        }
+
+        let mut text = Vec::new();
+        for a in addrs {
+            if let Some(name) = self.symbols.name(a) {
+                text.push(Spans::from(format!("{}:", name)));
+            }
+            let (instruction, style) = match Instruction::at(memory, a) {
+                Some(i) => (self.symbols.instruction_str(&i), Style::default()),
+                None => (format!("{}", memory[a]), Style::default().fg(Color::DarkGray)),
+            };
+            let bp = self.breakpoints.contains(&a);
+            let style = match (a == pc, bp) {
+                (true, _) => style.fg(Color::Yellow).add_modifier(Modifier::BOLD),
+                (false, true) => style.fg(Color::Red),
+                _ => style,
+            };
+            let marker = format!("{}{}", if bp { "*" } else { " " }, if a == pc { ">" } else { " " });
+            text.push(Spans::from(Span::styled(format!("{} {:5} {}", marker, a, instruction), style)));
+        }
+        f.render_widget(Paragraph::new(text).block(Block::default().borders(Borders::ALL).title(" Disassembly ")), area);
+    }
+
+    fn draw_memory<B: Backend>(&self, f: &mut Frame<B>, s: &Snapshot, area: Rect) {
+        let memory = s.memory();
+        let start = self.memory_at.unwrap_or_else(|| s.pc()) / 8 * 8;
+        let text: Vec<Spans> = (start..memory.len()).step_by(8)
+            .take(area.height.saturating_sub(2) as usize)
+            .map(|row| {
+                let words = &memory[row..(row + 8).min(memory.len())];
+                let hex: Vec<String> = words.iter().map(|w| format!("{:04x}", w)).collect();
+                let ascii: String = words.iter()
+                    .map(|&w| if (32..127).contains(&w) { w as u8 as char } else { '.' })
+                    .collect();
+                Spans::from(format!("{:5}: {} {}", row, hex.join(" "), ascii))
+            })
+            .collect();
+        let title = match self.memory_at {
+            Some(_) => format!(" Memory @ {} ", start),
+            None => " Memory @ pc ".to_string(),
+        };
+        f.render_widget(Paragraph::new(text).block(Block::default().borders(Borders::ALL).title(title)), area);
+    }
+}
+
+/// `text` split into lines no wider than `width`.
+fn wrap(text: &str, width: usize) -> Vec<String> {
+    let width = width.max(1);
+    let mut lines = Vec::new();
+    for line in text.split('\n') {
+        let chars: Vec<char> = line.chars().collect();
+        if chars.is_empty() {
+            lines.push(String::new());
+        }
+        for chunk in chars.chunks(width) {
+            lines.push(chunk.iter().collect());
+        }
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wrap() {
+        assert_eq!(wrap("abcde\n\nfg", 2), vec!["ab", "cd", "e", "", "fg"]);
+    }
+}
@@ -0,0 +1,180 @@
+use crate::vm::MAX_VAL;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinOp {
+    Add,
+    Mult,
+    Mod,
+    And,
+    Or,
+    Eq,
+    Gt,
+}
+
+impl BinOp {
+    fn symbol(self) -> &'static str {
+        match self {
+            BinOp::Add => "+",
+            BinOp::Mult => "*",
+            BinOp::Mod => "%",
+            BinOp::And => "&",
+            BinOp::Or => "|",
+            BinOp::Eq => "==",
+            BinOp::Gt => ">",
+        }
+    }
+}
+
+/// A value computed from registers and memory as they were when the
+/// enclosing block's pending assignments started.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Lit(u16),
+    Reg(u8),
+    Bin(BinOp, Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Load(Box<Expr>),
+    Saved(u8), // Local holding a register across a call
+}
+
+impl Expr {
+    /// `a op b`, worked out now if both are literals.
+    pub fn bin(op: BinOp, a: Expr, b: Expr) -> Expr {
+        let m = MAX_VAL as u32;
+        match (&a, &b) {
+            (Expr::Lit(x), Expr::Lit(y)) if op != BinOp::Mod || *y != 0 => {
+                let (x, y) = (*x as u32, *y as u32);
+                Expr::Lit(match op {
+                    BinOp::Add => (x + y) % m,
+                    BinOp::Mult => (x * y) % m,
+                    BinOp::Mod => x % y,
+                    BinOp::And => x & y,
+                    BinOp::Or => x | y,
+                    BinOp::Eq => (x == y) as u32,
+                    BinOp::Gt => (x > y) as u32,
+                } as u16)
+            },
+            (_, Expr::Lit(0)) if op == BinOp::Add => a,
+            (Expr::Lit(0), _) if op == BinOp::Add => b,
+            _ => Expr::Bin(op, Box::new(a), Box::new(b)),
+        }
+    }
+
+    pub fn not(a: Expr) -> Expr {
+        match a {
+            Expr::Lit(x) => Expr::Lit(!x & (MAX_VAL - 1) as u16),
+            a => Expr::Not(Box::new(a)),
+        }
+    }
+
+    pub fn uses(&self, r: u8) -> bool {
+        match self {
+            Expr::Lit(_) => false,
+            Expr::Reg(q) => *q == r,
+            Expr::Bin(_, a, b) => a.uses(r) || b.uses(r),
+            Expr::Not(a) | Expr::Load(a) => a.uses(r),
+            Expr::Saved(_) => false,
+        }
+    }
+
+    pub fn loads(&self) -> bool {
+        match self {
+            Expr::Lit(_) | Expr::Reg(_) | Expr::Saved(_) => false,
+            Expr::Bin(_, a, b) => a.loads() || b.loads(),
+            Expr::Not(a) => a.loads(),
+            Expr::Load(_) => true,
+        }
+    }
+
+    /// C condition that holds when the value is nonzero, or when it is
+    /// zero if `nonzero` is false.
+    pub fn test(&self, nonzero: bool) -> String {
+        match (self, nonzero) {
+            (Expr::Bin(BinOp::Eq, a, b), true) => format!("{} == {}", Operand(a), Operand(b)),
+            (Expr::Bin(BinOp::Eq, a, b), false) => format!("{} != {}", Operand(a), Operand(b)),
+            (Expr::Bin(BinOp::Gt, a, b), true) => format!("{} > {}", Operand(a), Operand(b)),
+            (Expr::Bin(BinOp::Gt, a, b), false) => format!("{} <= {}", Operand(a), Operand(b)),
+            (e, true) => format!("{} != 0", Operand(e)),
+            (e, false) => format!("{} == 0", Operand(e)),
+        }
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Expr::Lit(v) => write!(f, "{}", v),
+            Expr::Reg(r) => write!(f, "r{}", r),
+            // Adding a large literal is how the code subtracts
+            Expr::Bin(BinOp::Add, a, b) if matches!(**b, Expr::Lit(v) if v as usize > MAX_VAL / 2) => {
+                let v = match **b { Expr::Lit(v) => v, _ => unreachable!() };
+                write!(f, "{} - {}", Operand(a), MAX_VAL - v as usize)
+            },
+            Expr::Bin(op, a, b) => write!(f, "{} {} {}", Operand(a), op.symbol(), Operand(b)),
+            Expr::Not(a) => write!(f, "~{}", Operand(a)),
+            Expr::Load(a) => write!(f, "mem[{}]", a),
+            Expr::Saved(r) => write!(f, "saved_r{}", r),
+        }
+    }
+}
+
+/// An expression parenthesised where it is a subexpression.
+struct Operand<'a>(&'a Expr);
+
+impl<'a> fmt::Display for Operand<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Expr::Bin(..) => write!(f, "({})", self.0),
+            e => write!(f, "{}", e),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stmt {
+    Assign(u8, Expr),
+    Store(Expr, Expr),
+    Push(Expr),
+    Pop(u8),
+    Save(u8, Expr),
+    In(u8),
+    Out(Expr),
+    Call(Expr, Vec<u8>), // Target and the registers saved around it
+}
+
+impl fmt::Display for Stmt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Stmt::Assign(r, e) => write!(f, "r{} = {};", r, e),
+            Stmt::Store(a, v) => write!(f, "mem[{}] = {};", a, v),
+            Stmt::Push(e) => write!(f, "push({});", e),
+            Stmt::Pop(r) => write!(f, "r{} = pop();", r),
+            Stmt::Save(r, e) => write!(f, "saved_r{} = {};", r, e),
+            Stmt::In(r) => write!(f, "r{} = getchar();", r),
+            Stmt::Out(Expr::Lit(c)) if *c < 128 => write!(f, "putchar({:?});", *c as u8 as char),
+            Stmt::Out(e) => write!(f, "putchar({});", e),
+            Stmt::Call(target, saved) => {
+                match target {
+                    Expr::Lit(t) => write!(f, "fn_{}();", t)?,
+                    e => write!(f, "(*{})();", Operand(e))?,
+                }
+                if !saved.is_empty() {
+                    write!(f, " // preserves {}", super::regs_str(saved))?;
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+/// How control leaves a block.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Term {
+    Goto(usize),
+    Jump(Expr), // Through a register
+    Branch { cond: Expr, nonzero: bool, target: usize, next: usize },
+    BranchIndirect { cond: Expr, nonzero: bool, target: Expr, next: usize },
+    Return,
+    Halt,
+}
@@ -0,0 +1,808 @@
+use log::{trace, debug, info, warn, error};
+use crate::cfg::{BasicBlock, Cfg, EdgeKind, Target};
+use crate::vm::{Instruction, InstructionCode, MAX_VAL};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{self, Write};
+
+mod ir;
+
+use ir::{BinOp, Expr, Stmt, Term};
+
+#[allow(dead_code)]
+fn _get_rid_of_log_unused_import_warnings() {
+    trace!("Example trace.");
+    debug!("Example debug.");
+    info!("Example info.");
+    warn!("Example warn.");
+    error!("Example error.");
+}
+
+type Regs = u8; // Bit set of registers
+
+const ALL_REGS: Regs = 0xff;
+
+fn reg(v: u16) -> Option<u8> {
+    match v as usize {
+        v if (MAX_VAL..MAX_VAL + 8).contains(&v) => Some((v - MAX_VAL) as u8),
+        _ => None,
+    }
+}
+
+fn bit(v: u16) -> Regs {
+    reg(v).map_or(0, |r| 1 << r)
+}
+
+/// Registers `i` reads and writes.  Calls and returns read everything,
+/// since nothing is known about the calling convention.
+fn reads_writes(i: &Instruction) -> (Regs, Regs) {
+    let (a, b, c) = i.operands;
+    match i.operator {
+        InstructionCode::HALT | InstructionCode::NOOP => (0, 0),
+        InstructionCode::SET | InstructionCode::NOT | InstructionCode::RMEM => (bit(b), bit(a)),
+        InstructionCode::EQ | InstructionCode::GT | InstructionCode::ADD | InstructionCode::MULT |
+        InstructionCode::MOD | InstructionCode::AND | InstructionCode::OR => (bit(b) | bit(c), bit(a)),
+        InstructionCode::PUSH | InstructionCode::JMP | InstructionCode::OUT => (bit(a), 0),
+        InstructionCode::POP | InstructionCode::IN => (0, bit(a)),
+        InstructionCode::WMEM | InstructionCode::JT | InstructionCode::JF => (bit(a) | bit(b), 0),
+        InstructionCode::CALL | InstructionCode::RET => (ALL_REGS, 0),
+    }
+}
+
+fn regs_str(regs: &[u8]) -> String {
+    regs.iter().map(|r| format!("r{}", r)).collect::<Vec<_>>().join(", ")
+}
+
+/// A function's blocks with register saves taken out, ready to lift.
+struct Prepared {
+    code: BTreeMap<usize, Vec<(usize, Instruction)>>,
+    succs: BTreeMap<usize, Vec<usize>>, // Only those inside the function
+    leaves: BTreeSet<usize>,            // Blocks with a successor outside the function
+    saves: Vec<u8>,                     // Pushed on entry, popped before every RET
+    call_saves: BTreeMap<usize, Vec<u8>>, // By CALL address
+    spills: BTreeSet<usize>,  // PUSH/POP addresses standing for a local
+    dropped: BTreeSet<usize>, // PUSH/POP addresses left out entirely
+}
+
+fn pushed(code: &[(usize, Instruction)]) -> Vec<u8> {
+    code.iter()
+        .take_while(|(_, i)| i.operator == InstructionCode::PUSH && reg(i.operands.0).is_some())
+        .filter_map(|(_, i)| reg(i.operands.0))
+        .collect()
+}
+
+fn popped(code: &[(usize, Instruction)]) -> Vec<u8> {
+    code.iter()
+        .take_while(|(_, i)| i.operator == InstructionCode::POP && reg(i.operands.0).is_some())
+        .filter_map(|(_, i)| reg(i.operands.0))
+        .collect()
+}
+
+impl Prepared {
+    fn new(cfg: &Cfg, entry: usize) -> Prepared {
+        let f = cfg.function(entry).unwrap();
+        let blocks: Vec<&BasicBlock> = f.blocks.iter().map(|s| &cfg.blocks[s]).collect();
+        let inside: BTreeSet<usize> = f.blocks.iter().cloned().collect();
+
+        let mut prepared = Prepared {
+            code: blocks.iter().map(|b| (b.start, b.instructions.clone())).collect(),
+            succs: BTreeMap::new(),
+            leaves: BTreeSet::new(),
+            saves: Vec::new(),
+            call_saves: BTreeMap::new(),
+            spills: BTreeSet::new(),
+            dropped: BTreeSet::new(),
+        };
+        for b in &blocks {
+            let mut succs = Vec::new();
+            for e in cfg.successors(b.start).filter(|e| e.kind != EdgeKind::Call) {
+                match e.to {
+                    Target::Address(to) if inside.contains(&to) => succs.push(to),
+                    _ => { prepared.leaves.insert(b.start); },
+                }
+            }
+            prepared.succs.insert(b.start, succs);
+        }
+        prepared.strip_saves(&blocks);
+        let dropped = prepared.dropped.clone();
+        for code in prepared.code.values_mut() {
+            code.retain(|(pc, _)| !dropped.contains(pc));
+        }
+        prepared
+    }
+
+    /// Drops PUSH/POP pairs that only preserve registers: a run of pushes
+    /// on entry undone before every RET, and pushes just before a CALL
+    /// undone just after it.
+    fn strip_saves(&mut self, blocks: &[&BasicBlock]) {
+        let entry = blocks[0].start;
+        let mut saves = pushed(&self.code[&entry]);
+        let rets: Vec<usize> = blocks.iter()
+            .filter(|b| b.last().operator == InstructionCode::RET)
+            .map(|b| b.start)
+            .collect();
+        let restored = |code: &Vec<(usize, Instruction)>, saves: &[u8]| {
+            let body = &code[..code.len() - 1];
+            body.len() >= saves.len() && popped(&body[body.len() - saves.len()..]).iter().rev().eq(saves.iter())
+        };
+        // Keep the longest prefix of pushes that every return undoes
+        while !saves.is_empty() && (rets.is_empty() || !rets.iter().all(|r| restored(&self.code[r], &saves))) {
+            saves.pop();
+        }
+        let n = saves.len();
+        if n > 0 && !(rets.contains(&entry) && self.code[&entry].len() < 2 * n + 1) {
+            self.code.get_mut(&entry).unwrap().drain(..n);
+            for r in &rets {
+                let code = self.code.get_mut(r).unwrap();
+                let len = code.len();
+                code.drain(len - 1 - n..len - 1);
+            }
+            self.saves = saves;
+        }
+
+        for b in blocks.iter().filter(|b| b.last().operator == InstructionCode::CALL) {
+            if let Some(after) = self.code.get(&b.end) {
+                self.match_call_saves(&self.code[&b.start].clone(), &after.clone());
+            }
+        }
+    }
+
+    /// Pairs the last pushes before a CALL with the first pops after it.
+    /// Where nothing else touches the register in between, the pair just
+    /// preserves it across the call and is dropped; otherwise it becomes a
+    /// local.
+    fn match_call_saves(&mut self, code: &[(usize, Instruction)], after: &[(usize, Instruction)]) {
+        let stack_op = |i: &Instruction| matches!(i.operator,
+            InstructionCode::PUSH | InstructionCode::POP | InstructionCode::CALL | InstructionCode::RET);
+        let call = code.len() - 1;
+        let push_end = call - code[..call].iter().rev().take_while(|(_, i)| !stack_op(i)).count();
+        let push_start = push_end - code[..push_end].iter().rev()
+            .take_while(|(_, i)| i.operator == InstructionCode::PUSH && reg(i.operands.0).is_some())
+            .count();
+        let pop_start = after.iter().take_while(|(_, i)| !stack_op(i)).count();
+        let pops = popped(&after[pop_start..]);
+
+        let pushes = &code[push_start..push_end];
+        let mut saved = Vec::new();
+        let mut inner = None;
+        for (k, (push, i)) in pushes.iter().rev().enumerate().take(pops.len()) {
+            let r = pops[k];
+            if reg(i.operands.0) != Some(r) {
+                break;
+            }
+            let (pop, _) = after[pop_start + k];
+            if inner == Some(r) {
+                // Pushed twice in a row, so both pops restore the same value
+                self.dropped.insert(*push);
+                self.dropped.insert(pop);
+                continue;
+            }
+            inner = Some(r);
+            let written = code[push_start + pushes.len() - k..call].iter().any(|(_, i)| reads_writes(i).1 & (1 << r) != 0);
+            let touched = after[..pop_start].iter().any(|(_, i)| {
+                let (reads, writes) = reads_writes(i);
+                (reads | writes) & (1 << r) != 0
+            });
+            if written || touched {
+                self.spills.insert(*push);
+                self.spills.insert(pop);
+            } else {
+                self.dropped.insert(*push);
+                self.dropped.insert(pop);
+                saved.insert(0, r);
+            }
+        }
+        if !saved.is_empty() {
+            self.call_saves.insert(code[call].0, saved);
+        }
+    }
+
+    /// Registers live on entry to each block.
+    fn liveness(&self) -> BTreeMap<usize, Regs> {
+        let mut live_in: BTreeMap<usize, Regs> = self.code.keys().map(|&b| (b, 0)).collect();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (&b, code) in self.code.iter().rev() {
+                let mut live = self.live_out(b, &live_in);
+                for (_, i) in code.iter().rev() {
+                    let (r, w) = self.reads_writes(i);
+                    live = (live & !w) | r;
+                }
+                if live != live_in[&b] {
+                    live_in.insert(b, live);
+                    changed = true;
+                }
+            }
+        }
+        live_in
+    }
+
+    /// Like `reads_writes`, except registers the function preserves are
+    /// dead at its returns.
+    fn reads_writes(&self, i: &Instruction) -> (Regs, Regs) {
+        match i.operator {
+            InstructionCode::RET => (ALL_REGS & !self.saves.iter().fold(0, |m, r| m | 1 << r), 0),
+            _ => reads_writes(i),
+        }
+    }
+
+    fn live_out(&self, b: usize, live_in: &BTreeMap<usize, Regs>) -> Regs {
+        let mut live = if self.leaves.contains(&b) { ALL_REGS } else { 0 };
+        for s in &self.succs[&b] {
+            live |= live_in[s];
+        }
+        live
+    }
+}
+
+/// Turns one block into statements, folding register values into
+/// expressions until something forces them out.
+struct Lifter<'a> {
+    pending: Vec<(u8, Expr)>, // In definition order
+    stmts: Vec<Stmt>,
+    prepared: &'a Prepared,
+}
+
+impl<'a> Lifter<'a> {
+    fn val(&self, v: u16) -> Expr {
+        match reg(v) {
+            Some(r) => self.pending.iter().find(|(p, _)| *p == r)
+                .map_or(Expr::Reg(r), |(_, e)| e.clone()),
+            None => Expr::Lit(v),
+        }
+    }
+
+    /// Emits the pending values matching `pred`, plus any that need the old
+    /// contents of a register being assigned.
+    fn emit_where(&mut self, pred: impl Fn(u8, &Expr) -> bool) {
+        let mut out: Vec<bool> = self.pending.iter().map(|(r, e)| pred(*r, e)).collect();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for j in 0..self.pending.len() {
+                if !out[j] && (0..self.pending.len()).any(|i| out[i] && i != j && self.pending[j].1.uses(self.pending[i].0)) {
+                    out[j] = true;
+                    changed = true;
+                }
+            }
+        }
+        let (out, keep): (Vec<_>, Vec<_>) = self.pending.drain(..).zip(out).partition(|(_, o)| *o);
+        self.pending = keep.into_iter().map(|(p, _)| p).collect();
+        let mut out: Vec<(u8, Expr)> = out.into_iter().map(|(p, _)| p).collect();
+        // In definition order, except that a register is only assigned once
+        // nothing left needs its old value
+        while !out.is_empty() {
+            let k = (0..out.len())
+                .find(|&i| !out.iter().enumerate().any(|(j, (_, e))| j != i && e.uses(out[i].0)))
+                .unwrap_or(0);
+            let (r, e) = out.remove(k);
+            self.stmts.push(Stmt::Assign(r, e));
+        }
+    }
+
+    /// Emits any pending value that still needs the current contents of `r`.
+    fn clobber(&mut self, r: u8) {
+        self.emit_where(|p, e| p != r && e.uses(r));
+    }
+
+    fn define(&mut self, r: u8, e: Expr) {
+        self.clobber(r);
+        self.pending.retain(|(p, _)| *p != r);
+        self.pending.push((r, e));
+    }
+
+    fn write(&mut self, a: u16, e: Expr) {
+        if let Some(r) = reg(a) {
+            self.define(r, e);
+        }
+    }
+
+    fn lift(&mut self, pc: usize, i: &Instruction) {
+        let (a, b, c) = i.operands;
+        let bin = |op| Expr::bin(op, self.val(b), self.val(c));
+        match i.operator {
+            InstructionCode::SET => self.write(a, self.val(b)),
+            InstructionCode::EQ => self.write(a, bin(BinOp::Eq)),
+            InstructionCode::GT => self.write(a, bin(BinOp::Gt)),
+            InstructionCode::ADD => self.write(a, bin(BinOp::Add)),
+            InstructionCode::MULT => self.write(a, bin(BinOp::Mult)),
+            InstructionCode::MOD => self.write(a, bin(BinOp::Mod)),
+            InstructionCode::AND => self.write(a, bin(BinOp::And)),
+            InstructionCode::OR => self.write(a, bin(BinOp::Or)),
+            InstructionCode::NOT => self.write(a, Expr::not(self.val(b))),
+            InstructionCode::RMEM => self.write(a, Expr::Load(Box::new(self.val(b)))),
+            InstructionCode::WMEM => {
+                let (addr, v) = (self.val(a), self.val(b));
+                self.emit_where(|_, e| e.loads());
+                self.stmts.push(Stmt::Store(addr, v));
+            },
+            InstructionCode::PUSH => {
+                let v = self.val(a);
+                match reg(a) {
+                    // Re-saving a restored value changes nothing
+                    Some(r) if self.prepared.spills.contains(&pc) && v == Expr::Saved(r) => {},
+                    Some(r) if self.prepared.spills.contains(&pc) => self.stmts.push(Stmt::Save(r, v)),
+                    _ => self.stmts.push(Stmt::Push(v)),
+                }
+            },
+            InstructionCode::POP if self.prepared.spills.contains(&pc) => self.write(a, Expr::Saved(reg(a).unwrap())),
+            InstructionCode::POP | InstructionCode::IN => {
+                if let Some(r) = reg(a) {
+                    self.clobber(r);
+                    self.pending.retain(|(p, _)| *p != r);
+                    self.stmts.push(if i.operator == InstructionCode::POP { Stmt::Pop(r) } else { Stmt::In(r) });
+                }
+            },
+            InstructionCode::OUT => {
+                let v = self.val(a);
+                self.stmts.push(Stmt::Out(v));
+            },
+            InstructionCode::CALL => {
+                let target = self.val(a);
+                self.emit_where(|_, _| true);
+                let saved = self.prepared.call_saves.get(&pc).cloned().unwrap_or_default();
+                self.stmts.push(Stmt::Call(target, saved));
+            },
+            InstructionCode::NOOP | InstructionCode::HALT | InstructionCode::JMP |
+            InstructionCode::JT | InstructionCode::JF | InstructionCode::RET => {},
+        }
+    }
+}
+
+struct Lifted {
+    stmts: Vec<Stmt>,
+    term: Term,
+}
+
+fn lift_block(prepared: &Prepared, block: &BasicBlock, live_out: Regs) -> Lifted {
+    let code = &prepared.code[&block.start];
+    let mut lifter = Lifter {
+        pending: Vec::new(),
+        stmts: Vec::new(),
+        prepared,
+    };
+    for (pc, i) in code {
+        lifter.lift(*pc, i);
+    }
+
+    let last = block.last();
+    let (a, b, _) = last.operands;
+    let target = |lifter: &Lifter, v: u16| match reg(v) {
+        Some(_) => Err(lifter.val(v)),
+        None => Ok(v as usize),
+    };
+    let term = match last.operator {
+        InstructionCode::HALT => Term::Halt,
+        InstructionCode::RET => Term::Return,
+        InstructionCode::JMP => match target(&lifter, a) {
+            Ok(t) => Term::Goto(t),
+            Err(_) => {
+                lifter.emit_where(|_, _| true);
+                Term::Jump(lifter.val(a))
+            },
+        },
+        InstructionCode::JT | InstructionCode::JF => {
+            let cond = lifter.val(a);
+            // The test has to read the values from before the block's
+            // assignments, which only works if it doesn't mention them.
+            let cond = if lifter.pending.iter().any(|(r, _)| live_out & (1 << r) != 0 && cond.uses(*r)) {
+                lifter.emit_where(|_, _| true);
+                lifter.val(a)
+            } else {
+                cond
+            };
+            let nonzero = last.operator == InstructionCode::JT;
+            match target(&lifter, b) {
+                // The self-test checks JT/JF with literal conditions
+                Ok(t) if matches!(cond, Expr::Lit(_)) => {
+                    Term::Goto(if (cond != Expr::Lit(0)) == nonzero { t } else { block.end })
+                },
+                Ok(t) => Term::Branch { cond, nonzero, target: t, next: block.end },
+                Err(e) => Term::BranchIndirect { cond, nonzero, target: e, next: block.end },
+            }
+        },
+        _ => Term::Goto(block.end),
+    };
+    // Branch conditions were folded in above, so only returns add reads
+    let live = match last.operator {
+        InstructionCode::RET => live_out | prepared.reads_writes(last).0,
+        _ => live_out,
+    };
+    lifter.emit_where(|r, _| live & (1 << r) != 0);
+    Lifted {
+        stmts: lifter.stmts,
+        term,
+    }
+}
+
+/// Loop headers found from back edges, each with its body and the block
+/// control leaves it for, if there is only one.
+fn find_loops(entry: usize, succs: &BTreeMap<usize, Vec<usize>>) -> BTreeMap<usize, (BTreeSet<usize>, Option<usize>)> {
+    let mut latches: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+    let mut on_stack = BTreeSet::new();
+    let mut done = BTreeSet::new();
+    let mut stack = vec![(entry, 0)];
+    on_stack.insert(entry);
+    while let Some((b, k)) = stack.pop() {
+        match succs[&b].get(k) {
+            Some(&s) => {
+                stack.push((b, k + 1));
+                if on_stack.contains(&s) {
+                    latches.entry(s).or_default().push(b);
+                } else if !done.contains(&s) {
+                    on_stack.insert(s);
+                    stack.push((s, 0));
+                }
+            },
+            None => {
+                on_stack.remove(&b);
+                done.insert(b);
+            },
+        }
+    }
+
+    let mut loops = BTreeMap::new();
+    for (header, latches) in latches {
+        let mut body: BTreeSet<usize> = vec![header].into_iter().collect();
+        let mut todo = latches;
+        while let Some(b) = todo.pop() {
+            if body.insert(b) {
+                todo.extend(succs.iter().filter(|(_, s)| s.contains(&b)).map(|(p, _)| *p));
+            }
+        }
+        let exits: BTreeSet<usize> = body.iter()
+            .flat_map(|b| succs[b].iter())
+            .filter(|s| !body.contains(s))
+            .cloned()
+            .collect();
+        let exit = if exits.len() == 1 { exits.into_iter().next() } else { None };
+        loops.insert(header, (body, exit));
+    }
+    loops
+}
+
+/// Immediate post-dominator of each block, where one exists inside the
+/// function.
+fn post_dominators(succs: &BTreeMap<usize, Vec<usize>>) -> BTreeMap<usize, usize> {
+    let all: BTreeSet<usize> = succs.keys().cloned().collect();
+    let mut pdom: BTreeMap<usize, BTreeSet<usize>> = succs.iter()
+        .map(|(&b, s)| (b, if s.is_empty() { vec![b].into_iter().collect() } else { all.clone() }))
+        .collect();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (&b, s) in succs.iter().rev().filter(|(_, s)| !s.is_empty()) {
+            let mut set = s.iter()
+                .map(|s| pdom[s].clone())
+                .fold(all.clone(), |acc, p| acc.intersection(&p).cloned().collect());
+            set.insert(b);
+            if set != pdom[&b] {
+                pdom.insert(b, set);
+                changed = true;
+            }
+        }
+    }
+    let mut ipdom = BTreeMap::new();
+    for (&b, set) in &pdom {
+        let strict: BTreeSet<usize> = set.iter().cloned().filter(|&d| d != b).collect();
+        if let Some(&d) = strict.iter().find(|d| pdom[d].len() == strict.len() && pdom[d].is_subset(&strict)) {
+            ipdom.insert(b, d);
+        }
+    }
+    ipdom
+}
+
+/// Structures lifted blocks into nested C statements, falling back to
+/// gotos where the flow isn't an if/else or a loop.
+struct Emitter {
+    blocks: BTreeMap<usize, Lifted>,
+    loops: BTreeMap<usize, (BTreeSet<usize>, Option<usize>)>,
+    ipdom: BTreeMap<usize, usize>,
+    active: Vec<usize>, // Loop headers being emitted, innermost last
+    emitted: BTreeSet<usize>,
+    gotos: BTreeSet<usize>,
+    lines: Vec<(usize, String)>, // Indent depth and text
+}
+
+impl Emitter {
+    fn line(&mut self, depth: usize, text: String) {
+        self.lines.push((depth, text));
+    }
+
+    fn goto(&mut self, depth: usize, b: usize) {
+        self.gotos.insert(b);
+        self.line(depth, format!("goto L_{};", b));
+    }
+
+    fn region(&mut self, mut b: usize, stop: Option<usize>, depth: usize) {
+        loop {
+            if Some(b) == stop {
+                return;
+            }
+            if let Some(&header) = self.active.last() {
+                if b == header {
+                    return self.line(depth, "continue;".to_string());
+                }
+                if Some(b) == self.loops[&header].1 {
+                    return self.line(depth, "break;".to_string());
+                }
+            }
+            if self.emitted.contains(&b) || !self.blocks.contains_key(&b) {
+                return self.goto(depth, b);
+            }
+            if self.loops.contains_key(&b) {
+                self.line(depth, "while (1) {".to_string());
+                self.active.push(b);
+                if let Some(next) = self.block(b, None, depth + 1) {
+                    self.region(next, None, depth + 1);
+                }
+                self.active.pop();
+                if self.lines.last() == Some(&(depth + 1, "continue;".to_string())) {
+                    self.lines.pop();
+                }
+                self.line(depth, "}".to_string());
+                match self.loops[&b].1 {
+                    Some(exit) => b = exit,
+                    None => return,
+                }
+                continue;
+            }
+            match self.block(b, stop, depth) {
+                Some(next) => b = next,
+                None => return,
+            }
+        }
+    }
+
+    /// Where a branch to `b` leaves the current loop, the statement that does it.
+    fn escape(&self, b: usize) -> Option<&'static str> {
+        let header = *self.active.last()?;
+        if b == header {
+            Some("continue;")
+        } else if Some(b) == self.loops[&header].1 {
+            Some("break;")
+        } else {
+            None
+        }
+    }
+
+    /// Emits `b` and returns the block to carry on with, if any.
+    fn block(&mut self, b: usize, stop: Option<usize>, depth: usize) -> Option<usize> {
+        self.emitted.insert(b);
+        self.line(0, format!("@{}", b));
+        let lifted = &self.blocks[&b];
+        let stmts: Vec<String> = lifted.stmts.iter().map(|s| s.to_string()).collect();
+        for s in stmts {
+            self.line(depth, s);
+        }
+        match self.blocks[&b].term.clone() {
+            Term::Halt => { self.line(depth, "halt();".to_string()); None },
+            Term::Return => { self.line(depth, "return;".to_string()); None },
+            Term::Jump(e) => { self.line(depth, format!("goto *{};", e)); None },
+            Term::Goto(t) => Some(t),
+            Term::BranchIndirect { cond, nonzero, target, next } => {
+                self.line(depth, format!("if ({}) goto *{};", cond.test(nonzero), target));
+                Some(next)
+            },
+            Term::Branch { cond, nonzero, target, next } => {
+                if let Some(s) = self.escape(next) {
+                    self.line(depth, format!("if ({}) {}", cond.test(!nonzero), s));
+                    return Some(target);
+                }
+                if let Some(s) = self.escape(target) {
+                    self.line(depth, format!("if ({}) {}", cond.test(nonzero), s));
+                    return Some(next);
+                }
+                let merge = self.ipdom.get(&b).cloned().filter(|m| match self.active.last() {
+                    Some(h) => self.loops[h].0.contains(m),
+                    None => true,
+                });
+                let inner = merge.or(stop);
+                // The fallthrough goes first, as it comes first in memory
+                if merge == Some(next) {
+                    self.line(depth, format!("if ({}) {{", cond.test(nonzero)));
+                    self.region(target, inner, depth + 1);
+                } else {
+                    self.line(depth, format!("if ({}) {{", cond.test(!nonzero)));
+                    self.region(next, inner, depth + 1);
+                    if merge != Some(target) {
+                        self.line(depth, "} else {".to_string());
+                        self.region(target, inner, depth + 1);
+                    }
+                }
+                self.line(depth, "}".to_string());
+                merge
+            },
+        }
+    }
+}
+
+/// Pseudo-C for one function.
+pub struct Function {
+    pub entry: usize,
+    pub saves: Vec<u8>,
+    pub body: Vec<String>,
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !self.saves.is_empty() {
+            writeln!(f, "// preserves {}", regs_str(&self.saves))?;
+        }
+        writeln!(f, "void fn_{}(void) {{", self.entry)?;
+        for line in &self.body {
+            writeln!(f, "{}", line)?;
+        }
+        writeln!(f, "}}")
+    }
+}
+
+pub fn decompile_function(cfg: &Cfg, entry: usize) -> Option<Function> {
+    cfg.function(entry)?;
+    let prepared = Prepared::new(cfg, entry);
+    let live_in = prepared.liveness();
+    let blocks = prepared.code.keys()
+        .map(|&b| (b, lift_block(&prepared, &cfg.blocks[&b], prepared.live_out(b, &live_in))))
+        .collect();
+
+    let mut emitter = Emitter {
+        blocks,
+        loops: find_loops(entry, &prepared.succs),
+        ipdom: post_dominators(&prepared.succs),
+        active: Vec::new(),
+        emitted: BTreeSet::new(),
+        gotos: BTreeSet::new(),
+        lines: Vec::new(),
+    };
+    emitter.region(entry, None, 1);
+
+    let mut body = Vec::new();
+    for (depth, text) in &emitter.lines {
+        match text.strip_prefix('@').and_then(|b| b.parse::<usize>().ok()) {
+            Some(b) if emitter.gotos.contains(&b) => body.push(format!("L_{}:", b)),
+            Some(_) => {},
+            None => body.push(format!("{}{}", "    ".repeat(*depth), text)),
+        }
+    }
+    Some(Function {
+        entry,
+        saves: prepared.saves,
+        body,
+    })
+}
+
+/// Pseudo-C for every function in `memory`, in address order.
+pub fn decompile(memory: &[u16]) -> String {
+    let cfg = Cfg::build(memory);
+    let mut out = String::new();
+    writeln!(out, "// Arithmetic is modulo 32768; r0-r7 are the registers.").unwrap();
+    for f in &cfg.functions {
+        writeln!(out).unwrap();
+        write!(out, "{}", decompile_function(&cfg, f.entry).unwrap()).unwrap();
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::get_file_as_byte_vec;
+    use crate::vm::Vm;
+
+    const R0: u16 = 32768;
+    const R1: u16 = 32769;
+    const R2: u16 = 32770;
+
+    fn decompiled(code: &[u16], entry: usize) -> String {
+        decompile_function(&Cfg::build(code), entry).unwrap().to_string()
+    }
+
+    #[test]
+    fn test_decompile_loop() {
+        let code = [
+            17, 3,              // 0:  call 3
+            0,                  // 2:  halt
+            2, R1,              // 3:  push r1
+            2, R2,              // 5:  push r2
+            1, R1, 0,           // 7:  set r1 0
+            4, R2, R0, 0,       // 10: eq r2 r0 0
+            7, R2, 30,          // 14: jt r2 30
+            9, R1, R1, R0,      // 17: add r1 r1 r0
+            9, R0, R0, 32767,   // 21: add r0 r0 -1
+            6, 10,              // 25: jmp 10
+            0, 0, 0,            // 27: padding
+            1, R0, R1,          // 30: set r0 r1
+            3, R2,              // 33: pop r2
+            3, R1,              // 35: pop r1
+            18,                 // 37: ret
+        ];
+
+        assert_eq!(decompiled(&code, 3), "\
+// preserves r1, r2
+void fn_3(void) {
+    r1 = 0;
+    while (1) {
+        if (r0 == 0) break;
+        r1 = r1 + r0;
+        r0 = r0 - 1;
+    }
+    r0 = r1;
+    return;
+}
+");
+    }
+
+    #[test]
+    fn test_decompile_if_else() {
+        let code = [
+            2, R2,              // 0:  push r2
+            2, R1,              // 2:  push r1
+            17, 25,             // 4:  call 25
+            3, R1,              // 6:  pop r1
+            5, R2, R0, 3,       // 8:  gt r2 r0 3
+            8, R2, 19,          // 12: jf r2 19
+            19, 89,             // 15: out 'Y'
+            6, 21,              // 17: jmp 21
+            19, 78,             // 19: out 'N'
+            3, R2,              // 21: pop r2
+            18,                 // 23: ret
+            0,                  // 24: halt
+            18,                 // 25: ret
+        ];
+
+        assert_eq!(decompiled(&code, 0), "\
+// preserves r2
+void fn_0(void) {
+    fn_25(); // preserves r1
+    if (r0 > 3) {
+        putchar('Y');
+    } else {
+        putchar('N');
+    }
+    return;
+}
+");
+    }
+
+    #[test]
+    fn test_decompile_repeated_save() {
+        let code = [
+            2, R0,              // 0:  push r0
+            2, R0,              // 2:  push r0
+            9, R1, 5, 6,        // 4:  add r1 5 6
+            1, R0, 7,           // 8:  set r0 7
+            17, 21,             // 11: call 21
+            3, R0,              // 13: pop r0
+            3, R0,              // 15: pop r0
+            19, R0,             // 17: out r0
+            18,                 // 19: ret
+            0,                  // 20: halt
+            18,                 // 21: ret
+        ];
+
+        assert_eq!(decompiled(&code, 0), "\
+void fn_0(void) {
+    saved_r0 = r0;
+    r1 = 11;
+    r0 = 7;
+    fn_21();
+    putchar(saved_r0);
+    r0 = saved_r0;
+    return;
+}
+");
+    }
+
+    #[test]
+    fn test_decompile_teleporter() {
+        let mut vm = Vm::new(get_file_as_byte_vec("challenge.bin"), 32768);
+        vm.set_capture_output(true);
+        vm.run_until_input(10_000_000);
+        let cfg = Cfg::build(vm.memory());
+
+        let f = decompile_function(&cfg, 6027).unwrap().to_string();
+
+        assert!(f.contains("fn_6027();"), "{}", f);
+        assert!(!f.contains("push("), "{}", f);
+    }
+}
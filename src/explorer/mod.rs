@@ -0,0 +1,270 @@
+use log::{trace, debug, info, warn, error};
+use crate::vm::{Vm, Snapshot};
+use crate::game::{GameState, OutputParser, MAX_STEPS_PER_COMMAND};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Write;
+
+#[allow(dead_code)]
+fn _get_rid_of_log_unused_import_warnings() {
+    trace!("Example trace.");
+    debug!("Example debug.");
+    info!("Example info.");
+    warn!("Example warn.");
+    error!("Example error.");
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Room {
+    pub id: usize,
+    pub name: String,
+    pub description: String,
+    pub items: Vec<String>,
+    pub exits: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Edge {
+    pub from: usize,
+    pub to: usize,
+    pub exit: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Map {
+    pub rooms: Vec<Room>,
+    pub edges: Vec<Edge>,
+}
+
+impl Map {
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::new();
+        writeln!(dot, "digraph map {{").unwrap();
+        writeln!(dot, "    node [shape=box];").unwrap();
+        for room in &self.rooms {
+            let mut label = room.name.clone();
+            for item in &room.items {
+                label.push_str("\\n* ");
+                label.push_str(item);
+            }
+            writeln!(dot, "    r{} [label=\"{}\"];", room.id, escape(&label)).unwrap();
+        }
+        for edge in &self.edges {
+            writeln!(dot, "    r{} -> r{} [label=\"{}\"];", edge.from, edge.to, escape(&edge.exit)).unwrap();
+        }
+        writeln!(dot, "}}").unwrap();
+        dot
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    pub fn from_json(json: &str) -> serde_json::Result<Map> {
+        serde_json::from_str(json)
+    }
+
+    pub fn find_room(&self, state: &GameState) -> Option<usize> {
+        self.rooms.iter()
+            .position(|r| Some(&r.name) == state.room.as_ref() && r.description == state.description)
+    }
+
+    /// Shortest sequence of edges from room `from` to any room named `to`.
+    pub fn path(&self, from: usize, to: &str) -> Option<Vec<Edge>> {
+        let mut came_by: Vec<Option<&Edge>> = vec![None; self.rooms.len()];
+        let mut visited = vec![false; self.rooms.len()];
+        let mut queue = VecDeque::new();
+        visited[from] = true;
+        queue.push_back(from);
+        while let Some(id) = queue.pop_front() {
+            if self.rooms[id].name == to {
+                let mut path = Vec::new();
+                let mut at = id;
+                while let Some(edge) = came_by[at] {
+                    path.push(edge.clone());
+                    at = edge.from;
+                }
+                path.reverse();
+                return Some(path);
+            }
+            for edge in self.edges.iter().filter(|e| e.from == id) {
+                if !visited[edge.to] {
+                    visited[edge.to] = true;
+                    came_by[edge.to] = Some(edge);
+                    queue.push_back(edge.to);
+                }
+            }
+        }
+        None
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('"', "\\\"")
+}
+
+/// Walks every exit of every reachable room breadth-first, restoring a
+/// snapshot of the VM before each move so rooms are visited independently.
+/// Exploration starts from wherever the VM currently is, and the VM is put
+/// back there afterwards.
+pub struct Explorer<'a> {
+    vm: &'a mut Vm,
+    max_rooms: usize,
+}
+
+impl<'a> Explorer<'a> {
+    pub fn new(vm: &'a mut Vm, max_rooms: usize) -> Explorer<'a> {
+        Explorer {
+            vm,
+            max_rooms,
+        }
+    }
+
+    pub fn explore(&mut self) -> Map {
+        let origin = self.vm.snapshot();
+        let captured = self.vm.captures_output();
+        self.vm.set_capture_output(true);
+        let map = self.walk();
+        self.vm.restore(&origin);
+        self.vm.set_capture_output(captured);
+        map
+    }
+
+    fn walk(&mut self) -> Map {
+        let mut map = Map::default();
+        let mut seen: HashMap<(String, String), usize> = HashMap::new();
+        let mut queue: VecDeque<(usize, Snapshot)> = VecDeque::new();
+
+        self.vm.run_until_input(MAX_STEPS_PER_COMMAND);
+        let mut out = self.vm.take_output();
+        if parse_room(&out).is_none() {
+            self.vm.insert_buffer("look\n".to_string());
+            self.vm.run_until_input(MAX_STEPS_PER_COMMAND);
+            out = self.vm.take_output();
+        }
+        match parse_room(&out) {
+            Some(room) => {
+                seen.insert((room.name.clone(), room.description.clone()), 0);
+                queue.push_back((0, self.vm.snapshot()));
+                map.rooms.push(Room { id: 0, ..room });
+            },
+            None => {
+                error!("No room found in the initial output.");
+                return map;
+            }
+        }
+
+        while let Some((from, snapshot)) = queue.pop_front() {
+            for exit in map.rooms[from].exits.clone() {
+                self.vm.restore(&snapshot);
+                self.vm.insert_buffer(format!("go {}\n", exit));
+                if !self.vm.run_until_input(MAX_STEPS_PER_COMMAND) {
+                    warn!("Gave up on '{}' from room {}", exit, from);
+                    continue;
+                }
+                if self.vm.is_stopped() {
+                    let mut parser = OutputParser::new();
+                    parser.feed(&self.vm.take_output());
+                    debug!("Taking '{}' from room {} ended the game: {:?}", exit, from, parser.flush().messages);
+                    continue;
+                }
+                let out = self.vm.take_output();
+                let room = match parse_room(&out) {
+                    Some(room) => room,
+                    None => continue,
+                };
+                let key = (room.name.clone(), room.description.clone());
+                let to = match seen.get(&key) {
+                    Some(&id) => id,
+                    None => {
+                        if map.rooms.len() >= self.max_rooms {
+                            warn!("Room limit of {} reached.", self.max_rooms);
+                            continue;
+                        }
+                        let id = map.rooms.len();
+                        trace!("Discovered room {}: {}", id, room.name);
+                        seen.insert(key, id);
+                        queue.push_back((id, self.vm.snapshot()));
+                        map.rooms.push(Room { id, ..room });
+                        id
+                    }
+                };
+                map.edges.push(Edge { from, to, exit });
+            }
+        }
+
+        info!("Explored {} rooms and {} exits.", map.rooms.len(), map.edges.len());
+        map
+    }
+}
+
+/// Builds a room from the last prompt's worth of `text`.
+fn parse_room(text: &str) -> Option<Room> {
+    let state = OutputParser::new().feed(text).pop()?;
+    Some(Room {
+        id: 0,
+        name: state.room?,
+        description: state.description,
+        items: state.items,
+        exits: state.exits,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::get_file_as_byte_vec;
+
+    fn room(id: usize, name: &str) -> Room {
+        Room { id, name: name.to_string(), description: String::new(), items: Vec::new(), exits: Vec::new() }
+    }
+
+    fn edge(from: usize, to: usize, exit: &str) -> Edge {
+        Edge { from, to, exit: exit.to_string() }
+    }
+
+    fn sample() -> Map {
+        let mut rooms = vec![room(0, "Hall"), room(1, "Cellar"), room(2, "Attic \"A\"")];
+        rooms[1].items.push("lamp".to_string());
+        Map {
+            rooms,
+            edges: vec![edge(0, 1, "down"), edge(1, 0, "up"), edge(0, 2, "ladder"), edge(1, 2, "chute")],
+        }
+    }
+
+    #[test]
+    fn test_path() {
+        let map = sample();
+        assert_eq!(map.path(1, "Attic \"A\""), Some(vec![edge(1, 2, "chute")]));
+        assert_eq!(map.path(2, "Hall"), None);
+        assert_eq!(map.path(0, "Hall"), Some(vec![]));
+    }
+
+    #[test]
+    fn test_exports() {
+        let map = sample();
+        let dot = map.to_dot();
+        assert!(dot.starts_with("digraph map {\n"));
+        assert!(dot.contains("    r1 [label=\"Cellar\\n* lamp\"];\n"));
+        assert!(dot.contains("    r2 [label=\"Attic \\\"A\\\"\"];\n"));
+        assert!(dot.contains("    r0 -> r1 [label=\"down\"];\n"));
+
+        let loaded = Map::from_json(&map.to_json()).unwrap();
+        assert_eq!((loaded.rooms, loaded.edges), (map.rooms, map.edges));
+    }
+
+    #[test]
+    fn test_explore_dedups_rooms() {
+        let mut vm = Vm::new(get_file_as_byte_vec("challenge.bin"), 32768);
+        let map = Explorer::new(&mut vm, 12).explore();
+
+        assert_eq!(map.rooms[0].name, "Foothills");
+        let mut keys: Vec<_> = map.rooms.iter().map(|r| (&r.name, &r.description)).collect();
+        keys.sort();
+        keys.dedup();
+        assert_eq!(keys.len(), map.rooms.len());
+        // Coming back into a room links to the one already found
+        assert!(map.edges.iter().any(|e| e.to == 0 && e.from != 0));
+        assert!(map.edges.iter().all(|e| e.from < map.rooms.len() && e.to < map.rooms.len()));
+    }
+}
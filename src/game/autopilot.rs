@@ -0,0 +1,192 @@
+use log::{trace, debug, info, warn, error};
+use crate::vm::Vm;
+use crate::explorer::Map;
+use super::{GameState, OutputParser, MAX_STEPS_PER_COMMAND};
+use std::{error::Error, fmt};
+
+#[allow(dead_code)]
+fn _get_rid_of_log_unused_import_warnings() {
+    trace!("Example trace.");
+    debug!("Example debug.");
+    info!("Example info.");
+    warn!("Example warn.");
+    error!("Example error.");
+}
+
+/// Replies the game gives when it did not do what was asked.
+const FAILURES: [&str; 3] = [
+    "You see no such item here.",
+    "You can't find that in your pack.",
+    "I don't understand; try 'help' for instructions.",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expect {
+    Room(String),
+    AnyRoom,
+    Message(String),
+    Accepted, // Anything but one of the FAILURES
+}
+
+impl Expect {
+    fn matches(&self, state: &GameState) -> bool {
+        match self {
+            Expect::Room(name) => state.room.as_ref() == Some(name),
+            Expect::AnyRoom => state.room.is_some(),
+            Expect::Message(m) => state.messages.contains(m),
+            Expect::Accepted => !state.messages.iter().any(|m| FAILURES.contains(&m.as_str())),
+        }
+    }
+}
+
+impl fmt::Display for Expect {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Expect::Room(name) => write!(f, "room '{}'", name),
+            Expect::AnyRoom => write!(f, "a room"),
+            Expect::Message(m) => write!(f, "'{}'", m),
+            Expect::Accepted => write!(f, "the command to be accepted"),
+        }
+    }
+}
+
+/// The game's reply to a macro step did not match what the macro expected,
+/// or never finished.
+#[derive(Debug, Clone)]
+pub struct Divergence {
+    pub command: String,
+    pub expected: Expect,
+    pub state: Box<GameState>, // What was printed before it stopped
+    pub hung: bool, // Ran out of steps without halting or asking for input
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.hung {
+            return write!(f, "'{}' expected {}, but the game ran {} steps without asking for input",
+                self.command, self.expected, MAX_STEPS_PER_COMMAND);
+        }
+        write!(f, "'{}' expected {}, got ", self.command, self.expected)?;
+        match (&self.state.room, self.state.messages.last()) {
+            (Some(room), _) => write!(f, "room '{}'", room),
+            (None, Some(m)) => write!(f, "'{}'", m),
+            (None, None) if !self.state.prompt => write!(f, "the game stopping"),
+            (None, None) => write!(f, "nothing"),
+        }
+    }
+}
+
+impl Error for Divergence {}
+
+/// Feeds game commands into the VM input buffer one at a time, checking the
+/// reply to each before sending the next.
+pub struct Autopilot<'a> {
+    vm: &'a mut Vm,
+    captured: bool,
+}
+
+impl<'a> Autopilot<'a> {
+    pub fn new(vm: &'a mut Vm) -> Autopilot<'a> {
+        let captured = vm.captures_output();
+        vm.set_capture_output(true);
+        Autopilot {
+            vm,
+            captured,
+        }
+    }
+
+    pub fn send(&mut self, command: &str, expected: Expect) -> Result<GameState, Divergence> {
+        trace!("autopilot> {}", command);
+        self.vm.insert_buffer(format!("{}\n", command));
+        let settled = self.vm.run_until_input(MAX_STEPS_PER_COMMAND);
+
+        let mut parser = OutputParser::new();
+        let state = match parser.feed(&self.vm.take_output()).pop() {
+            Some(state) => state,
+            None => parser.flush(),
+        };
+        if settled && state.prompt && expected.matches(&state) {
+            Ok(state)
+        } else {
+            Err(Divergence {
+                command: command.to_string(),
+                expected,
+                state: Box::new(state),
+                hung: !settled,
+            })
+        }
+    }
+
+    pub fn look(&mut self) -> Result<GameState, Divergence> {
+        self.send("look", Expect::AnyRoom)
+    }
+
+    /// Takes every item in the current room, returning what was taken.
+    pub fn take_all(&mut self) -> Result<Vec<String>, Divergence> {
+        let here = self.look()?;
+        for item in &here.items {
+            self.send(&format!("take {}", item), Expect::Message("Taken.".to_string()))?;
+        }
+        Ok(here.items)
+    }
+
+    /// Uses every item in the inventory, returning what was used.
+    pub fn use_all(&mut self) -> Result<Vec<String>, Divergence> {
+        let inventory = self.send("inv", Expect::Accepted)?.inventory;
+        for item in &inventory {
+            self.send(&format!("use {}", item), Expect::Accepted)?;
+        }
+        Ok(inventory)
+    }
+
+    /// Walks the shortest known route on `map` to the first room named `room`.
+    pub fn goto(&mut self, map: &Map, room: &str) -> Result<GameState, Box<dyn Error>> {
+        let mut here = self.look()?;
+        let from = map.find_room(&here).ok_or("Current room is not on the map.")?;
+        let path = map.path(from, room).ok_or(format!("No known path to '{}'.", room))?;
+        for edge in path {
+            let expected = Expect::Room(map.rooms[edge.to].name.clone());
+            here = self.send(&format!("go {}", edge.exit), expected)?;
+        }
+        Ok(here)
+    }
+}
+
+impl<'a> Drop for Autopilot<'a> {
+    fn drop(&mut self) {
+        self.vm.set_capture_output(self.captured);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::explorer::Explorer;
+    use crate::util::get_file_as_byte_vec;
+
+    #[test]
+    fn test_autopilot() {
+        let mut vm = Vm::new(get_file_as_byte_vec("challenge.bin"), 32768);
+        let map = Explorer::new(&mut vm, 500).explore();
+        let mut autopilot = Autopilot::new(&mut vm);
+
+        assert_eq!(autopilot.take_all().unwrap(), vec!["tablet"]);
+        assert_eq!(autopilot.use_all().unwrap(), vec!["tablet"]);
+
+        let state = autopilot.goto(&map, "Rope bridge").unwrap();
+        assert_eq!(state.room.as_deref(), Some("Rope bridge"));
+
+        let err = autopilot.send("take tablet", Expect::Message("Taken.".to_string())).unwrap_err();
+        assert_eq!(err.state.messages, vec!["You see no such item here."]);
+        assert!(!err.hung);
+    }
+
+    #[test]
+    fn test_hang_is_not_a_reply() {
+        // out 'x'; jmp 2
+        let mut vm = Vm::from_words(&[19, 'x' as u16, 6, 2]);
+        let err = Autopilot::new(&mut vm).look().unwrap_err();
+        assert!(err.hung);
+        assert_eq!(err.to_string(), format!("'look' expected a room, but the game ran {} steps without asking for input", MAX_STEPS_PER_COMMAND));
+    }
+}
@@ -0,0 +1,174 @@
+pub mod autopilot;
+
+use log::{trace, debug, info, warn, error};
+use serde::Serialize;
+
+#[allow(dead_code)]
+fn _get_rid_of_log_unused_import_warnings() {
+    trace!("Example trace.");
+    debug!("Example debug.");
+    info!("Example info.");
+    warn!("Example warn.");
+    error!("Example error.");
+}
+
+const PROMPT: &str = "What do you do?";
+
+/// Instructions allowed between two prompts before a command is abandoned.
+pub const MAX_STEPS_PER_COMMAND: usize = 10_000_000;
+
+/// Everything the game printed between two `What do you do?` prompts.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct GameState {
+    pub room: Option<String>,
+    pub description: String,
+    pub items: Vec<String>,
+    pub exits: Vec<String>,
+    pub inventory: Vec<String>,
+    pub messages: Vec<String>, // Lines outside of a room block, e.g. "Taken."
+    pub prompt: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Section {
+    Messages,
+    Description,
+    Items,
+    Exits,
+    Inventory,
+}
+
+/// Turns the character-at-a-time OUT stream into `GameState`s.
+#[derive(Debug, Clone)]
+pub struct OutputParser {
+    line: String,
+    section: Section,
+    state: GameState,
+}
+
+impl Default for OutputParser {
+    fn default() -> OutputParser {
+        OutputParser::new()
+    }
+}
+
+impl OutputParser {
+    pub fn new() -> OutputParser {
+        OutputParser {
+            line: String::new(),
+            section: Section::Messages,
+            state: GameState::default(),
+        }
+    }
+
+    /// Feeds one character; returns the finished state once the prompt is seen.
+    pub fn push(&mut self, c: char) -> Option<GameState> {
+        if c != '\n' {
+            self.line.push(c);
+            return None;
+        }
+        let line = std::mem::take(&mut self.line);
+        self.parse_line(&line)
+    }
+
+    pub fn feed(&mut self, text: &str) -> Vec<GameState> {
+        text.chars().filter_map(|c| self.push(c)).collect()
+    }
+
+    /// Returns what has been parsed so far without waiting for a prompt,
+    /// e.g. when the game halts.
+    pub fn flush(&mut self) -> GameState {
+        if !self.line.is_empty() {
+            let line = std::mem::take(&mut self.line);
+            self.parse_line(&line);
+        }
+        self.finish(false)
+    }
+
+    fn parse_line(&mut self, line: &str) -> Option<GameState> {
+        if line.starts_with(PROMPT) {
+            return Some(self.finish(true));
+        }
+        if line.starts_with("== ") && line.ends_with(" ==") {
+            let messages = std::mem::take(&mut self.state.messages);
+            self.state = GameState {
+                room: Some(line.trim_matches(|c| c == '=' || c == ' ').to_string()),
+                messages,
+                ..GameState::default()
+            };
+            self.section = Section::Description;
+        } else if line.starts_with("Things of interest here:") {
+            self.section = Section::Items;
+        } else if line.starts_with("There is 1 exit:") || (line.starts_with("There are ") && line.ends_with(" exits:")) {
+            self.section = Section::Exits;
+        } else if line.starts_with("Your inventory:") {
+            self.section = Section::Inventory;
+        } else if let (Some(entry), Section::Items) = (line.strip_prefix("- "), self.section) {
+            self.state.items.push(entry.to_string());
+        } else if let (Some(entry), Section::Exits) = (line.strip_prefix("- "), self.section) {
+            self.state.exits.push(entry.to_string());
+        } else if let (Some(entry), Section::Inventory) = (line.strip_prefix("- "), self.section) {
+            self.state.inventory.push(entry.to_string());
+        } else if self.section == Section::Description {
+            if !self.state.description.is_empty() || !line.is_empty() {
+                if !self.state.description.is_empty() {
+                    self.state.description.push('\n');
+                }
+                self.state.description.push_str(line);
+            }
+        } else if !line.is_empty() {
+            self.section = Section::Messages;
+            self.state.messages.push(line.to_string());
+        }
+        None
+    }
+
+    fn finish(&mut self, prompt: bool) -> GameState {
+        let mut state = std::mem::take(&mut self.state);
+        state.description = state.description.trim().to_string();
+        state.prompt = prompt;
+        self.section = Section::Messages;
+        state
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_room() {
+        let text = "Chained to the wall.\n\n== Foothills ==\nYou find yourself at the base of a mountain.\n\n\
+                    Things of interest here:\n- tablet\n\nThere are 2 exits:\n- doorway\n- south\n\nWhat do you do?\n";
+
+        let states = OutputParser::new().feed(text);
+
+        assert_eq!(states.len(), 1);
+        assert_eq!(states[0], GameState {
+            room: Some("Foothills".to_string()),
+            description: "You find yourself at the base of a mountain.".to_string(),
+            items: vec!["tablet".to_string()],
+            exits: vec!["doorway".to_string(), "south".to_string()],
+            inventory: Vec::new(),
+            messages: vec!["Chained to the wall.".to_string()],
+            prompt: true,
+        });
+    }
+
+    #[test]
+    fn test_parse_messages() {
+        let mut parser = OutputParser::new();
+
+        let states = parser.feed("\n\nTaken.\n\nWhat do you do?\n\nYour inventory:\n- tablet\n\nWhat do you do?\n\n\
+                                  You have been eaten by a grue.\n");
+
+        assert_eq!(states.len(), 2);
+        assert_eq!(states[0].room, None);
+        assert_eq!(states[0].messages, vec!["Taken."]);
+        assert_eq!(states[1].inventory, vec!["tablet"]);
+
+        let last = parser.flush();
+        assert!(!last.prompt);
+        assert_eq!(last.messages, vec!["You have been eaten by a grue."]);
+    }
+}
@@ -0,0 +1,504 @@
+use log::{trace, debug, info, warn, error};
+use crate::vm::{StopReason, Vm};
+use std::collections::{BTreeSet, VecDeque};
+use std::fmt::Write as FmtWrite;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+#[allow(dead_code)]
+fn _get_rid_of_log_unused_import_warnings() {
+    trace!("Example trace.");
+    debug!("Example debug.");
+    info!("Example info.");
+    warn!("Example warn.");
+    error!("Example error.");
+}
+
+/// Steps run between checks for an interrupt from GDB.
+const SLICE: usize = 10_000;
+
+const PACKET_SIZE: usize = 0x4000;
+
+/// Registers in `g` order: R0-R7, pc and sp.
+const REGISTERS: usize = 10;
+
+/// GDB addresses bytes, so pc and memory addresses are word addresses
+/// times two.  sp is the depth of the VM's separate stack, which
+/// `monitor stack` prints.
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.synacor.core">
+    <reg name="r0" bitsize="16" type="uint16" regnum="0"/>
+    <reg name="r1" bitsize="16" type="uint16"/>
+    <reg name="r2" bitsize="16" type="uint16"/>
+    <reg name="r3" bitsize="16" type="uint16"/>
+    <reg name="r4" bitsize="16" type="uint16"/>
+    <reg name="r5" bitsize="16" type="uint16"/>
+    <reg name="r6" bitsize="16" type="uint16"/>
+    <reg name="r7" bitsize="16" type="uint16"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+    <reg name="sp" bitsize="16" type="uint16"/>
+  </feature>
+</target>
+"#;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Stop {
+    Breakpoint,
+    Step,
+    Interrupt,
+    Input, // Blocked on IN with nothing queued
+    Violation, // One the execution policy stops on
+    Halted,
+}
+
+impl Stop {
+    fn reply(self) -> String {
+        match self {
+            Stop::Breakpoint => "T05swbreak:;".to_string(),
+            Stop::Step => "S05".to_string(),   // SIGTRAP
+            Stop::Interrupt => "S02".to_string(), // SIGINT
+            Stop::Input => "S15".to_string(),  // SIGTTIN
+            Stop::Violation => "S04".to_string(), // SIGILL
+            Stop::Halted => "W00".to_string(),
+        }
+    }
+}
+
+/// What to send back for one packet.
+#[derive(Debug, Default, PartialEq)]
+struct Reply {
+    packets: Vec<String>,
+    close: bool,
+}
+
+impl Reply {
+    fn one(packet: &str) -> Reply {
+        Reply { packets: vec![packet.to_string()], close: false }
+    }
+}
+
+/// GDB remote serial protocol server for one VM.
+pub struct GdbStub {
+    vm: Vm,
+    breakpoints: BTreeSet<usize>,
+    no_ack: bool,
+    last_stop: Stop,
+}
+
+impl GdbStub {
+    pub fn new(mut vm: Vm) -> GdbStub {
+        vm.set_capture_output(true);
+        GdbStub { vm, breakpoints: BTreeSet::new(), no_ack: false, last_stop: Stop::Step }
+    }
+
+    /// Serves GDB sessions on 127.0.0.1:`port`, one after another.
+    pub fn listen(&mut self, port: u16) -> io::Result<()> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        info!("Waiting for GDB on {}", listener.local_addr()?);
+        for stream in listener.incoming() {
+            let stream = stream?;
+            info!("GDB connected from {}", stream.peer_addr()?);
+            if let Err(e) = self.session(stream) {
+                warn!("GDB session ended: {}", e);
+            }
+        }
+        Ok(())
+    }
+
+    fn session(&mut self, mut stream: TcpStream) -> io::Result<()> {
+        self.no_ack = false;
+        let mut reader = PacketReader { stream: stream.try_clone()?, buffer: VecDeque::new() };
+        while let Some(packet) = reader.next_packet(!self.no_ack)? {
+            trace!("GDB <- {}", packet);
+            let reply = self.handle(&packet, &mut || reader.interrupted());
+            for p in &reply.packets {
+                trace!("GDB -> {}", p);
+                stream.write_all(frame(p).as_bytes())?;
+            }
+            stream.flush()?;
+            if reply.close {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn handle(&mut self, packet: &str, interrupted: &mut dyn FnMut() -> bool) -> Reply {
+        let error = || Reply::one("E01");
+        let (command, args) = packet.split_at(packet.len().min(1));
+        match command {
+            "?" => Reply::one(&self.last_stop.reply()),
+            "g" => Reply::one(&(0..REGISTERS).map(|r| hex_word(self.register(r))).collect::<String>()),
+            "G" => {
+                let words = parse_words(args);
+                if words.len() < REGISTERS {
+                    return error();
+                }
+                for (r, v) in words.into_iter().enumerate().take(REGISTERS) {
+                    self.set_register(r, v);
+                }
+                Reply::one("OK")
+            },
+            "p" => match usize::from_str_radix(args, 16) {
+                Ok(r) if r < REGISTERS => Reply::one(&hex_word(self.register(r))),
+                _ => error(),
+            },
+            "P" => {
+                let (r, v) = match args.split_once('=') {
+                    Some((r, v)) => (usize::from_str_radix(r, 16).ok(), parse_words(v).first().cloned()),
+                    None => (None, None),
+                };
+                match (r, v) {
+                    (Some(r), Some(v)) if r < REGISTERS => {
+                        self.set_register(r, v);
+                        Reply::one("OK")
+                    },
+                    _ => error(),
+                }
+            },
+            "m" => match parse_range(args) {
+                Some((addr, len)) if addr < self.vm.memory().len() * 2 => {
+                    let end = addr.saturating_add(len).min(self.vm.memory().len() * 2);
+                    Reply::one(&(addr..end).map(|b| format!("{:02x}", self.byte(b))).collect::<String>())
+                },
+                _ => error(),
+            },
+            "M" => {
+                let (range, data) = args.split_once(':').unwrap_or((args, ""));
+                let bytes: Option<Vec<u8>> = data.as_bytes().chunks(2).map(hex_byte).collect();
+                match (parse_range(range), bytes) {
+                    (Some((addr, len)), Some(bytes)) if addr.checked_add(len).filter(|&end| end <= self.vm.memory().len() * 2).is_some() &&
+                                                        bytes.len() == len => {
+                        for (k, b) in bytes.into_iter().enumerate() {
+                            self.set_byte(addr + k, b);
+                        }
+                        Reply::one("OK")
+                    },
+                    _ => error(),
+                }
+            },
+            "Z" | "z" => {
+                let fields: Vec<&str> = args.split(',').collect();
+                match (fields.first(), fields.get(1).and_then(|a| usize::from_str_radix(a, 16).ok())) {
+                    (Some(&"0"), Some(addr)) | (Some(&"1"), Some(addr)) => {
+                        if command == "Z" {
+                            self.breakpoints.insert(addr / 2);
+                        } else {
+                            self.breakpoints.remove(&(addr / 2));
+                        }
+                        Reply::one("OK")
+                    },
+                    _ => Reply::one(""),
+                }
+            },
+            "c" | "s" => {
+                if let Ok(addr) = usize::from_str_radix(args, 16) {
+                    self.vm.set_pc(addr / 2);
+                }
+                self.resume(command == "s", interrupted)
+            },
+            "q" | "Q" | "v" => self.query(packet),
+            "H" | "T" => Reply::one("OK"),
+            "D" => Reply { packets: vec!["OK".to_string()], close: true },
+            "k" => Reply { packets: vec![], close: true },
+            _ => Reply::one(""),
+        }
+    }
+
+    fn query(&mut self, packet: &str) -> Reply {
+        if packet.starts_with("qSupported") {
+            Reply::one(&format!("PacketSize={:x};qXfer:features:read+;swbreak+;QStartNoAckMode+", PACKET_SIZE))
+        } else if packet == "QStartNoAckMode" {
+            self.no_ack = true;
+            Reply::one("OK")
+        } else if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            match parse_range(range) {
+                Some((offset, len)) => {
+                    let xml = TARGET_XML.as_bytes();
+                    let start = offset.min(xml.len());
+                    let end = offset.saturating_add(len).min(xml.len());
+                    let more = if end < xml.len() { "m" } else { "l" };
+                    Reply::one(&format!("{}{}", more, String::from_utf8_lossy(&xml[start..end])))
+                },
+                None => Reply::one("E01"),
+            }
+        } else if let Some(hex) = packet.strip_prefix("qRcmd,") {
+            let command = String::from_utf8_lossy(&parse_bytes(hex)).to_string();
+            self.monitor(command.trim())
+        } else {
+            Reply::one(match packet {
+                "qAttached" => "1",
+                "qC" => "QC1",
+                "qfThreadInfo" => "m1",
+                "qsThreadInfo" => "l",
+                _ => "",
+            })
+        }
+    }
+
+    /// `monitor` commands: game input, the stack and resetting the VM.
+    fn monitor(&mut self, command: &str) -> Reply {
+        let text = if let Some(line) = command.strip_prefix("input ") {
+            self.vm.insert_buffer(format!("{}\n", line));
+            String::new()
+        } else if command == "stack" {
+            let stack: Vec<String> = self.vm.stack().iter().map(|v| v.to_string()).collect();
+            format!("{}\n", stack.join(" "))
+        } else if command == "reset" {
+            self.vm.reset();
+            "VM reset.\n".to_string()
+        } else {
+            "Commands: input <text>, stack, reset\n".to_string()
+        };
+        let mut packets: Vec<String> = console_packets(&text);
+        packets.push("OK".to_string());
+        Reply { packets, close: false }
+    }
+
+    /// Runs until something stops it, or for one instruction, sending what
+    /// the game printed along the way as console output.
+    fn resume(&mut self, single: bool, interrupted: &mut dyn FnMut() -> bool) -> Reply {
+        let stop = loop {
+            break match self.vm.run_until(&self.breakpoints, if single { 1 } else { SLICE }).0 {
+                StopReason::Violation(..) => Stop::Violation,
+                StopReason::Halted => Stop::Halted,
+                StopReason::Input => Stop::Input,
+                StopReason::Breakpoint(_) | StopReason::Paused | StopReason::Stepped if single => Stop::Step,
+                StopReason::Breakpoint(_) => Stop::Breakpoint,
+                StopReason::Paused | StopReason::Stepped if interrupted() => Stop::Interrupt,
+                StopReason::Paused | StopReason::Stepped => continue,
+            };
+        };
+        self.last_stop = stop;
+        let mut text = self.vm.take_output();
+        if stop == Stop::Input {
+            text.push_str("[Waiting for input: monitor input <text>]\n");
+        }
+        let mut packets = console_packets(&text);
+        packets.push(stop.reply());
+        Reply { packets, close: false }
+    }
+
+    fn register(&self, r: usize) -> u16 {
+        match r {
+            0..=7 => self.vm.registers()[r],
+            8 => (self.vm.pc() * 2) as u16,
+            _ => self.vm.stack().len() as u16,
+        }
+    }
+
+    /// sp can't be written; the stack only changes by running code.
+    fn set_register(&mut self, r: usize, v: u16) {
+        match r {
+            0..=7 => self.vm.set_register(r, v),
+            8 => self.vm.set_pc(v as usize / 2),
+            _ => {},
+        }
+    }
+
+    fn byte(&self, b: usize) -> u8 {
+        let w = self.vm.memory()[b / 2];
+        if b & 1 == 0 { (w & 0xff) as u8 } else { (w >> 8) as u8 }
+    }
+
+    fn set_byte(&mut self, b: usize, v: u8) {
+        let w = self.vm.memory()[b / 2];
+        let w = if b & 1 == 0 { (w & 0xff00) | v as u16 } else { (w & 0xff) | (v as u16) << 8 };
+        self.vm.write_memory(b / 2, w);
+    }
+}
+
+/// Bytes from GDB, split into packets.
+struct PacketReader {
+    stream: TcpStream,
+    buffer: VecDeque<u8>,
+}
+
+impl PacketReader {
+    /// Reads more into the buffer.  Returns false at end of stream.
+    fn fill(&mut self, blocking: bool) -> io::Result<bool> {
+        self.stream.set_nonblocking(!blocking)?;
+        let mut bytes = [0; 4096];
+        let result = match self.stream.read(&mut bytes) {
+            Ok(0) => Ok(false),
+            Ok(n) => {
+                self.buffer.extend(&bytes[..n]);
+                Ok(true)
+            },
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(true),
+            Err(e) => Err(e),
+        };
+        self.stream.set_nonblocking(false)?;
+        result
+    }
+
+    /// The next packet's payload, acknowledging it if `ack`.  Interrupts
+    /// outside of running are dropped.
+    fn next_packet(&mut self, ack: bool) -> io::Result<Option<String>> {
+        loop {
+            while let Some(&b) = self.buffer.front() {
+                if b == b'$' {
+                    break;
+                }
+                self.buffer.pop_front();
+            }
+            if let Some(end) = self.buffer.iter().position(|&b| b == b'#') {
+                if self.buffer.len() >= end + 3 {
+                    let bytes: Vec<u8> = self.buffer.drain(..end + 3).collect();
+                    let payload = String::from_utf8_lossy(&bytes[1..end]).to_string();
+                    let sum = std::str::from_utf8(&bytes[end + 1..]).ok().and_then(|s| u8::from_str_radix(s, 16).ok());
+                    if ack {
+                        let ok = sum == Some(checksum(&payload));
+                        self.stream.write_all(if ok { b"+" } else { b"-" })?;
+                        if !ok {
+                            continue;
+                        }
+                    }
+                    return Ok(Some(payload));
+                }
+            }
+            if !self.fill(true)? {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Whether GDB has sent an interrupt (^C) since last asked.
+    fn interrupted(&mut self) -> bool {
+        if self.fill(false).is_err() {
+            return true;
+        }
+        match self.buffer.iter().position(|&b| b == 0x03) {
+            Some(k) => {
+                self.buffer.remove(k);
+                true
+            },
+            None => false,
+        }
+    }
+}
+
+fn checksum(payload: &str) -> u8 {
+    payload.bytes().fold(0u8, |sum, b| sum.wrapping_add(b))
+}
+
+fn frame(payload: &str) -> String {
+    format!("${}#{:02x}", payload, checksum(payload))
+}
+
+/// A word as GDB expects it: little-endian hex.
+fn hex_word(v: u16) -> String {
+    format!("{:02x}{:02x}", v & 0xff, v >> 8)
+}
+
+/// Two hex digits as a byte; None for anything else, including half a pair.
+fn hex_byte(pair: &[u8]) -> Option<u8> {
+    let digit = |b: &u8| (*b as char).to_digit(16);
+    match pair {
+        [hi, lo] => Some((digit(hi)? << 4 | digit(lo)?) as u8),
+        _ => None,
+    }
+}
+
+fn parse_bytes(hex: &str) -> Vec<u8> {
+    hex.as_bytes().chunks_exact(2).filter_map(hex_byte).collect()
+}
+
+fn parse_words(hex: &str) -> Vec<u16> {
+    parse_bytes(hex).chunks(2).filter(|c| c.len() == 2).map(|c| c[0] as u16 | (c[1] as u16) << 8).collect()
+}
+
+/// `addr,len` in hex.
+fn parse_range(s: &str) -> Option<(usize, usize)> {
+    let (addr, len) = s.split_once(',')?;
+    Some((usize::from_str_radix(addr, 16).ok()?, usize::from_str_radix(len, 16).ok()?))
+}
+
+/// `O` packets carrying `text` to GDB's console.
+fn console_packets(text: &str) -> Vec<String> {
+    text.as_bytes().chunks(PACKET_SIZE / 4)
+        .map(|chunk| {
+            let mut packet = "O".to_string();
+            for b in chunk {
+                write!(packet, "{:02x}", b).unwrap();
+            }
+            packet
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stub() -> GdbStub {
+        GdbStub::new(Vm::from_words(&[
+            9, 32768, 32768, 1, // 0: add R0 R0 1
+            19, 'A' as u16,     // 4: out 'A'
+            6, 0,               // 6: jmp 0
+            20, 32769,          // 8: in R1
+        ]))
+    }
+
+    #[test]
+    fn test_registers_and_memory() {
+        let mut gdb = stub();
+        let never = &mut || false;
+        assert_eq!(gdb.handle("s", never), Reply::one("S05"));
+        assert_eq!(gdb.handle("g", never).packets[0], format!("0100{}0800{}", "0000".repeat(7), "0000"));
+        assert_eq!(gdb.handle("P8=1000", never), Reply::one("OK"));
+        assert_eq!(gdb.vm.pc(), 8);
+        assert_eq!(gdb.handle("m8,4", never), Reply::one("13004100"));
+        assert_eq!(gdb.handle("M2,2:0080", never), Reply::one("OK"));
+        assert_eq!(gdb.vm.memory()[1], 32768);
+        assert_eq!(gdb.handle("M2,2:é0", never), Reply::one("E01"));
+        assert_eq!(gdb.handle("M2,1:0", never), Reply::one("E01"));
+        assert_eq!(gdb.handle("Mffffffffffffffff,1:00", never), Reply::one("E01"));
+        assert_eq!(gdb.handle("P8=é0", never), Reply::one("E01"));
+        assert_eq!(gdb.handle("mffffffffffffffff,ffffffffffffffff", never), Reply::one("E01"));
+        assert_eq!(gdb.handle("m2,ffffffffffffffff", never).packets[0].len(), (20 - 2) * 2);
+        assert_eq!(gdb.handle("qXfer:features:read:target.xml:0,ffff", never).packets[0], format!("l{}", TARGET_XML));
+        assert_eq!(gdb.handle("qXfer:features:read:target.xml:1,ffffffffffffffff", never).packets[0], format!("l{}", &TARGET_XML[1..]));
+    }
+
+    #[test]
+    fn test_breakpoints_and_input() {
+        let mut gdb = stub();
+        let never = &mut || false;
+        assert_eq!(gdb.handle("Z0,c,2", never), Reply::one("OK"));
+        let reply = gdb.handle("c", never);
+        assert_eq!(reply.packets, vec!["O41".to_string(), "T05swbreak:;".to_string()]);
+        assert_eq!(gdb.vm.registers()[0], 1);
+
+        gdb.handle("z0,c,2", never);
+        gdb.handle("P8=1000", never);
+        let reply = gdb.handle("c", never);
+        assert_eq!(reply.packets.last().unwrap(), "S15");
+        let input: String = "input x".bytes().map(|b| format!("{:02x}", b)).collect();
+        gdb.handle(&format!("qRcmd,{}", input), never);
+        assert_eq!(gdb.handle("s", never), Reply::one("S05"));
+        assert_eq!(gdb.vm.registers()[1], 'x' as u16);
+        gdb.handle("P8=0000", never);
+        assert_eq!(gdb.handle("c", &mut || true).packets.last().unwrap(), "S02");
+    }
+
+    #[test]
+    fn test_session() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            stub().session(stream).unwrap();
+        });
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(frame("p8").as_bytes()).unwrap();
+        let mut reply = [0; 9];
+        client.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply, b"+$0000#c0");
+        client.write_all(frame("D").as_bytes()).unwrap();
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,18 @@
+pub mod vm;
+pub mod cfg;
+pub mod console;
+pub mod controller;
+pub mod dap;
+pub mod debugger;
+pub mod decompile;
+pub mod explorer;
+pub mod game;
+pub mod gdb;
+pub mod strings;
+pub mod rpc;
+pub mod script;
+pub mod server;
+pub mod symbols;
+pub mod transcript;
+pub mod translate;
+pub mod util;
@@ -1,9 +1,6 @@
-mod vm;
-mod console;
-mod util;
+use synacor::{cfg, console, dap, debugger, decompile, explorer, game, gdb, rpc, server, strings, symbols, transcript, translate, util, vm};
 
 use log::{Level}; // trace, debug, info, warn, error
-use env_logger;
 use rustop::opts;
 use std::{error::Error};
 
@@ -16,14 +13,42 @@ fn main() -> Result<(), Box<dyn Error>> {
         synopsis "Synacor Challenge 2020";
         opt memsize:usize=32768, desc: "Memory size of VM";
         opt input_file:String="challenge.bin".to_string(), desc: "Input file";
-        opt disassemble:Option<String>, desc: "Dissassemble input into ASM.";
+        opt policy:String="lenient".to_string(), desc: "What the VM does on spec violations: strict stops, lenient wraps or skips, compat does what other implementations do.";
+        opt disassemble:Option<String>, desc: "Dissassemble input into ASM, after the self-test, with string references noted.";
+        opt strings:Option<String>, desc: "Write the string table after the self-test to this file as JSON.";
         opt bp:Option<usize>, desc: "Add a breakpoint.";
+        opt symbols:Option<String>, desc: "Load names, comments and data types from this JSON symbol file.";
+        opt gdb:Option<u16>, desc: "Serve the GDB remote protocol on this localhost port.";
+        opt dap:bool, desc: "Speak the Debug Adapter Protocol on stdin and stdout; the client's launch request names the program and may override --policy.";
+        opt rpc:bool, desc: "Answer JSON-RPC 2.0 requests, one per line, on stdin and stdout with the input file loaded.";
+        opt rpc_port:Option<u16>, desc: "Answer JSON-RPC 2.0 requests on this localhost port instead.";
+        opt serve:Option<u16>, desc: "Serve the game to TCP clients on this localhost port, each with their own VM.";
+        opt max_sessions:usize=8, desc: "Session limit for --serve.";
+        opt record:Option<String>, desc: "Record the console session's input and output to this transcript file.";
+        opt replay:Option<String>, desc: "Replay a transcript's input, reporting where the output first differs; --record saves the new run.";
+        opt tui:bool, desc: "Run the full-screen debugger instead of the console.";
+        opt trace:Option<String>, desc: "Write an annotated trace of execution up to the first prompt to this file.";
+        opt trace_steps:usize=100000, desc: "Step limit for --trace.";
+        opt explore:Option<String>, desc: "Explore the map, writing <explore>.dot and <explore>.json.";
+        opt max_rooms:usize=500, desc: "Room limit for --explore.";
+        opt translate:Option<String>, desc: "Translate the input into a Rust crate in this directory.";
+        opt callgraph:Option<String>, desc: "Write the call graph after the self-test to <callgraph>.dot and <callgraph>.json.";
+        opt decompile:Option<String>, desc: "Write pseudo-C for every function after the self-test to this file.";
+        opt cfg:Option<String>, desc: "Write a fn_<entry>.dot control-flow graph per function after the self-test into this directory.";
     };
 
     let (args, _rest) = opts.parse_or_exit();
 
     // Set up logging
-    let mut c = console::Console::new(args.input_file, args.memsize);
+    let mut c = console::Console::new(args.input_file.clone(), args.memsize);
+    let policy: vm::ExecutionPolicy = args.policy.parse()?;
+    c.set_policy(policy);
+    let memsize = args.memsize;
+    let load = |file: &str| {
+        let mut vm = vm::Vm::new(util::get_file_as_byte_vec(file), memsize);
+        vm.set_policy(policy);
+        vm
+    };
 
     env_logger::builder()
         .format(|buf, record| {
@@ -42,7 +67,137 @@ fn main() -> Result<(), Box<dyn Error>> {
         })
         .init();
 
+    let mut symbols = match &args.symbols {
+        Some(file) => symbols::Symbols::load(file)?,
+        None => symbols::Symbols::default(),
+    };
+    c.set_symbols(symbols.clone());
+
+    if args.dap {
+        dap::serve_stdio(symbols, policy)?;
+        return Ok(());
+    }
+
+    if let Some(prefix) = args.explore {
+        let mut vm = load(&args.input_file);
+        let map = explorer::Explorer::new(&mut vm, args.max_rooms).explore();
+        std::fs::write(format!("{}.dot", prefix), map.to_dot())?;
+        std::fs::write(format!("{}.json", prefix), map.to_json())?;
+        return Ok(());
+    }
+
+    if let Some(dir) = args.translate {
+        let vm = load(&args.input_file);
+        translate::write_crate(vm.memory(), std::path::Path::new(&dir))?;
+        return Ok(());
+    }
+
+    if let Some(file) = args.disassemble {
+        let vm = after_self_test(load(&args.input_file));
+        symbols.add_strings(&strings::StringTable::build(vm.memory()));
+        std::fs::write(file, symbols.disassemble(vm.memory()))?;
+        return Ok(());
+    }
+
+    if let Some(file) = args.trace {
+        let mut vm = load(&args.input_file);
+        vm.set_capture_output(true);
+        let entries = vm.trace(args.trace_steps);
+        std::fs::write(file, symbols.render_trace(vm.memory(), &entries))?;
+        return Ok(());
+    }
+
+    if let Some(file) = args.strings {
+        let vm = after_self_test(load(&args.input_file));
+        std::fs::write(file, strings::StringTable::build(vm.memory()).to_json())?;
+        return Ok(());
+    }
+
+    if let Some(prefix) = args.callgraph {
+        let vm = after_self_test(load(&args.input_file));
+        let graph = cfg::callgraph::CallGraph::new(&cfg::Cfg::build(vm.memory()));
+        std::fs::write(format!("{}.dot", prefix), graph.to_dot())?;
+        std::fs::write(format!("{}.json", prefix), graph.to_json())?;
+        return Ok(());
+    }
+
+    if let Some(file) = args.decompile {
+        let vm = after_self_test(load(&args.input_file));
+        std::fs::write(file, decompile::decompile(vm.memory()))?;
+        return Ok(());
+    }
+
+    if let Some(dir) = args.cfg {
+        let vm = after_self_test(load(&args.input_file));
+        let graph = cfg::Cfg::build(vm.memory());
+        std::fs::create_dir_all(&dir)?;
+        for f in &graph.functions {
+            std::fs::write(std::path::Path::new(&dir).join(format!("fn_{}.dot", f.entry)), graph.function_dot(f))?;
+        }
+        return Ok(());
+    }
+
+    if let Some(port) = args.gdb {
+        let vm = load(&args.input_file);
+        gdb::GdbStub::new(vm).listen(port)?;
+        return Ok(());
+    }
+
+    if args.rpc || args.rpc_port.is_some() {
+        let vm = load(&args.input_file);
+        let mut server = rpc::RpcServer::with_vm(vm);
+        match args.rpc_port {
+            Some(port) => server.listen(port)?,
+            None => server.serve(std::io::stdin().lock(), std::io::stdout())?,
+        }
+        return Ok(());
+    }
+
+    if let Some(port) = args.serve {
+        let vm = load(&args.input_file);
+        server::GameServer::new(vm, args.max_sessions).listen(port)?;
+        return Ok(());
+    }
+
+    if let Some(file) = args.replay {
+        let expected = transcript::Transcript::load(&file)?;
+        let mut vm = load(&expected.program);
+        let replayed = transcript::replay(&mut vm, &expected);
+        if let Some(file) = args.record {
+            replayed.save(&file)?;
+        }
+        match expected.diff(&replayed) {
+            Some(difference) => {
+                println!("Diverged from {}: {}", file, difference);
+                std::process::exit(1);
+            },
+            None => println!("Matches {}: {} entries", file, expected.entries.len()),
+        }
+        return Ok(());
+    }
+
+    if args.tui {
+        let vm = load(&args.input_file);
+        debugger::Debugger::new(vm, symbols).run()?;
+        return Ok(());
+    }
+
+    if args.record.is_some() {
+        c.start_recording();
+    }
     c.run()?;
+    if let (Some(file), Some(mut recorded)) = (args.record, c.finish_recording()) {
+        recorded.program = args.input_file.clone();
+        recorded.save(&file)?;
+    }
 
     Ok(())
 }
+
+/// A VM run up to the first prompt, by which point the self-test has
+/// decrypted the rest of the program.
+fn after_self_test(mut vm: vm::Vm) -> vm::Vm {
+    vm.set_capture_output(true);
+    vm.run_until_input(game::MAX_STEPS_PER_COMMAND);
+    vm
+}
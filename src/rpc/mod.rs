@@ -0,0 +1,307 @@
+use log::{trace, debug, info, warn, error};
+use crate::util::get_file_as_byte_vec;
+use crate::vm::{ExecutionPolicy, Snapshot, StopReason, Vm, MAX_VAL};
+use serde_json::{json, Value};
+use std::collections::BTreeSet;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpListener;
+
+#[allow(dead_code)]
+fn _get_rid_of_log_unused_import_warnings() {
+    trace!("Example trace.");
+    debug!("Example debug.");
+    info!("Example info.");
+    warn!("Example warn.");
+    error!("Example error.");
+}
+
+/// Default step limit for `run`.
+const MAX_STEPS: usize = 100_000_000;
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const NOT_LOADED: i64 = -32000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: &str) -> RpcError {
+        RpcError { code, message: message.to_string() }
+    }
+
+    fn params(message: &str) -> RpcError {
+        RpcError::new(INVALID_PARAMS, message)
+    }
+}
+
+/// JSON-RPC 2.0 over newline-delimited JSON, one request (or batch) per
+/// line.  Params are by name:
+///
+/// - `load {path, memsize?, policy?}` -> `{words}`; memsize is at most
+///   32768, and policy is strict, lenient or compat, defaulting to the
+///   loaded VM's
+/// - `step {count?}`, `run {max_steps?}` -> `{reason, pc, steps}`, where
+///   reason is stepped, breakpoint, input, halted, violation or limit;
+///   violations also say what it was in `violation`
+/// - `send_input {text}`; a missing newline is added
+/// - `read_output` -> `{output}`
+/// - `get_registers` -> `{registers, pc, stack}`
+/// - `read_memory {address, count?}` -> `{words}`
+/// - `write_memory {address, words}`
+/// - `snapshot` -> `{id}`, `restore {id}`
+/// - `set_breakpoint {address}`, `clear_breakpoint {address}`
+#[derive(Default)]
+pub struct RpcServer {
+    vm: Option<Vm>,
+    breakpoints: BTreeSet<usize>,
+    snapshots: Vec<Snapshot>,
+}
+
+impl RpcServer {
+    pub fn new() -> RpcServer {
+        RpcServer::default()
+    }
+
+    /// Starts out with `vm` loaded.
+    pub fn with_vm(mut vm: Vm) -> RpcServer {
+        vm.set_capture_output(true);
+        RpcServer { vm: Some(vm), ..RpcServer::default() }
+    }
+
+    /// Serves one client after another on localhost `port`.
+    pub fn listen(&mut self, port: u16) -> io::Result<()> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        info!("JSON-RPC on {}", listener.local_addr()?);
+        for stream in listener.incoming() {
+            let stream = stream?;
+            info!("JSON-RPC client {}", stream.peer_addr()?);
+            if let Err(e) = self.serve(BufReader::new(stream.try_clone()?), stream) {
+                warn!("JSON-RPC client left: {}", e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Answers each line of `input` on `out` until end of input.
+    pub fn serve(&mut self, input: impl BufRead, mut out: impl Write) -> io::Result<()> {
+        for line in input.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            if let Some(response) = self.handle_line(&line) {
+                writeln!(out, "{}", response)?;
+                out.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The response to one line, if it wants one.
+    pub fn handle_line(&mut self, line: &str) -> Option<Value> {
+        trace!("RPC <- {}", line);
+        match serde_json::from_str::<Value>(line) {
+            Ok(Value::Array(batch)) if !batch.is_empty() => {
+                let responses: Vec<Value> = batch.into_iter().filter_map(|r| self.handle(r)).collect();
+                if responses.is_empty() { None } else { Some(Value::Array(responses)) }
+            },
+            Ok(request) => self.handle(request),
+            Err(e) => Some(failure(Value::Null, RpcError::new(PARSE_ERROR, &e.to_string()))),
+        }
+    }
+
+    /// Nothing for notifications, which have no id.
+    fn handle(&mut self, request: Value) -> Option<Value> {
+        let id = request.get("id").cloned();
+        let method = request["method"].as_str();
+        if request["jsonrpc"] != "2.0" || method.is_none() {
+            return Some(failure(id.unwrap_or(Value::Null), RpcError::new(INVALID_REQUEST, "Not a JSON-RPC 2.0 request")));
+        }
+        let params = match &request["params"] {
+            Value::Null => json!({}),
+            p @ Value::Object(_) => p.clone(),
+            _ => return id.map(|id| failure(id, RpcError::params("Params go by name"))),
+        };
+        let result = self.call(method.unwrap(), &params);
+        let id = id?;
+        Some(match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err(e) => failure(id, e),
+        })
+    }
+
+    pub fn call(&mut self, method: &str, params: &Value) -> Result<Value, RpcError> {
+        if method == "load" {
+            let path = params["path"].as_str().ok_or_else(|| RpcError::params("path is required"))?;
+            if !std::path::Path::new(path).exists() {
+                return Err(RpcError::params(&format!("No such file {}", path)));
+            }
+            let memsize = optional(params, "memsize")?.unwrap_or(MAX_VAL);
+            if memsize > MAX_VAL {
+                return Err(RpcError::params(&format!("memsize can be at most {}", MAX_VAL)));
+            }
+            let policy = match params["policy"].as_str() {
+                Some(p) => p.parse().map_err(|e: String| RpcError::params(&e))?,
+                None => self.vm.as_ref().map_or(ExecutionPolicy::default(), |vm| vm.policy()),
+            };
+            let mut vm = Vm::new(get_file_as_byte_vec(path), memsize);
+            vm.set_policy(policy);
+            *self = RpcServer::with_vm(vm);
+            return Ok(json!({ "words": self.vm().unwrap().memory().len() }));
+        }
+
+        let breakpoints = &mut self.breakpoints;
+        let vm = self.vm.as_mut().ok_or_else(|| RpcError::new(NOT_LOADED, "No program loaded"))?;
+        match method {
+            "step" => Ok(run(vm, breakpoints, optional(params, "count")?.unwrap_or(1), "stepped")),
+            "run" => Ok(run(vm, breakpoints, optional(params, "max_steps")?.unwrap_or(MAX_STEPS), "limit")),
+            "send_input" => {
+                let mut text = params["text"].as_str().ok_or_else(|| RpcError::params("text is required"))?.to_string();
+                if !text.ends_with('\n') {
+                    text.push('\n');
+                }
+                vm.insert_buffer(text);
+                Ok(json!({}))
+            },
+            "read_output" => Ok(json!({ "output": vm.take_output() })),
+            "get_registers" => Ok(json!({ "registers": vm.registers(), "pc": vm.pc(), "stack": vm.stack() })),
+            "read_memory" => {
+                let address = required(params, "address")?;
+                let count = optional(params, "count")?.unwrap_or(1);
+                let memory = vm.memory();
+                let end = address.checked_add(count).filter(|&end| end <= memory.len())
+                    .ok_or_else(|| RpcError::params("Past the end of memory"))?;
+                Ok(json!({ "words": &memory[address..end] }))
+            },
+            "write_memory" => {
+                let address = required(params, "address")?;
+                let words: Vec<u16> = serde_json::from_value(params["words"].clone())
+                    .map_err(|_| RpcError::params("words must be a list of 16-bit numbers"))?;
+                address.checked_add(words.len()).filter(|&end| end <= vm.memory().len())
+                    .ok_or_else(|| RpcError::params("Past the end of memory"))?;
+                for (k, &w) in words.iter().enumerate() {
+                    vm.write_memory(address + k, w);
+                }
+                Ok(json!({ "written": words.len() }))
+            },
+            "snapshot" => {
+                self.snapshots.push(vm.snapshot());
+                Ok(json!({ "id": self.snapshots.len() - 1 }))
+            },
+            "restore" => {
+                let snapshot = self.snapshots.get(required(params, "id")?).ok_or_else(|| RpcError::params("No such snapshot"))?;
+                vm.restore(snapshot);
+                Ok(json!({}))
+            },
+            "set_breakpoint" => {
+                breakpoints.insert(required(params, "address")?);
+                Ok(json!({}))
+            },
+            "clear_breakpoint" => {
+                breakpoints.remove(&required(params, "address")?);
+                Ok(json!({}))
+            },
+            _ => Err(RpcError::new(METHOD_NOT_FOUND, &format!("No method {}", method))),
+        }
+    }
+
+    pub fn vm(&self) -> Option<&Vm> {
+        self.vm.as_ref()
+    }
+}
+
+fn failure(id: Value, e: RpcError) -> Value {
+    json!({ "jsonrpc": "2.0", "id": id, "error": { "code": e.code, "message": e.message } })
+}
+
+fn optional(params: &Value, name: &str) -> Result<Option<usize>, RpcError> {
+    match &params[name] {
+        Value::Null => Ok(None),
+        v => v.as_u64().map(|n| Some(n as usize)).ok_or_else(|| RpcError::params(&format!("{} must be a number", name))),
+    }
+}
+
+fn required(params: &Value, name: &str) -> Result<usize, RpcError> {
+    optional(params, name)?.ok_or_else(|| RpcError::params(&format!("{} is required", name)))
+}
+
+/// `Vm::run_until` as a result, with `finished` as the reason if it ran
+/// all `max_steps`.
+fn run(vm: &mut Vm, breakpoints: &BTreeSet<usize>, max_steps: usize, finished: &str) -> Value {
+    let (reason, steps) = vm.run_until(breakpoints, max_steps);
+    let reason = match reason {
+        StopReason::Violation(pc, v) => {
+            return json!({ "reason": "violation", "violation": v.to_string(), "pc": pc, "steps": steps });
+        },
+        StopReason::Halted => "halted",
+        StopReason::Input => "input",
+        StopReason::Breakpoint(_) => "breakpoint",
+        StopReason::Paused | StopReason::Stepped => finished,
+    };
+    json!({ "reason": reason, "pc": vm.pc(), "steps": steps })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(server: &mut RpcServer, method: &str, params: Value) -> Value {
+        let request = json!({ "jsonrpc": "2.0", "id": 7, "method": method, "params": params });
+        let response = server.handle_line(&request.to_string()).unwrap();
+        assert_eq!(response["id"], 7);
+        response
+    }
+
+    #[test]
+    fn test_methods() {
+        // 0: out 'A'; 2: in R0; 4: add R1 R0 1; 8: halt
+        let mut server = RpcServer::with_vm(Vm::from_words(&[19, 65, 20, 32768, 9, 32769, 32768, 1, 0]));
+        assert_eq!(call(&mut server, "run", json!({}))["result"], json!({ "reason": "input", "pc": 2, "steps": 1 }));
+        assert_eq!(call(&mut server, "read_output", json!({}))["result"]["output"], "A");
+
+        let id = call(&mut server, "snapshot", json!({}))["result"]["id"].clone();
+        call(&mut server, "set_breakpoint", json!({ "address": 4 }));
+        call(&mut server, "send_input", json!({ "text": "x" }));
+        assert_eq!(call(&mut server, "run", json!({}))["result"]["reason"], "breakpoint");
+        assert_eq!(call(&mut server, "step", json!({}))["result"]["pc"], 8);
+        assert_eq!(call(&mut server, "get_registers", json!({}))["result"]["registers"][1], 'x' as u64 + 1);
+        assert_eq!(call(&mut server, "run", json!({}))["result"]["reason"], "halted");
+
+        call(&mut server, "restore", json!({ "id": id }));
+        call(&mut server, "write_memory", json!({ "address": 7, "words": [2] }));
+        assert_eq!(call(&mut server, "read_memory", json!({ "address": 6, "count": 2 }))["result"]["words"], json!([32768, 2]));
+        assert_eq!(server.vm().unwrap().pc(), 2);
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut server = RpcServer::new();
+        assert_eq!(call(&mut server, "step", json!({}))["error"]["code"], NOT_LOADED);
+        assert_eq!(call(&mut server, "load", json!({ "path": "nowhere.bin" }))["error"]["code"], INVALID_PARAMS);
+        assert_eq!(call(&mut server, "load", json!({ "path": "challenge.bin" }))["result"]["words"], 32768);
+        assert_eq!(call(&mut server, "load", json!({ "path": "challenge.bin", "policy": "sloppy" }))["error"]["code"], INVALID_PARAMS);
+        assert_eq!(call(&mut server, "load", json!({ "path": "challenge.bin", "memsize": 1_000_000_000_000u64 }))["error"]["code"], INVALID_PARAMS);
+        assert_eq!(call(&mut server, "fly", json!({}))["error"]["code"], METHOD_NOT_FOUND);
+        assert_eq!(call(&mut server, "read_memory", json!({ "address": 32768 }))["error"]["code"], INVALID_PARAMS);
+        assert_eq!(call(&mut server, "read_memory", json!({ "address": u64::MAX, "count": 1 }))["error"]["code"], INVALID_PARAMS);
+        assert_eq!(call(&mut server, "write_memory", json!({ "address": u64::MAX, "words": [1] }))["error"]["code"], INVALID_PARAMS);
+        assert_eq!(server.handle_line("{").unwrap()["error"]["code"], PARSE_ERROR);
+
+        // Notifications get no answer, even in a batch
+        let batch = r#"[{"jsonrpc": "2.0", "method": "step"}, {"jsonrpc": "2.0", "id": 1, "method": "read_output"}]"#;
+        assert_eq!(server.handle_line(batch).unwrap().as_array().unwrap().len(), 1);
+
+        // Strict stops on an unknown opcode and says why
+        let mut vm = Vm::from_words(&[30, 0]);
+        vm.set_policy(ExecutionPolicy::Strict);
+        let mut server = RpcServer::with_vm(vm);
+        assert_eq!(call(&mut server, "run", json!({}))["result"],
+            json!({ "reason": "violation", "violation": "unknown opcode 30", "pc": 0, "steps": 1 }));
+    }
+}
@@ -0,0 +1,285 @@
+use log::{trace, debug, info, warn, error};
+use crate::symbols::Symbols;
+use crate::vm::{StopReason, Vm, MAX_VAL};
+use rhai::{Array, Dynamic, Engine, EvalAltResult, FnPtr, NativeCallContext};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::rc::Rc;
+
+#[allow(dead_code)]
+fn _get_rid_of_log_unused_import_warnings() {
+    trace!("Example trace.");
+    debug!("Example debug.");
+    info!("Example info.");
+    warn!("Example warn.");
+    error!("Example error.");
+}
+
+/// Default step limit for `run()`.
+const MAX_STEPS: usize = 100_000_000;
+
+type ScriptResult<T> = Result<T, Box<EvalAltResult>>;
+
+/// What scripts see and change.
+struct State {
+    vm: Vm,
+    symbols: Symbols,
+    breakpoints: BTreeSet<usize>,
+    callbacks: BTreeMap<usize, FnPtr>,
+    log: Vec<String>,
+}
+
+impl State {
+    fn resolve(&self, target: &str) -> ScriptResult<usize> {
+        self.symbols.resolve(target)
+            .filter(|&a| a < self.vm.memory().len())
+            .ok_or_else(|| format!("No address or symbol {}", target).into())
+    }
+
+    fn address(&self, a: i64) -> ScriptResult<usize> {
+        if a < 0 || a as usize >= self.vm.memory().len() {
+            return Err(format!("Address {} is out of memory", a).into());
+        }
+        Ok(a as usize)
+    }
+
+    /// `Vm::run_until`, with the breakpoints or without, and why it
+    /// stopped in the words scripts see.
+    fn run(&mut self, max_steps: usize, breakpoints: bool) -> (&'static str, usize) {
+        let none = BTreeSet::new();
+        let (reason, steps) = self.vm.run_until(if breakpoints { &self.breakpoints } else { &none }, max_steps);
+        let reason = match reason {
+            StopReason::Violation(..) => "violation",
+            StopReason::Halted => "halted",
+            StopReason::Input => "input",
+            StopReason::Breakpoint(_) => "breakpoint",
+            StopReason::Paused | StopReason::Stepped if breakpoints => "limit",
+            StopReason::Paused | StopReason::Stepped => "stepped",
+        };
+        (reason, steps)
+    }
+}
+
+/// Rhai bound to a VM, for automating the debugger.
+///
+/// - `reg(r)`, `set_reg(r, v)`, `pc()`, `set_pc(a)`, `mem(a)`, `poke(a, v)`, `stack()`
+/// - `step()`, `step(n)`, `run()`, `run(max_steps)`; `run` says why it stopped:
+///   breakpoint, input, halted, violation or limit
+/// - `input(line)` queues a line of game input, `output()` takes what the game printed
+/// - `set_break(a)`, `clear_break(a)`, and `on_break(a, callback)`, where `run()`
+///   carries on past the breakpoint if the callback returns true
+/// - `addr(name)` and `locate(a)` go between symbols and addresses
+///
+/// Addresses can be numbers or symbol names.  For example, to stop at the
+/// teleporter check and patch it to return at once with a passing result:
+///
+/// ```text
+/// on_break("teleporter_check", || {
+///     set_reg(7, 25734);
+///     set_reg(0, 6);
+///     poke(addr("teleporter_check"), 18); // RET
+///     true
+/// });
+/// input("use teleporter");
+/// run();
+/// ```
+pub struct Scripting {
+    engine: Engine,
+    state: Rc<RefCell<State>>,
+}
+
+impl Scripting {
+    pub fn new(mut vm: Vm, symbols: Symbols, breakpoints: BTreeSet<usize>) -> Scripting {
+        vm.set_capture_output(true);
+        let state = Rc::new(RefCell::new(State { vm, symbols, breakpoints, callbacks: BTreeMap::new(), log: Vec::new() }));
+        let mut engine = Engine::new();
+
+        let s = state.clone();
+        engine.on_print(move |text| s.borrow_mut().log.push(text.to_string()));
+        let s = state.clone();
+        engine.on_debug(move |text, _, _| s.borrow_mut().log.push(text.to_string()));
+
+        let s = state.clone();
+        engine.register_fn("reg", move |r: i64| -> ScriptResult<i64> {
+            let registers = s.borrow().vm.registers();
+            registers.get(r as usize).map(|&v| v as i64).ok_or_else(|| format!("No register {}", r).into())
+        });
+        let s = state.clone();
+        engine.register_fn("set_reg", move |r: i64, v: i64| -> ScriptResult<()> {
+            if !(0..8).contains(&r) {
+                return Err(format!("No register {}", r).into());
+            }
+            if !(0..MAX_VAL as i64).contains(&v) {
+                return Err(format!("Register value {} isn't 15-bit", v).into());
+            }
+            s.borrow_mut().vm.set_register(r as usize, v as u16);
+            Ok(())
+        });
+        let s = state.clone();
+        engine.register_fn("pc", move || s.borrow().vm.pc() as i64);
+        let s = state.clone();
+        engine.register_fn("set_pc", move |a: i64| -> ScriptResult<()> {
+            let a = s.borrow().address(a)?;
+            s.borrow_mut().vm.set_pc(a);
+            Ok(())
+        });
+        let s = state.clone();
+        engine.register_fn("mem", move |a: i64| -> ScriptResult<i64> {
+            let s = s.borrow();
+            Ok(s.vm.memory()[s.address(a)?] as i64)
+        });
+        let s = state.clone();
+        engine.register_fn("mem", move |name: &str| -> ScriptResult<i64> {
+            let s = s.borrow();
+            Ok(s.vm.memory()[s.resolve(name)?] as i64)
+        });
+        let s = state.clone();
+        engine.register_fn("poke", move |a: i64, v: i64| -> ScriptResult<()> {
+            let a = s.borrow().address(a)?;
+            s.borrow_mut().vm.write_memory(a, v as u16);
+            Ok(())
+        });
+        let s = state.clone();
+        engine.register_fn("stack", move || -> Array {
+            s.borrow().vm.stack().iter().map(|&v| Dynamic::from(v as i64)).collect()
+        });
+
+        let s = state.clone();
+        engine.register_fn("step", move || s.borrow_mut().run(1, false).0.to_string());
+        let s = state.clone();
+        engine.register_fn("step", move |n: i64| s.borrow_mut().run(n.max(0) as usize, false).0.to_string());
+        let s = state.clone();
+        engine.register_fn("run", move |ctx: NativeCallContext| run(&ctx, &s, MAX_STEPS));
+        let s = state.clone();
+        engine.register_fn("run", move |ctx: NativeCallContext, n: i64| run(&ctx, &s, n.max(0) as usize));
+
+        let s = state.clone();
+        engine.register_fn("input", move |line: &str| s.borrow_mut().vm.insert_buffer(format!("{}\n", line)));
+        let s = state.clone();
+        engine.register_fn("output", move || s.borrow_mut().vm.take_output());
+
+        let s = state.clone();
+        engine.register_fn("set_break", move |a: i64| -> ScriptResult<()> {
+            let a = s.borrow().address(a)?;
+            s.borrow_mut().breakpoints.insert(a);
+            Ok(())
+        });
+        let s = state.clone();
+        engine.register_fn("set_break", move |name: &str| -> ScriptResult<()> {
+            let a = s.borrow().resolve(name)?;
+            s.borrow_mut().breakpoints.insert(a);
+            Ok(())
+        });
+        let s = state.clone();
+        engine.register_fn("clear_break", move |a: i64| {
+            let mut s = s.borrow_mut();
+            s.breakpoints.remove(&(a as usize));
+            s.callbacks.remove(&(a as usize));
+        });
+        let s = state.clone();
+        engine.register_fn("clear_break", move |name: &str| -> ScriptResult<()> {
+            let a = s.borrow().resolve(name)?;
+            let mut s = s.borrow_mut();
+            s.breakpoints.remove(&a);
+            s.callbacks.remove(&a);
+            Ok(())
+        });
+        let s = state.clone();
+        engine.register_fn("on_break", move |a: i64, callback: FnPtr| -> ScriptResult<()> {
+            let a = s.borrow().address(a)?;
+            let mut s = s.borrow_mut();
+            s.breakpoints.insert(a);
+            s.callbacks.insert(a, callback);
+            Ok(())
+        });
+        let s = state.clone();
+        engine.register_fn("on_break", move |name: &str, callback: FnPtr| -> ScriptResult<()> {
+            let a = s.borrow().resolve(name)?;
+            let mut s = s.borrow_mut();
+            s.breakpoints.insert(a);
+            s.callbacks.insert(a, callback);
+            Ok(())
+        });
+
+        let s = state.clone();
+        engine.register_fn("addr", move |name: &str| -> ScriptResult<i64> { Ok(s.borrow().resolve(name)? as i64) });
+        let s = state.clone();
+        engine.register_fn("locate", move |a: i64| s.borrow().symbols.locate(a.max(0) as usize));
+
+        Scripting { engine, state }
+    }
+
+    /// Runs `source`, giving back its value as text.
+    pub fn eval(&mut self, source: &str) -> Result<String, String> {
+        self.engine.eval::<Dynamic>(source).map(|v| v.to_string()).map_err(|e| e.to_string())
+    }
+
+    /// Takes what the script printed and what the game has output.
+    pub fn take_output(&mut self) -> (Vec<String>, String) {
+        let mut s = self.state.borrow_mut();
+        (std::mem::take(&mut s.log), s.vm.take_output())
+    }
+
+    /// Hands back the VM and the breakpoints as the scripts left them.
+    pub fn finish(self) -> (Vm, BTreeSet<usize>) {
+        drop(self.engine);
+        let state = Rc::try_unwrap(self.state).ok().expect("Script state still shared").into_inner();
+        (state.vm, state.breakpoints)
+    }
+}
+
+/// `run()`, calling back at breakpoints that have callbacks.  Borrows
+/// of the state are let go before each callback, which may use it.
+fn run(ctx: &NativeCallContext, state: &Rc<RefCell<State>>, max_steps: usize) -> ScriptResult<String> {
+    let mut left = max_steps;
+    loop {
+        let (reason, steps) = state.borrow_mut().run(left, true);
+        left -= steps;
+        let pc = state.borrow().vm.pc();
+        let callback = state.borrow().callbacks.get(&pc).cloned();
+        match callback.filter(|_| reason == "breakpoint") {
+            Some(callback) => {
+                let carry_on: Dynamic = callback.call_within_context(ctx, ())?;
+                if !carry_on.as_bool().unwrap_or(false) {
+                    return Ok(reason.to_string());
+                }
+            },
+            None => return Ok(reason.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 0: out 'A'; 2: call 6; 4: in R0; 6: add R1 R1 1; 10: ret
+    const PROGRAM: [u16; 11] = [19, 65, 17, 6, 20, 32768, 9, 32769, 32769, 1, 18];
+
+    #[test]
+    fn test_bindings() {
+        let mut script = Scripting::new(Vm::from_words(&PROGRAM), Symbols::default(), BTreeSet::new());
+        assert_eq!(script.eval("set_break(6); run()"), Ok("breakpoint".to_string()));
+        assert_eq!(script.eval("[pc(), stack(), mem(2)]"), Ok("[6, [4], 17]".to_string()));
+        assert_eq!(script.eval("set_reg(1, 41); step(2); print(locate(pc())); reg(1)"), Ok("42".to_string()));
+        assert_eq!(script.eval("run()"), Ok("input".to_string()));
+        assert_eq!(script.take_output(), (vec!["4".to_string()], "A".to_string()));
+        assert!(script.eval("mem(40000)").is_err());
+        assert!(script.eval("set_reg(0, -1)").is_err());
+        assert!(script.eval("set_reg(0, 32768)").is_err());
+        assert_eq!(script.eval("set_reg(0, 32767); reg(0)"), Ok("32767".to_string()));
+
+        let (vm, breakpoints) = script.finish();
+        assert_eq!((vm.pc(), breakpoints.into_iter().collect::<Vec<_>>()), (4, vec![6]));
+    }
+
+    #[test]
+    fn test_callbacks() {
+        let mut script = Scripting::new(Vm::from_words(&PROGRAM), Symbols::default(), BTreeSet::new());
+        // Patches the routine on the way in and carries on to the IN
+        let source = "on_break(6, || { poke(9, 5); true }); run()";
+        assert_eq!(script.eval(source), Ok("input".to_string()));
+        assert_eq!(script.eval("reg(1)"), Ok("5".to_string()));
+        assert_eq!(script.eval("on_break(6, || false); set_pc(2); run()"), Ok("breakpoint".to_string()));
+    }
+}
@@ -0,0 +1,204 @@
+use log::{trace, debug, info, warn, error};
+use crate::vm::{Snapshot, Vm};
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+#[allow(dead_code)]
+fn _get_rid_of_log_unused_import_warnings() {
+    trace!("Example trace.");
+    debug!("Example debug.");
+    info!("Example info.");
+    warn!("Example warn.");
+    error!("Example error.");
+}
+
+/// Steps a session may run for one line of input before it's dropped as hung.
+const MAX_STEPS: usize = 100_000_000;
+
+const HELP: &str = "/save [name], /load [name], /snapshots, /quit\n";
+
+/// Serves the game over TCP, one VM per client, each a fresh copy of the
+/// blueprint.  Lines starting with `/` are for the server rather than the game.
+pub struct GameServer {
+    blueprint: Vm,
+    max_sessions: usize,
+    sessions: Arc<AtomicUsize>,
+}
+
+/// Counts a session as live until dropped.
+struct SessionGuard(Arc<AtomicUsize>);
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl GameServer {
+    pub fn new(blueprint: Vm, max_sessions: usize) -> GameServer {
+        GameServer { blueprint, max_sessions, sessions: Arc::new(AtomicUsize::new(0)) }
+    }
+
+    /// Serves on localhost `port` until the listener fails.
+    pub fn listen(&self, port: u16) -> io::Result<()> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        info!("Serving the game on {}", listener.local_addr()?);
+        self.serve(listener)
+    }
+
+    pub fn serve(&self, listener: TcpListener) -> io::Result<()> {
+        for stream in listener.incoming() {
+            let mut stream = stream?;
+            let peer = stream.peer_addr()?;
+            if self.sessions.fetch_add(1, Ordering::SeqCst) >= self.max_sessions {
+                self.sessions.fetch_sub(1, Ordering::SeqCst);
+                warn!("Turning away {}: {} sessions already", peer, self.max_sessions);
+                stream.write_all(b"Server full; try again later.\n").ok();
+                continue;
+            }
+            info!("Session for {}", peer);
+            let guard = SessionGuard(self.sessions.clone());
+            let mut vm = self.blueprint.clone();
+            vm.reset();
+            let mut session = Session::new(vm);
+            thread::spawn(move || {
+                let _guard = guard;
+                if let Err(e) = session.connect(stream) {
+                    warn!("Session for {} ended: {}", peer, e);
+                }
+                info!("{} left", peer);
+            });
+        }
+        Ok(())
+    }
+}
+
+/// One client's game and the snapshots they've saved.
+pub struct Session {
+    vm: Vm,
+    snapshots: BTreeMap<String, Snapshot>,
+}
+
+impl Session {
+    pub fn new(mut vm: Vm) -> Session {
+        vm.set_capture_output(true);
+        Session { vm, snapshots: BTreeMap::new() }
+    }
+
+    fn connect(&mut self, stream: TcpStream) -> io::Result<()> {
+        let input = BufReader::new(stream.try_clone()?);
+        let mut out = stream;
+        self.play(input, &mut out)
+    }
+
+    /// Runs the game until it wants a line, sends what it printed, and
+    /// repeats until it halts or the client goes away.
+    pub fn play(&mut self, mut input: impl BufRead, out: &mut impl Write) -> io::Result<()> {
+        loop {
+            let done = self.vm.run_until_input(MAX_STEPS);
+            out.write_all(self.vm.take_output().as_bytes())?;
+            if self.vm.is_stopped() {
+                out.write_all(b"\n[The game has halted.]\n")?;
+                return Ok(());
+            }
+            if !done {
+                out.write_all(b"\n[The game stopped responding.]\n")?;
+                return Ok(());
+            }
+            out.flush()?;
+
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+            let line = line.trim_end_matches(&['\r', '\n'][..]);
+            trace!("Session <- {:?}", line);
+            match line.strip_prefix('/') {
+                Some(command) => {
+                    if command.trim() == "quit" {
+                        return Ok(());
+                    }
+                    out.write_all(self.command(command).as_bytes())?;
+                },
+                None => self.vm.insert_buffer(format!("{}\n", line)),
+            }
+        }
+    }
+
+    fn command(&mut self, command: &str) -> String {
+        let mut words = command.split_whitespace();
+        let verb = words.next().unwrap_or_default();
+        let name = words.next().unwrap_or("default").to_string();
+        match verb {
+            "save" => {
+                self.snapshots.insert(name.clone(), self.vm.snapshot());
+                format!("[Saved {}.]\n", name)
+            },
+            "load" => match self.snapshots.get(&name) {
+                Some(snapshot) => {
+                    self.vm.restore(snapshot);
+                    format!("[Loaded {}.]\n", name)
+                },
+                None => format!("[No snapshot {}.]\n", name),
+            },
+            "snapshots" => {
+                let names: Vec<&str> = self.snapshots.keys().map(|k| k.as_str()).collect();
+                format!("[{}]\n", names.join(", "))
+            },
+            _ => HELP.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::get_file_as_byte_vec;
+    use std::io::{Cursor, Read};
+
+    #[test]
+    fn test_session() {
+        let mut session = Session::new(Vm::new(get_file_as_byte_vec("challenge.bin"), 32768));
+        let mut out = Vec::new();
+        let input = "/save start\ngo doorway\n/load start\n/snapshots\nlook\n/quit\n";
+        session.play(Cursor::new(input), &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("== Dark cave =="));
+        assert!(out.contains("[Saved start.]\n"));
+        assert!(out.contains("[Loaded start.]\n[start]\n"));
+        assert_eq!(out.matches("== Foothills ==").count(), 2);
+    }
+
+    #[test]
+    fn test_session_limit() {
+        // Prints "x" and halts once given a line
+        let blueprint = Vm::from_words(&[19, 'x' as u16, 20, 32768, 0]);
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || GameServer::new(blueprint, 1).serve(listener));
+
+        let mut first = TcpStream::connect(addr).unwrap();
+        let mut x = [0; 1];
+        first.read_exact(&mut x).unwrap();
+        assert_eq!(&x, b"x");
+
+        let mut reply = String::new();
+        TcpStream::connect(addr).unwrap().read_to_string(&mut reply).unwrap();
+        assert_eq!(reply, "Server full; try again later.\n");
+
+        // The first session halting leaves room for a fresh one, once its
+        // thread has wound down
+        first.write_all(b"go\n").unwrap();
+        first.read_to_string(&mut reply).unwrap();
+        let admitted = (0..100).any(|_| {
+            thread::sleep(std::time::Duration::from_millis(10));
+            let mut second = TcpStream::connect(addr).unwrap();
+            second.read_exact(&mut x).is_ok() && &x == b"x"
+        });
+        assert!(admitted);
+    }
+}
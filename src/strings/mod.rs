@@ -0,0 +1,266 @@
+use log::{trace, debug, info, warn, error};
+use crate::cfg::Cfg;
+use crate::vm::{InstructionCode, Vm, MAX_VAL};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+#[allow(dead_code)]
+fn _get_rid_of_log_unused_import_warnings() {
+    trace!("Example trace.");
+    debug!("Example debug.");
+    info!("Example info.");
+    warn!("Example warn.");
+    error!("Example error.");
+}
+
+/// Longest string looked for, in characters.  The journal is 3764.
+const MAX_LEN: usize = 4096;
+
+/// Steps allowed when trying out a call site.
+const MAX_CALL_STEPS: usize = 100_000;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StringEntry {
+    pub address: usize, // Of the length word
+    pub length: usize,
+    pub text: String,
+    pub printer: Option<usize>, // Routine seen printing it
+    pub decoder: Option<usize>, // Per-character routine the printer was given
+    pub encoded: bool,          // Memory doesn't hold the text as is
+    pub references: Vec<usize>, // Instructions with the address as a literal
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StringTable {
+    pub strings: Vec<StringEntry>, // Sorted by address
+}
+
+fn printable(c: char) -> bool {
+    c == '\n' || (' '..='~').contains(&c)
+}
+
+/// The text stored as is at `addr`, if it looks like a string.
+fn plain(memory: &[u16], addr: usize) -> Option<String> {
+    let len = memory[addr] as usize;
+    if len == 0 || len > MAX_LEN || addr + len >= memory.len() {
+        return None;
+    }
+    let text: String = memory[addr + 1..=addr + len].iter()
+        .map(|&w| if w < 128 { w as u8 as char } else { '\0' })
+        .collect();
+    if text.chars().all(printable) { Some(text) } else { None }
+}
+
+/// Registers known to hold a literal just before each CALL, by the address
+/// of the CALL, found by running through its block.
+fn call_sites(cfg: &Cfg) -> Vec<(usize, usize, [Option<u16>; 8])> {
+    let mut sites = Vec::new();
+    for block in cfg.blocks.values() {
+        let mut regs: [Option<u16>; 8] = [None; 8];
+        for (pc, i) in &block.instructions {
+            let (a, b, c) = i.operands;
+            let val = |regs: &[Option<u16>; 8], v: u16| match v as usize {
+                v if v < MAX_VAL => Some(v as u16),
+                v if v < MAX_VAL + 8 => regs[v - MAX_VAL],
+                _ => None,
+            };
+            let (x, y) = (val(&regs, b).map(u32::from), val(&regs, c).map(u32::from));
+            let m = MAX_VAL as u32;
+            let result = match i.operator {
+                InstructionCode::SET => x,
+                InstructionCode::ADD => x.and_then(|x| y.map(|y| (x + y) % m)),
+                InstructionCode::MULT => x.and_then(|x| y.map(|y| (x * y) % m)),
+                InstructionCode::MOD => x.and_then(|x| y.filter(|&y| y != 0).map(|y| x % y)),
+                InstructionCode::AND => x.and_then(|x| y.map(|y| x & y)),
+                InstructionCode::OR => x.and_then(|x| y.map(|y| x | y)),
+                InstructionCode::NOT => x.map(|x| !x & (m - 1)),
+                InstructionCode::EQ => x.and_then(|x| y.map(|y| (x == y) as u32)),
+                InstructionCode::GT => x.and_then(|x| y.map(|y| (x > y) as u32)),
+                InstructionCode::CALL => {
+                    if let Some(target) = val(&regs, a) {
+                        sites.push((*pc, target as usize, regs));
+                    }
+                    regs = [None; 8];
+                    continue;
+                },
+                InstructionCode::RMEM | InstructionCode::POP | InstructionCode::IN => None,
+                _ => continue,
+            };
+            if let Some(r) = (a as usize).checked_sub(MAX_VAL).filter(|&r| r < 8) {
+                regs[r] = result.map(|v| v as u16);
+            }
+        }
+    }
+    sites
+}
+
+impl StringTable {
+    /// Finds strings in `memory`: first by trying every call that passes a
+    /// length-prefixed address in R0, which catches strings decoded as
+    /// they're printed, then by scanning for plain text.
+    pub fn build(memory: &[u16]) -> StringTable {
+        let cfg = Cfg::build(memory);
+        let mut vm = Vm::from_words(memory);
+        let mut found: BTreeMap<usize, StringEntry> = BTreeMap::new();
+
+        for (pc, target, regs) in call_sites(&cfg) {
+            let addr = match regs[0] {
+                Some(addr) if (addr as usize) < memory.len() => addr as usize,
+                _ => continue,
+            };
+            let len = memory[addr] as usize;
+            if len == 0 || len > MAX_LEN || addr + len >= memory.len() || found.contains_key(&addr) {
+                continue;
+            }
+            let registers = [
+                addr as u16,
+                regs[1].unwrap_or(0), regs[2].unwrap_or(0), regs[3].unwrap_or(0),
+                regs[4].unwrap_or(0), regs[5].unwrap_or(0), regs[6].unwrap_or(0), regs[7].unwrap_or(0),
+            ];
+            let text = match vm.call(target, registers, MAX_CALL_STEPS) {
+                Some(text) if text.chars().count() == len && text.chars().all(printable) => text,
+                _ => continue,
+            };
+            trace!("Call at {} printed {:?} from {}", pc, text, addr);
+            found.insert(addr, StringEntry {
+                address: addr,
+                length: len,
+                encoded: plain(memory, addr).as_ref() != Some(&text),
+                text,
+                printer: Some(target),
+                decoder: regs[1].map(|r| r as usize).filter(|&r| r < memory.len()),
+                references: Vec::new(),
+            });
+        }
+
+        let mut addr = 0;
+        while addr < memory.len() {
+            if let Some(entry) = found.get(&addr) {
+                addr += entry.length + 1;
+                continue;
+            }
+            match plain(memory, addr) {
+                Some(text) if text.len() >= 3 && !found.range(addr..=addr + text.len()).any(|_| true) => {
+                    let length = text.len();
+                    found.insert(addr, StringEntry {
+                        address: addr,
+                        length,
+                        text,
+                        printer: None,
+                        decoder: None,
+                        encoded: false,
+                        references: Vec::new(),
+                    });
+                    addr += length + 1;
+                },
+                _ => addr += 1,
+            }
+        }
+
+        for block in cfg.blocks.values() {
+            for (pc, i) in &block.instructions {
+                let (a, b, c) = i.operands;
+                for v in [a, b, c].iter().take(i.operator.size() - 1) {
+                    if let Some(entry) = found.get_mut(&(*v as usize)) {
+                        entry.references.push(*pc);
+                    }
+                }
+            }
+        }
+
+        let table = StringTable {
+            strings: found.into_values().collect(),
+        };
+        debug!("Found {} strings, {} encoded, printed by {:?} through {:?}.",
+               table.strings.len(), table.strings.iter().filter(|s| s.encoded).count(),
+               table.printers(), table.decoders());
+        table
+    }
+
+    pub fn get(&self, address: usize) -> Option<&StringEntry> {
+        self.strings.binary_search_by_key(&address, |s| s.address).ok().map(|k| &self.strings[k])
+    }
+
+    /// Routines that print length-prefixed strings.
+    pub fn printers(&self) -> BTreeSet<usize> {
+        self.strings.iter().filter_map(|s| s.printer).collect()
+    }
+
+    /// Per-character routines handed to the printers.
+    pub fn decoders(&self) -> BTreeSet<usize> {
+        self.strings.iter().filter(|s| s.encoded).filter_map(|s| s.decoder).collect()
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::get_file_as_byte_vec;
+
+    const R0: u16 = 32768;
+    const R1: u16 = 32769;
+    const R2: u16 = 32770;
+    const R3: u16 = 32771;
+    const R4: u16 = 32772;
+
+    #[test]
+    fn test_decode_strings() {
+        let mut code = vec![
+            1, R0, 80,          // 0:  set r0 80
+            1, R1, 54,          // 3:  set r1 54
+            9, R2, 1, 2,        // 6:  add r2 1 2
+            17, 19,             // 10: call 19
+            0,                  // 12: halt
+            0, 0, 0, 0, 0, 0,   // 13: padding
+            // Prints the string at r0 a character at a time through r1
+            1, R3, 1,           // 19: set r3 1
+            15, R4, R0,         // 22: rmem r4 r0
+            5, R4, R3, R4,      // 25: gt r4 r3 r4
+            7, R4, 53,          // 29: jt r4 53
+            2, R0,              // 32: push r0
+            9, R0, R0, R3,      // 34: add r0 r0 r3
+            15, R0, R0,         // 38: rmem r0 r0
+            17, R1,             // 41: call r1
+            3, R0,              // 43: pop r0
+            9, R3, R3, 1,       // 45: add r3 r3 1
+            6, 22,              // 49: jmp 22
+            0, 0,               // 51: padding
+            18,                 // 53: ret
+            // Prints r0 + r2
+            9, R0, R0, R2,      // 54: add r0 r0 r2
+            19, R0,             // 58: out r0
+            18,                 // 60: ret
+        ];
+        code.resize(80, 0);
+        // "Hi" shifted down by 3 at 80, then a plain "abc"
+        code.extend(&[2, 'H' as u16 - 3, 'i' as u16 - 3, 3, 'a' as u16, 'b' as u16, 'c' as u16]);
+
+        let table = StringTable::build(&code);
+
+        let hi = table.get(80).unwrap();
+        assert_eq!((hi.text.as_str(), hi.encoded, hi.printer, hi.decoder), ("Hi", true, Some(19), Some(54)));
+        assert_eq!(hi.references, vec![0]);
+        let abc = table.get(83).unwrap();
+        assert_eq!((abc.text.as_str(), abc.encoded), ("abc", false));
+        assert_eq!(table.decoders().into_iter().collect::<Vec<_>>(), vec![54]);
+    }
+
+    #[test]
+    fn test_challenge_strings() {
+        let mut vm = Vm::new(get_file_as_byte_vec("challenge.bin"), 32768);
+        vm.set_capture_output(true);
+        vm.run_until_input(10_000_000);
+
+        let table = StringTable::build(vm.memory());
+
+        assert!(table.printers().contains(&1458));
+        assert!(table.decoders().contains(&1531));
+        let coin = table.get(28664).unwrap();
+        assert_eq!((coin.text.as_str(), coin.encoded), ("You place the ", true));
+        assert!(table.strings.iter().any(|s| s.text.starts_with("Fireflies were using this dusty old journal")));
+    }
+}
@@ -0,0 +1,341 @@
+use log::{trace, debug, info, warn, error};
+use crate::strings::StringTable;
+use crate::vm::{operand_str, Instruction, InstructionCode, TraceEntry, MAX_VAL};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt::Write;
+
+#[allow(dead_code)]
+fn _get_rid_of_log_unused_import_warnings() {
+    trace!("Example trace.");
+    debug!("Example debug.");
+    info!("Example info.");
+    warn!("Example warn.");
+    error!("Example error.");
+}
+
+/// Farthest a pc can be past a name and still be shown as `name+offset`.
+const MAX_OFFSET: usize = 256;
+
+/// How the words at a symbol should be shown instead of as code.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DataType {
+    String,       // Length-prefixed text
+    Table(usize), // Addresses, shown by name where they have one
+    Words(usize), // Plain numbers
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Symbol {
+    pub address: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+    #[serde(default, rename = "type", skip_serializing_if = "Option::is_none")]
+    pub data: Option<DataType>,
+}
+
+/// The file format: a list rather than a map, so it reads in address order.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct SymbolFile {
+    symbols: Vec<Symbol>,
+}
+
+/// Names, comments and data types by address, shared by the disassembler,
+/// the debugger and the trace renderer.
+#[derive(Debug, Clone, Default)]
+pub struct Symbols {
+    symbols: BTreeMap<usize, Symbol>,
+}
+
+impl Symbols {
+    pub fn load(path: &str) -> Result<Symbols, Box<dyn Error>> {
+        Ok(Symbols::from_json(&std::fs::read_to_string(path)?)?)
+    }
+
+    /// Fails on names used twice, since `break <name>` has to be unambiguous.
+    pub fn from_json(json: &str) -> serde_json::Result<Symbols> {
+        let file: SymbolFile = serde_json::from_str(json)?;
+        let mut symbols = Symbols::default();
+        for s in file.symbols {
+            if let Some(name) = &s.name {
+                if let Some(other) = symbols.resolve(name).filter(|&a| a != s.address) {
+                    return Err(serde::de::Error::custom(format!("{} names both {} and {}", name, other, s.address)));
+                }
+            }
+            symbols.insert(s);
+        }
+        Ok(symbols)
+    }
+
+    pub fn to_json(&self) -> String {
+        let file = SymbolFile { symbols: self.symbols.values().cloned().collect() };
+        serde_json::to_string_pretty(&file).unwrap()
+    }
+
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    /// Adds `symbol`, filling in only what an existing one at the same
+    /// address leaves out.
+    pub fn insert(&mut self, symbol: Symbol) {
+        let existing = self.symbols.entry(symbol.address).or_insert_with(|| Symbol { address: symbol.address, ..Symbol::default() });
+        existing.name = existing.name.take().or(symbol.name);
+        existing.comment = existing.comment.take().or(symbol.comment);
+        existing.data = existing.data.or(symbol.data);
+    }
+
+    /// Adds everything in `other`, keeping what's here where both say something.
+    pub fn extend(&mut self, other: Symbols) {
+        for s in other.symbols.into_values() {
+            self.insert(s);
+        }
+    }
+
+    /// Marks every string in `table` as string data, with the decoded text
+    /// as the comment where memory holds it encoded.
+    pub fn add_strings(&mut self, table: &StringTable) {
+        for s in &table.strings {
+            self.insert(Symbol {
+                address: s.address,
+                name: None,
+                comment: if s.encoded { Some(format!("{:?}", s.text)) } else { None },
+                data: Some(DataType::String),
+            });
+        }
+    }
+
+    pub fn get(&self, address: usize) -> Option<&Symbol> {
+        self.symbols.get(&address)
+    }
+
+    pub fn name(&self, address: usize) -> Option<&str> {
+        self.get(address).and_then(|s| s.name.as_deref())
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.symbols.values().filter_map(|s| s.name.as_deref())
+    }
+
+    /// The address a name stands for, or the number itself.
+    pub fn resolve(&self, name: &str) -> Option<usize> {
+        name.parse().ok()
+            .or_else(|| self.symbols.values().find(|s| s.name.as_deref() == Some(name)).map(|s| s.address))
+    }
+
+    /// `name` or `name+offset` for the closest named code at or before
+    /// `pc`, or the bare number.
+    pub fn locate(&self, pc: usize) -> String {
+        let nearest = self.symbols.range(pc.saturating_sub(MAX_OFFSET)..=pc).rev()
+            .find(|(_, s)| s.name.is_some() && s.data.is_none());
+        match nearest {
+            Some((&a, s)) if a == pc => s.name.clone().unwrap(),
+            Some((&a, s)) => format!("{}+{}", s.name.as_ref().unwrap(), pc - a),
+            None => pc.to_string(),
+        }
+    }
+
+    /// What a literal operand refers to: its name, its comment, or the
+    /// text of a plain string.
+    pub fn note(&self, memory: &[u16], v: u16) -> Option<String> {
+        let s = self.get(v as usize).filter(|_| (v as usize) < MAX_VAL)?;
+        s.name.clone().or_else(|| s.comment.clone()).or_else(|| match s.data {
+            Some(DataType::String) => Some(truncated(&string_at(memory, s.address))),
+            _ => None,
+        })
+    }
+
+    /// Note for the first literal operand of `i` that has one.
+    pub fn note_for(&self, memory: &[u16], i: &Instruction) -> Option<String> {
+        let (a, b, c) = i.operands;
+        [a, b, c].iter().take(i.operator.size() - 1).find_map(|&v| self.note(memory, v))
+    }
+
+    /// A data line for the symbol at `pc`, and the words it covers, if it
+    /// has a data type.
+    pub fn data_line(&self, memory: &[u16], pc: usize) -> Option<(String, usize)> {
+        let s = self.get(pc)?;
+        let (line, size) = match s.data? {
+            DataType::String => {
+                let len = memory.get(pc).cloned().unwrap_or_default() as usize;
+                (format!(".string {:?}", string_at(memory, pc)), len + 1)
+            },
+            DataType::Words(n) => {
+                let words: Vec<String> = words_at(memory, pc, n).iter().map(|w| w.to_string()).collect();
+                (format!(".words {}", words.join(" ")), n)
+            },
+            DataType::Table(n) => {
+                let entries: Vec<String> = words_at(memory, pc, n).iter()
+                    .map(|&w| self.name(w as usize).map(String::from).unwrap_or_else(|| w.to_string()))
+                    .collect();
+                (format!(".table {}", entries.join(" ")), n)
+            },
+        };
+        let line = match &s.comment {
+            Some(comment) => format!("{}  ; {}", line, comment),
+            None => line,
+        };
+        Some((line, size.max(1)))
+    }
+
+    /// One line per traced step: where it was, what it ran, and the
+    /// registers before it ran.
+    pub fn render_trace(&self, memory: &[u16], entries: &[TraceEntry]) -> String {
+        let mut out = String::new();
+        for e in entries {
+            let instruction = self.instruction_str(&e.instruction);
+            let mut line = format!("{:<24} {:<24} {:?}", self.locate(e.pc), instruction, e.registers);
+            if let Some(note) = self.note_for(memory, &e.instruction).filter(|n| !instruction.contains(n.as_str())) {
+                write!(line, "  ; {}", note).unwrap();
+            }
+            writeln!(out, "{}", line).unwrap();
+        }
+        out
+    }
+
+    /// A listing of `memory`: names become labels, data is shown as data,
+    /// and instructions with a literal operand that has a symbol get its
+    /// name or comment.
+    pub fn disassemble(&self, memory: &[u16]) -> String {
+        let mut out = String::new();
+        let mut pc = 0;
+        while pc < memory.len() {
+            if let Some(s) = self.get(pc) {
+                if let Some(name) = &s.name {
+                    writeln!(out, "{}:", name).unwrap();
+                }
+                if let Some((line, size)) = self.data_line(memory, pc) {
+                    writeln!(out, "{:<5} {}", pc, line).unwrap();
+                    pc += size;
+                    continue;
+                }
+                if let Some(comment) = &s.comment {
+                    writeln!(out, "; {}", comment).unwrap();
+                }
+            }
+            let i = match Instruction::at(memory, pc) {
+                Some(i) => i,
+                None => {
+                    writeln!(out, "{:<5} {:<5}", pc, memory[pc]).unwrap();
+                    pc += 1;
+                    continue;
+                },
+            };
+            write!(out, "{:<5} {:<5?}", pc, i.operator).unwrap();
+            let (a, b, c) = i.operands;
+            for (k, &v) in [a, b, c].iter().take(i.operator.size() - 1).enumerate() {
+                let operand = match v as usize {
+                    r if r >= MAX_VAL => format!("R{}", r % MAX_VAL),
+                    10 if i.operator == InstructionCode::OUT => "\\n".to_string(),
+                    _ if i.operator == InstructionCode::OUT => (v as u8 as char).to_string(),
+                    _ => v.to_string(),
+                };
+                write!(out, "{}{:<5}", if k == 0 { "  " } else { " " }, operand).unwrap();
+            }
+            match self.note_for(memory, &i) {
+                Some(note) => writeln!(out, "  ; {}", note).unwrap(),
+                None => writeln!(out).unwrap(),
+            }
+            pc += i.operator.size();
+        }
+        out
+    }
+
+    /// An instruction with named jump and call targets.
+    pub fn instruction_str(&self, i: &Instruction) -> String {
+        let (a, b, _) = i.operands;
+        let target = match i.operator {
+            InstructionCode::JMP | InstructionCode::CALL => Some(a),
+            InstructionCode::JT | InstructionCode::JF => Some(b),
+            _ => None,
+        };
+        match target.filter(|&t| (t as usize) < MAX_VAL).and_then(|t| self.name(t as usize)) {
+            Some(name) => {
+                let mut s = format!("{:?}", i.operator);
+                if let InstructionCode::JT | InstructionCode::JF = i.operator {
+                    write!(s, " {}", operand_str(a)).unwrap();
+                }
+                write!(s, " {}", name).unwrap();
+                s
+            },
+            None => i.to_string(),
+        }
+    }
+}
+
+fn words_at(memory: &[u16], addr: usize, n: usize) -> &[u16] {
+    &memory[addr.min(memory.len())..(addr + n).min(memory.len())]
+}
+
+/// The length-prefixed string at `addr`, empty if that's past the end.
+fn string_at(memory: &[u16], addr: usize) -> String {
+    let len = memory.get(addr).cloned().unwrap_or_default() as usize;
+    words_at(memory, addr + 1, len).iter()
+        .map(|&w| if w < 128 { w as u8 as char } else { '?' })
+        .collect()
+}
+
+fn truncated(text: &str) -> String {
+    let short: String = text.chars().take(60).collect();
+    let more = if text.chars().count() > 60 { "..." } else { "" };
+    format!("{:?}{}", short, more)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const JSON: &str = r#"{
+        "symbols": [
+            { "address": 2, "name": "print_string", "comment": "Prints the string at R0" },
+            { "address": 7, "type": "string" },
+            { "address": 10, "name": "handlers", "type": { "table": 2 } }
+        ]
+    }"#;
+
+    #[test]
+    fn test_symbols() {
+        let symbols = Symbols::from_json(JSON).unwrap();
+        let memory = vec![17, 2, 19, 32768, 18, 0, 0, 2, 'h' as u16, 'i' as u16, 2, 0];
+
+        assert_eq!(symbols.resolve("print_string"), Some(2));
+        assert_eq!(symbols.resolve("40"), Some(40));
+        assert_eq!(symbols.resolve("nowhere"), None);
+        assert_eq!(symbols.locate(4), "print_string+2");
+        assert_eq!(symbols.locate(1), "1");
+        assert_eq!(symbols.note(&memory, 7), Some("\"hi\"".to_string()));
+        assert_eq!(symbols.data_line(&memory, 7), Some((".string \"hi\"".to_string(), 3)));
+        assert_eq!(symbols.data_line(&memory, 10), Some((".table print_string 0".to_string(), 2)));
+
+        let call = Instruction::parse(&memory, 0).unwrap();
+        assert_eq!(symbols.instruction_str(&call), "CALL print_string");
+        assert_eq!(Symbols::from_json(&symbols.to_json()).unwrap().len(), 3);
+
+        let listing = symbols.disassemble(&memory);
+        assert!(listing.starts_with("0     CALL  2      ; print_string\nprint_string:\n; Prints the string at R0\n2     OUT  R0   \n"));
+        assert!(listing.ends_with("7     .string \"hi\"\nhandlers:\n10    .table print_string 0\n"));
+    }
+
+    #[test]
+    fn test_string_past_memory() {
+        let symbols = Symbols::from_json(r#"{ "symbols": [ { "address": 100, "type": "string" } ] }"#).unwrap();
+        let memory = vec![19, 100, 0];
+        assert_eq!(symbols.note(&memory, 100), Some("\"\"".to_string()));
+        assert_eq!(symbols.data_line(&memory, 100), Some((".string \"\"".to_string(), 1)));
+        assert!(symbols.disassemble(&memory).starts_with("0     OUT  d"));
+    }
+
+    #[test]
+    fn test_duplicate_names() {
+        let json = r#"{ "symbols": [ { "address": 1, "name": "a" }, { "address": 2, "name": "a" } ] }"#;
+        assert!(Symbols::from_json(json).is_err());
+    }
+}
@@ -0,0 +1,148 @@
+use log::{trace, debug, info, warn, error};
+pub use crate::vm::Entry;
+use crate::vm::Vm;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt::Write as FmtWrite;
+use std::io;
+
+#[allow(dead_code)]
+fn _get_rid_of_log_unused_import_warnings() {
+    trace!("Example trace.");
+    debug!("Example debug.");
+    info!("Example info.");
+    warn!("Example warn.");
+    error!("Example error.");
+}
+
+/// Steps replay allows the game for each line of input.
+const MAX_STEPS_PER_INPUT: usize = 100_000_000;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Header {
+    program: String,
+}
+
+/// A session's input and output, saved as JSON lines: a header naming the
+/// program, then one `Entry` per line.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Transcript {
+    pub program: String,
+    pub entries: Vec<Entry>,
+}
+
+impl Transcript {
+    pub fn load(path: &str) -> Result<Transcript, Box<dyn Error>> {
+        Transcript::from_jsonl(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn from_jsonl(text: &str) -> Result<Transcript, Box<dyn Error>> {
+        let mut lines = text.lines().filter(|l| !l.trim().is_empty());
+        let header: Header = serde_json::from_str(lines.next().ok_or("Empty transcript")?)?;
+        let entries = lines.map(serde_json::from_str).collect::<Result<_, _>>()?;
+        Ok(Transcript { program: header.program, entries })
+    }
+
+    pub fn to_jsonl(&self) -> String {
+        let mut out = String::new();
+        writeln!(out, "{}", serde_json::to_string(&Header { program: self.program.clone() }).unwrap()).unwrap();
+        for e in &self.entries {
+            writeln!(out, "{}", serde_json::to_string(e).unwrap()).unwrap();
+        }
+        out
+    }
+
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        std::fs::write(path, self.to_jsonl())
+    }
+
+    /// The lines the game read, newlines and all.
+    pub fn inputs(&self) -> impl Iterator<Item = &str> {
+        self.entries.iter().filter_map(|e| match e {
+            Entry::Input { text, .. } => Some(text.as_str()),
+            _ => None,
+        })
+    }
+
+    /// The first place `other` does something different, ignoring times.
+    pub fn diff(&self, other: &Transcript) -> Option<String> {
+        for (k, (a, b)) in self.entries.iter().zip(&other.entries).enumerate() {
+            let (a, b) = (a.untimed(), b.untimed());
+            if a != b {
+                return Some(format!("Entry {}: expected {} at step {} {:?}, got {} at step {} {:?}", k + 1, a.0, a.1, a.2, b.0, b.1, b.2));
+            }
+        }
+        let n = self.entries.len().min(other.entries.len());
+        match (self.entries.get(n), other.entries.get(n)) {
+            (Some(e), None) => Some(format!("Entry {}: expected {} at step {}, got the end", n + 1, e.untimed().0, e.untimed().1)),
+            (None, Some(e)) => Some(format!("Entry {}: expected the end, got {} at step {}", n + 1, e.untimed().0, e.untimed().1)),
+            _ => None,
+        }
+    }
+}
+
+/// Runs `vm` from where it is on the inputs of `transcript`, recording a
+/// new transcript to compare with it.
+pub fn replay(vm: &mut Vm, transcript: &Transcript) -> Transcript {
+    let captured = vm.captures_output();
+    vm.set_capture_output(true);
+    vm.start_recording();
+    for input in transcript.inputs() {
+        vm.run_until_input(MAX_STEPS_PER_INPUT);
+        if vm.is_stopped() {
+            break;
+        }
+        vm.insert_buffer(input.to_string());
+    }
+    vm.run_until_input(MAX_STEPS_PER_INPUT);
+    vm.take_output();
+    vm.set_capture_output(captured);
+    Transcript { program: transcript.program.clone(), entries: vm.stop_recording().unwrap() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Echoes each character read until it reads a 'q', then halts
+    const ECHO: [u16; 14] = [
+        19, '>' as u16,        // 0: out '>'
+        20, 32768,             // 2: in R0
+        19, 32768,             // 4: out R0
+        4, 32769, 32768, 'q' as u16, // 6: eq R1 R0 'q'
+        8, 32769, 2,           // 10: jf R1 2
+        0,                     // 13: halt
+    ];
+
+    #[test]
+    fn test_record_and_replay() {
+        let mut vm = Vm::from_words(&ECHO);
+        vm.set_capture_output(true);
+        vm.start_recording();
+        vm.insert_buffer("hi\n".to_string());
+        vm.run_until_input(1000);
+        vm.insert_buffer("q\n".to_string());
+        vm.run_until_input(1000);
+        let recorded = Transcript { program: "echo".to_string(), entries: vm.stop_recording().unwrap() };
+
+        let untimed: Vec<_> = recorded.entries.iter().map(|e| e.untimed()).collect();
+        assert_eq!(untimed, vec![
+            ("output", 1, ">"),
+            ("input", 2, "hi\n"),
+            ("output", 3, "hi\n"),
+            ("input", 14, "q\n"),
+            ("output", 15, "q"),
+        ]);
+
+        let loaded = Transcript::from_jsonl(&recorded.to_jsonl()).unwrap();
+        assert_eq!(loaded, recorded);
+        let replayed = replay(&mut Vm::from_words(&ECHO), &loaded);
+        assert_eq!(loaded.diff(&replayed), None);
+
+        // A VM that echoes differently shows up in the diff
+        let mut changed = ECHO;
+        changed[1] = '<' as u16;
+        let diverged = replay(&mut Vm::from_words(&changed), &loaded);
+        assert_eq!(loaded.diff(&diverged), Some("Entry 1: expected output at step 1 \">\", got output at step 1 \"<\"".to_string()));
+    }
+}
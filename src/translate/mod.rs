@@ -0,0 +1,404 @@
+use log::{trace, debug, info, warn, error};
+use crate::cfg::{self, BasicBlock};
+use crate::vm::{Instruction, InstructionCode, MAX_VAL};
+use std::fmt::Write as FmtWrite;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+#[allow(dead_code)]
+fn _get_rid_of_log_unused_import_warnings() {
+    trace!("Example trace.");
+    debug!("Example debug.");
+    info!("Example info.");
+    warn!("Example warn.");
+    error!("Example error.");
+}
+
+/// Fallback interpreter and runtime state shared by every generated crate.
+const RUNTIME: &str = r#"
+pub struct State {
+    pub memory: Vec<u16>,
+    pub registers: [u16; 8],
+    pub stack: Vec<u16>,
+    pub pc: usize,
+    pub input: String,
+    pub output: String,
+    pub interpreted: u64, // Instructions run by the fallback interpreter
+    stale: Vec<bool>,     // Translated words overwritten by WMEM
+    modified: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Exit {
+    Halted,
+    NeedsInput,
+}
+
+pub fn checksum(memory: &[u16]) -> u64 {
+    memory.iter().fold(0xcbf29ce484222325, |h, &w| (h ^ w as u64).wrapping_mul(0x100000001b3))
+}
+
+impl State {
+    pub fn new(mut memory: Vec<u16>) -> State {
+        if memory.len() < 32768 {
+            memory.resize(32768, 0);
+        }
+        let size = memory.len();
+        State {
+            memory,
+            registers: [0; 8],
+            stack: Vec::new(),
+            pc: 0,
+            input: String::new(),
+            output: String::new(),
+            interpreted: 0,
+            stale: vec![false; size],
+            modified: false,
+        }
+    }
+
+    fn val(&self, v: u16) -> u16 {
+        if v < 32768 { v } else { self.registers[(v - 32768) as usize & 7] }
+    }
+
+    fn set(&mut self, a: u16, v: u16) {
+        if a >= 32768 {
+            self.registers[(a - 32768) as usize & 7] = v;
+        }
+    }
+
+    /// Returns true if the write landed on translated code.
+    #[inline(always)]
+    fn write(&mut self, addr: usize, v: u16) -> bool {
+        self.memory[addr] = v;
+        if is_translated(addr) {
+            self.stale[addr] = true;
+            self.modified = true;
+            return true;
+        }
+        false
+    }
+
+    #[inline(always)]
+    fn is_stale(&self, start: usize, end: usize) -> bool {
+        self.modified && self.stale[start..end].iter().any(|&s| s)
+    }
+
+    /// Stops on something the spec doesn't allow, leaving the pc on it.
+    pub fn fault(&mut self, pc: usize, message: &str) -> Exit {
+        eprintln!("{} at {}; halting.", message, pc);
+        self.pc = pc;
+        Exit::Halted
+    }
+
+    /// Interprets the single instruction at pc.
+    fn step(&mut self) -> Option<Exit> {
+        self.interpreted += 1;
+        let pc = self.pc;
+        let m = |k: usize| self.memory.get(pc + k).cloned().unwrap_or(0);
+        let (op, a, b, c) = (m(0), m(1), m(2), m(3));
+        match op {
+            0 => return Some(Exit::Halted),
+            1 => { let v = self.val(b); self.set(a, v); self.pc += 3; },
+            2 => { let v = self.val(a); self.stack.push(v); self.pc += 2; },
+            3 => match self.stack.pop() {
+                Some(v) => { self.set(a, v); self.pc += 2; },
+                None => return Some(Exit::Halted),
+            },
+            4 => { let v = (self.val(b) == self.val(c)) as u16; self.set(a, v); self.pc += 4; },
+            5 => { let v = (self.val(b) > self.val(c)) as u16; self.set(a, v); self.pc += 4; },
+            6 => self.pc = self.val(a) as usize,
+            7 => self.pc = if self.val(a) != 0 { self.val(b) as usize } else { pc + 3 },
+            8 => self.pc = if self.val(a) == 0 { self.val(b) as usize } else { pc + 3 },
+            9 => { let v = ((self.val(b) as u32 + self.val(c) as u32) % 32768) as u16; self.set(a, v); self.pc += 4; },
+            10 => { let v = ((self.val(b) as u32 * self.val(c) as u32) % 32768) as u16; self.set(a, v); self.pc += 4; },
+            11 => {
+                let divisor = self.val(c);
+                if divisor == 0 {
+                    return Some(self.fault(pc, "MOD by zero"));
+                }
+                let v = self.val(b) % divisor;
+                self.set(a, v);
+                self.pc += 4;
+            },
+            12 => { let v = self.val(b) & self.val(c); self.set(a, v); self.pc += 4; },
+            13 => { let v = self.val(b) | self.val(c); self.set(a, v); self.pc += 4; },
+            14 => { let v = !self.val(b) & 0x7fff; self.set(a, v); self.pc += 3; },
+            15 => { let v = self.memory[self.val(b) as usize]; self.set(a, v); self.pc += 3; },
+            16 => { let (addr, v) = (self.val(a) as usize, self.val(b)); self.write(addr, v); self.pc += 3; },
+            17 => { self.stack.push(pc as u16 + 2); self.pc = self.val(a) as usize; },
+            18 => match self.stack.pop() {
+                Some(v) => self.pc = v as usize,
+                None => return Some(Exit::Halted),
+            },
+            19 => { self.output.push(self.val(a) as u8 as char); self.pc += 2; },
+            20 => {
+                if self.input.is_empty() {
+                    return Some(Exit::NeedsInput);
+                }
+                let v = self.input.remove(0) as u16;
+                self.set(a, v);
+                self.pc += 2;
+            },
+            21 => self.pc += 1,
+            _ => return Some(Exit::Halted),
+        }
+        None
+    }
+}
+"#;
+
+const MAIN: &str = r#"use std::io::{BufRead, Read, Write};
+
+fn main() {
+    let path = std::env::args().nth(1).unwrap_or_else(|| "challenge.bin".to_string());
+    let mut bytes = Vec::new();
+    std::fs::File::open(&path).expect("no file found").read_to_end(&mut bytes).unwrap();
+    let memory: Vec<u16> = bytes.chunks(2).map(|p| p[0] as u16 | (*p.get(1).unwrap_or(&0) as u16) << 8).collect();
+    let mut state = translated::State::new(memory);
+    if translated::checksum(&state.memory) != translated::CHECKSUM {
+        eprintln!("{} is not the binary this crate was translated from.", path);
+        std::process::exit(1);
+    }
+
+    let stdin = std::io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        let exit = translated::run(&mut state);
+        print!("{}", state.output);
+        std::io::stdout().flush().unwrap();
+        state.output.clear();
+        match (exit, lines.next()) {
+            (translated::Exit::NeedsInput, Some(Ok(line))) => {
+                state.input.push_str(&line);
+                state.input.push('\n');
+            },
+            _ => break,
+        }
+    }
+    eprintln!("{} instructions interpreted.", state.interpreted);
+}
+"#;
+
+/// Renders an operand read as a Rust expression.
+fn val(v: u16) -> String {
+    if (v as usize) < MAX_VAL {
+        format!("{}u16", v)
+    } else {
+        format!("s.registers[{}]", (v as usize - MAX_VAL) & 7)
+    }
+}
+
+/// Renders a register write, or a comment when `a` is not a register.
+fn set(a: u16, expr: &str) -> String {
+    if (a as usize) < MAX_VAL {
+        format!("/* write to literal {} ignored: {} */", a, expr)
+    } else {
+        format!("s.registers[{}] = {};", (a as usize - MAX_VAL) & 7, expr)
+    }
+}
+
+fn translate_instruction(src: &mut String, pc: usize, i: &Instruction) {
+    let (a, b, c) = i.operands;
+    let next = pc + i.operator.size();
+    let line = match i.operator {
+        InstructionCode::HALT => format!("s.pc = {}; return Exit::Halted;", pc),
+        InstructionCode::SET => set(a, &val(b)),
+        InstructionCode::PUSH => format!("s.stack.push({});", val(a)),
+        InstructionCode::POP => format!("match s.stack.pop() {{ Some(v) => {{ {} }}, None => {{ s.pc = {}; return Exit::Halted; }} }}",
+                                        set(a, "v"), pc),
+        InstructionCode::EQ => set(a, &format!("({} == {}) as u16", val(b), val(c))),
+        InstructionCode::GT => set(a, &format!("({} > {}) as u16", val(b), val(c))),
+        InstructionCode::JMP => format!("s.pc = {} as usize;", val(a)),
+        InstructionCode::JT => format!("s.pc = if {} != 0 {{ {} as usize }} else {{ {} }};", val(a), val(b), next),
+        InstructionCode::JF => format!("s.pc = if {} == 0 {{ {} as usize }} else {{ {} }};", val(a), val(b), next),
+        InstructionCode::ADD => set(a, &format!("(({} as u32 + {} as u32) % 32768) as u16", val(b), val(c))),
+        InstructionCode::MULT => set(a, &format!("(({} as u32 * {} as u32) % 32768) as u16", val(b), val(c))),
+        InstructionCode::MOD => format!("{{ let c = {}; if c == 0 {{ return s.fault({}, \"MOD by zero\"); }} {} }}",
+                                        val(c), pc, set(a, &format!("{} % c", val(b)))),
+        InstructionCode::AND => set(a, &format!("{} & {}", val(b), val(c))),
+        InstructionCode::OR => set(a, &format!("{} | {}", val(b), val(c))),
+        InstructionCode::NOT => set(a, &format!("!{} & 0x7fff", val(b))),
+        InstructionCode::RMEM => set(a, &format!("s.memory[{} as usize]", val(b))),
+        InstructionCode::WMEM => format!("if s.write({} as usize, {}) {{ s.pc = {}; continue; }}", val(a), val(b), next),
+        InstructionCode::CALL => format!("s.stack.push({}); s.pc = {} as usize;", next, val(a)),
+        InstructionCode::RET => format!("match s.stack.pop() {{ Some(v) => s.pc = v as usize, None => {{ s.pc = {}; return Exit::Halted; }} }}", pc),
+        InstructionCode::OUT => format!("s.output.push({} as u8 as char);", val(a)),
+        InstructionCode::IN => format!("if s.input.is_empty() {{ s.pc = {}; return Exit::NeedsInput; }} {}",
+                                       pc, set(a, "s.input.remove(0) as u16")),
+        InstructionCode::NOOP => String::new(),
+    };
+    if !line.is_empty() {
+        writeln!(src, "                // {}: {:?}", pc, i.operator).unwrap();
+        writeln!(src, "                {}", line).unwrap();
+    }
+}
+
+fn translate_block(src: &mut String, block: &BasicBlock) {
+    writeln!(src, "            {} if !s.is_stale({}, {}) => {{", block.start, block.start, block.end).unwrap();
+    for (pc, i) in &block.instructions {
+        translate_instruction(src, *pc, i);
+    }
+    if !cfg::ends_block(block.last().operator) {
+        writeln!(src, "                s.pc = {};", block.end).unwrap();
+    }
+    writeln!(src, "            }},").unwrap();
+}
+
+/// Emits the `lib.rs` of a crate running `memory` as native code, with one
+/// match arm per basic block and the interpreter for everything else.
+pub fn translate(memory: &[u16]) -> String {
+    let blocks = cfg::find_blocks(memory, &cfg::call_targets(memory));
+    info!("Translating {} basic blocks.", blocks.len());
+
+    let mut src = String::new();
+    writeln!(src, "// Generated by the synacor translator; do not edit.").unwrap();
+    writeln!(src, "#![allow(unused_parens, clippy::all)]").unwrap();
+    writeln!(src, "{}", RUNTIME).unwrap();
+    writeln!(src, "pub const CHECKSUM: u64 = {};\n", checksum(memory)).unwrap();
+
+    writeln!(src, "fn is_translated(addr: usize) -> bool {{").unwrap();
+    writeln!(src, "    matches!(addr,").unwrap();
+    let ranges: Vec<String> = merge(&blocks).iter().map(|(s, e)| format!("{}..={}", s, e - 1)).collect();
+    for chunk in ranges.chunks(8) {
+        writeln!(src, "        {} |", chunk.join(" | ")).unwrap();
+    }
+    writeln!(src, "        usize::MAX)\n}}\n").unwrap();
+
+    writeln!(src, "pub fn run(s: &mut State) -> Exit {{").unwrap();
+    writeln!(src, "    loop {{").unwrap();
+    writeln!(src, "        match s.pc {{").unwrap();
+    for block in &blocks {
+        translate_block(&mut src, block);
+    }
+    writeln!(src, "            _ => if let Some(exit) = s.step() {{ return exit; }},").unwrap();
+    writeln!(src, "        }}").unwrap();
+    writeln!(src, "    }}").unwrap();
+    writeln!(src, "}}").unwrap();
+    src
+}
+
+/// Coalesces adjacent blocks into address ranges.
+fn merge(blocks: &[BasicBlock]) -> Vec<(usize, usize)> {
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+    for b in blocks {
+        match ranges.last_mut() {
+            Some(last) if last.1 >= b.start => last.1 = last.1.max(b.end),
+            _ => ranges.push((b.start, b.end)),
+        }
+    }
+    ranges
+}
+
+/// Same hash the generated `checksum` computes.
+fn checksum(memory: &[u16]) -> u64 {
+    let mut memory = memory.to_vec();
+    if memory.len() < MAX_VAL {
+        memory.resize(MAX_VAL, 0);
+    }
+    memory.iter().fold(0xcbf29ce484222325, |h, &w| (h ^ w as u64).wrapping_mul(0x100000001b3))
+}
+
+/// Writes a standalone crate named `translated` to `dir`.
+pub fn write_crate(memory: &[u16], dir: &Path) -> io::Result<()> {
+    fs::create_dir_all(dir.join("src"))?;
+    fs::write(dir.join("Cargo.toml"), "[package]\n\
+                                        name = \"translated\"\n\
+                                        version = \"0.1.0\"\n\
+                                        edition = \"2018\"\n\n\
+                                        [workspace]\n\n\
+                                        [profile.release]\n\
+                                        debug = false\n")?;
+    fs::write(dir.join("src/lib.rs"), translate(memory))?;
+    fs::write(dir.join("src/main.rs"), MAIN)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::get_file_as_byte_vec;
+    use crate::vm::Vm;
+    use std::path::PathBuf;
+    use std::process::{Command, Stdio};
+
+    /// A directory of this test run's own for a generated crate.
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("synacor-{}-{}", name, std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        dir
+    }
+
+    #[test]
+    fn test_translate_blocks() {
+        let code = vec![
+            1, 32768, 3,   // 0: set R0 3
+            19, 32768,     // 3: out R0
+            7, 32768, 0,   // 5: jt R0 0
+            0,             // 8: halt
+        ];
+
+        let src = translate(&code);
+
+        assert!(src.contains("            0 if !s.is_stale(0, 8) => {"));
+        assert!(src.contains("s.registers[0] = 3u16;"));
+        assert!(src.contains("s.pc = if s.registers[0] != 0 { 0u16 as usize } else { 8 };"));
+        assert!(src.contains("            8 if !s.is_stale(8, 9) => {"));
+    }
+
+    #[test]
+    fn test_translated_toy_runs() {
+        let code: Vec<u16> = vec![
+            19, 'o' as u16,       // 0: out 'o'
+            19, 'k' as u16,       // 2: out 'k'
+            19, '\n' as u16,      // 4: out '\n'
+            1, 32768, 0,          // 6: set R0 0
+            11, 32769, 5, 32768,  // 9: mod R1 5 R0
+            19, 'x' as u16,       // 13: out 'x'
+            0,                    // 15: halt
+        ];
+        let dir = scratch("translated-toy");
+        write_crate(&code, &dir).unwrap();
+        let bytes: Vec<u8> = code.iter().flat_map(|w| vec![(w & 0xff) as u8, (w >> 8) as u8]).collect();
+        std::fs::write(dir.join("toy.bin"), bytes).unwrap();
+
+        let out = Command::new(env!("CARGO"))
+            .args(["run", "--offline", "--quiet", "--", "toy.bin"])
+            .current_dir(&dir)
+            .stdin(Stdio::null())
+            .output()
+            .unwrap();
+
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert!(out.status.success(), "{}", stderr);
+        assert_eq!(String::from_utf8_lossy(&out.stdout), "ok\n");
+        assert!(stderr.contains("MOD by zero at 9; halting."), "{}", stderr);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    /// Builds the translated crate and checks it prints the same self-test
+    /// output as the interpreter.
+    #[test]
+    fn test_translated_self_test() {
+        let mut vm = Vm::new(get_file_as_byte_vec("challenge.bin"), 32768);
+        vm.set_capture_output(true);
+        let dir = scratch("translated");
+        write_crate(vm.memory(), &dir).unwrap();
+
+        vm.run_until_input(100_000_000);
+        let expected = vm.take_output();
+
+        let out = Command::new(env!("CARGO"))
+            .args(["run", "--offline", "--release", "--quiet", "--"])
+            .arg(std::env::current_dir().unwrap().join("challenge.bin"))
+            .current_dir(&dir)
+            .stdin(Stdio::null())
+            .output()
+            .unwrap();
+
+        assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+        assert_eq!(String::from_utf8_lossy(&out.stdout), expected);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,172 @@
+use log::{trace, debug, info, warn, error};
+use super::{Instruction, MAX_VAL};
+use std::str::FromStr;
+
+#[allow(dead_code)]
+fn _get_rid_of_log_unused_import_warnings() {
+    trace!("Example trace.");
+    debug!("Example debug.");
+    info!("Example info.");
+    warn!("Example warn.");
+    error!("Example error.");
+}
+
+impl Instruction {
+    /// The words the instruction assembles to.
+    pub fn words(&self) -> Vec<u16> {
+        let (a, b, c) = self.operands;
+        let mut words = vec![self.operator as u16, a, b, c];
+        words.truncate(self.operator.size());
+        words
+    }
+}
+
+/// Reads an instruction as `Display` writes it, e.g. `ADD R0 R1 5` or
+/// `OUT '\n'`.  Characters in quotes work for any operand.
+impl FromStr for Instruction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Instruction, String> {
+        let tokens = tokens(s);
+        let (name, operands) = tokens.split_first().ok_or("No instruction")?;
+        let operator = (0..22)
+            .filter_map(|op| Instruction::parse(&[op, 0, 0, 0], 0).ok())
+            .map(|i| i.operator)
+            .find(|o| format!("{:?}", o).eq_ignore_ascii_case(name))
+            .ok_or_else(|| format!("No instruction {}", name))?;
+        if operands.len() != operator.size() - 1 {
+            return Err(format!("{:?} takes {} operands", operator, operator.size() - 1));
+        }
+        let mut words = [0; 3];
+        for (w, o) in words.iter_mut().zip(operands) {
+            *w = operand(o)?;
+        }
+        Ok(Instruction { operator, operands: (words[0], words[1], words[2]) })
+    }
+}
+
+/// Splits a line on spaces, keeping quoted characters whole and
+/// dropping a `;` comment.
+fn tokens(line: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut chars = line.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == ';' {
+            break;
+        } else if c == '\'' {
+            let mut token = String::new();
+            let mut escaped = false;
+            for c in chars.by_ref() {
+                token.push(c);
+                if token.len() > 1 && c == '\'' && !escaped {
+                    break;
+                }
+                escaped = c == '\\' && !escaped;
+            }
+            tokens.push(token);
+        } else {
+            let mut token = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() || c == ';' {
+                    break;
+                }
+                token.push(c);
+                chars.next();
+            }
+            tokens.push(token);
+        }
+    }
+    tokens
+}
+
+/// `R0`..`R7`, a number, or a character in Rust's quoting.
+fn operand(token: &str) -> Result<u16, String> {
+    let bad = || format!("Bad operand {}", token);
+    let v = if let Some(r) = token.strip_prefix('R').or_else(|| token.strip_prefix('r')) {
+        match r.parse::<usize>() {
+            Ok(r) if r < 8 => MAX_VAL + r,
+            _ => return Err(bad()),
+        }
+    } else if let Some(quoted) = token.strip_prefix('\'').and_then(|t| t.strip_suffix('\'')) {
+        unquote(quoted).ok_or_else(bad)? as usize
+    } else {
+        token.parse::<usize>().map_err(|_| bad())?
+    };
+    if v >= MAX_VAL + 8 {
+        return Err(bad());
+    }
+    Ok(v as u16)
+}
+
+fn unquote(s: &str) -> Option<char> {
+    let mut chars = s.chars();
+    let c = match chars.next()? {
+        '\\' => match chars.next()? {
+            'n' => '\n',
+            't' => '\t',
+            'r' => '\r',
+            '0' => '\0',
+            c @ ('\\' | '\'' | '"') => c,
+            'u' => {
+                let hex = chars.as_str().strip_prefix('{')?.strip_suffix('}')?;
+                chars = "".chars();
+                std::char::from_u32(u32::from_str_radix(hex, 16).ok()?)?
+            },
+            _ => return None,
+        },
+        c => c,
+    };
+    if chars.next().is_some() { None } else { Some(c) }
+}
+
+/// One instruction per line, as `Display` writes them.  Fails with the
+/// address of the first word that doesn't start a whole instruction.
+pub fn disassemble(code: &[u16]) -> Result<String, usize> {
+    let mut text = String::new();
+    let mut pc = 0;
+    while pc < code.len() {
+        let i = Instruction::at(code, pc).ok_or(pc)?;
+        text.push_str(&format!("{}\n", i));
+        pc += i.operator.size();
+    }
+    Ok(text)
+}
+
+/// Assembles one instruction per line; blank lines and `;` comments are
+/// skipped.
+pub fn assemble(source: &str) -> Result<Vec<u16>, String> {
+    let mut words = Vec::new();
+    for (k, line) in source.lines().enumerate() {
+        if tokens(line).is_empty() {
+            continue;
+        }
+        let i: Instruction = line.parse().map_err(|e| format!("Line {}: {}", k + 1, e))?;
+        words.extend(i.words());
+    }
+    Ok(words)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_assemble() {
+        let source = "
+            ; prints ';' and a newline
+            OUT ';'
+            out '\\n'  ; lower case works too
+            SET R1 'A'
+            JT R1 R7
+            HALT
+        ";
+        let words = assemble(source).unwrap();
+        assert_eq!(words, vec![19, 59, 19, 10, 1, 32769, 65, 7, 32769, 32775, 0]);
+        assert_eq!(disassemble(&words).unwrap(), "OUT ';'\nOUT '\\n'\nSET R1 65\nJT R1 R7\nHALT\n");
+        assert_eq!(assemble("ADD R0 R8 1"), Err("Line 1: Bad operand R8".to_string()));
+        assert_eq!(assemble("JMP"), Err("Line 1: JMP takes 1 operands".to_string()));
+        assert_eq!(disassemble(&[9, 32768]), Err(0));
+    }
+}
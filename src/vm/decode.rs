@@ -0,0 +1,111 @@
+use super::{Instruction, InstructionCode, MAX_VAL};
+
+/// An operand with its kind worked out once at decode time.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) enum Operand {
+    Lit(u16),
+    Reg(u8),
+}
+
+impl Operand {
+    fn new(value: u16) -> Option<Operand> {
+        match value as usize {
+            v if v < MAX_VAL => Some(Operand::Lit(value)),
+            v if v < MAX_VAL + 8 => Some(Operand::Reg((v - MAX_VAL) as u8)),
+            _ => None,
+        }
+    }
+}
+
+/// Where a result goes once the execution policy has had its say.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) enum Destination {
+    Register(usize),
+    Memory(usize),
+    Nowhere,
+}
+
+/// An instruction ready to execute without looking at memory again.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) struct Decoded {
+    pub op: InstructionCode,
+    pub a: Operand,
+    pub b: Operand,
+    pub c: Operand,
+    pub next: u16, // Address of the following instruction
+}
+
+impl Decoded {
+    /// Returns None for unknown opcodes, operands >= 32776 and
+    /// instructions that run off the end of memory.
+    pub fn decode(memory: &[u16], pc: usize) -> Option<Decoded> {
+        Decoded::decode_with(memory, pc, |_| None)
+    }
+
+    /// Like `decode`, but operands >= 32776 are given to `fix`.
+    pub fn decode_with(memory: &[u16], pc: usize, fix: impl Fn(u16) -> Option<Operand>) -> Option<Decoded> {
+        let i = Instruction::at(memory, pc)?;
+        let operand = |v| Operand::new(v).or_else(|| fix(v));
+        Some(Decoded {
+            op: i.operator,
+            a: operand(i.operands.0)?,
+            b: operand(i.operands.1)?,
+            c: operand(i.operands.2)?,
+            next: (pc + i.operator.size()) as u16,
+        })
+    }
+
+    /// A one-word NOOP standing in for something that can't be run.
+    pub fn noop(pc: usize) -> Decoded {
+        Decoded {
+            op: InstructionCode::NOOP,
+            a: Operand::Lit(0),
+            b: Operand::Lit(0),
+            c: Operand::Lit(0),
+            next: (pc + 1) as u16,
+        }
+    }
+}
+
+/// One lazily filled slot per memory address.
+#[derive(Debug, Clone)]
+pub(crate) struct DecodeCache {
+    slots: Vec<Option<Decoded>>,
+}
+
+impl DecodeCache {
+    pub fn new(size: usize) -> DecodeCache {
+        DecodeCache {
+            slots: vec![None; size],
+        }
+    }
+
+    #[inline(always)]
+    pub fn fetch(&mut self, memory: &[u16], pc: usize) -> Option<Decoded> {
+        let slot = self.slots.get_mut(pc)?;
+        if slot.is_none() {
+            *slot = Decoded::decode(memory, pc);
+        }
+        *slot
+    }
+
+    /// Drops every cached instruction that could cover `addr`.
+    #[inline(always)]
+    pub fn invalidate(&mut self, addr: usize) {
+        for slot in addr.saturating_sub(3)..=addr {
+            self.slots[slot] = None;
+        }
+    }
+
+    pub fn clear(&mut self) {
+        for slot in self.slots.iter_mut() {
+            *slot = None;
+        }
+    }
+
+    /// Empties the cache for a memory of `size` words, reusing its slots.
+    pub fn resize(&mut self, size: usize) {
+        self.clear();
+        self.slots.resize(size, None);
+    }
+}
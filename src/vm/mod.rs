@@ -1,11 +1,22 @@
 use log::{trace, debug, info, warn, error};
-use std::io::{stdin, stdout, Read, Write};
-use std::fs::File;
-use std::io::LineWriter;
-use std::thread;
-use std::time::Duration;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::collections::BTreeSet;
+use std::fmt;
+
+pub mod asm;
+mod decode;
+use decode::{DecodeCache, Decoded, Destination, Operand};
+mod policy;
+pub use policy::{ExecutionPolicy, Violation};
+mod recording;
+pub use recording::{Entry, Recording};
+#[cfg(test)]
+mod properties;
+#[cfg(test)]
+mod reference;
+#[cfg(feature = "superblocks")]
+mod superblock;
+#[cfg(feature = "superblocks")]
+use superblock::BlockCache;
 
 #[allow(dead_code)]
 fn _get_rid_of_log_unused_import_warnings() {
@@ -16,17 +27,11 @@ fn _get_rid_of_log_unused_import_warnings() {
     error!("Example error.");
 }
 
-fn pause() {
-    let mut stdout = stdout();
-    stdout.write(b"Press Enter to continue...").unwrap();
-    stdout.flush().unwrap();
-    stdin().read(&mut [0]).unwrap();
-}
-
-const MAX_VAL: usize = 32768;
+pub const MAX_VAL: usize = 32768;
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(PartialEq, Copy, Clone, Debug)]
-enum InstructionCode {
+pub enum InstructionCode {
     HALT, // 0,  stop execution and terminate the program
     SET,  // 1,  set register <a> to the value of <b>
     PUSH, // 2,  push <a> onto the stack
@@ -55,14 +60,62 @@ enum InstructionCode {
     NOOP  // 21,
 }
 
+impl InstructionCode {
+    /// Number of words the instruction occupies, opcode included.
+    pub fn size(self) -> usize {
+        match self {
+            InstructionCode::HALT | InstructionCode::RET | InstructionCode::NOOP => 1,
+            InstructionCode::PUSH | InstructionCode::POP | InstructionCode::JMP |
+            InstructionCode::CALL | InstructionCode::OUT | InstructionCode::IN => 2,
+            InstructionCode::SET | InstructionCode::JT | InstructionCode::JF |
+            InstructionCode::NOT | InstructionCode::RMEM | InstructionCode::WMEM => 3,
+            InstructionCode::EQ | InstructionCode::GT | InstructionCode::ADD | InstructionCode::MULT |
+            InstructionCode::MOD | InstructionCode::AND | InstructionCode::OR => 4,
+        }
+    }
+}
+
 #[derive(Debug, Copy, Clone, PartialEq)]
-struct Instruction {
-    operator: InstructionCode,
-    operands: (u16, u16, u16)
+pub struct Instruction {
+    pub operator: InstructionCode,
+    pub operands: (u16, u16, u16)
 }
 
 impl Instruction {
-    pub fn parse(code: &Vec<u16>, pc: usize) -> Result<Instruction, Instruction> {
+    /// The instruction at `pc`, or None if there isn't a whole valid one.
+    /// Unlike `parse`, safe to call near the end of `code`.
+    pub fn at(code: &[u16], pc: usize) -> Option<Instruction> {
+        let mut words = [0; 4];
+        let start = pc.min(code.len());
+        let n = (code.len() - start).min(4);
+        words[..n].copy_from_slice(&code[start..start + n]);
+        Instruction::parse(&words, 0).ok().filter(|i| i.operator.size() <= n)
+    }
+
+    /// Addresses of up to `k` instructions that lead straight into `pc`,
+    /// found by decoding forward from a little way back.
+    pub fn before(code: &[u16], pc: usize, k: usize) -> Vec<usize> {
+        for start in pc.saturating_sub(k.saturating_mul(4))..pc {
+            let mut addrs = Vec::new();
+            let mut a = start;
+            while a < pc {
+                match Instruction::at(code, a) {
+                    Some(i) => {
+                        addrs.push(a);
+                        a += i.operator.size();
+                    },
+                    None => break,
+                }
+            }
+            if a == pc {
+                let n = addrs.len();
+                return addrs.split_off(n.saturating_sub(k));
+            }
+        }
+        Vec::new()
+    }
+
+    pub fn parse(code: &[u16], pc: usize) -> Result<Instruction, Instruction> {
         let op: u16 = code[pc];
         match op {
             0 => Ok(Instruction {
@@ -247,18 +300,97 @@ impl Instruction {
     }
 }
 
+/// Renders an operand the way the disassembler does: `R0`..`R7` or a number.
+pub fn operand_str(v: u16) -> String {
+    if v as usize >= MAX_VAL {
+        format!("R{}", v as usize % MAX_VAL)
+    } else {
+        v.to_string()
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (a, b, c) = self.operands;
+        write!(f, "{:?}", self.operator)?;
+        if self.operator == InstructionCode::OUT && a <= 255 {
+            return write!(f, " {:?}", a as u8 as char);
+        }
+        for v in [a, b, c].iter().take(self.operator.size() - 1) {
+            write!(f, " {}", operand_str(*v))?;
+        }
+        Ok(())
+    }
+}
+
+/// One step of a trace: the instruction at `pc` and the registers just
+/// before it ran.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceEntry {
+    pub pc: usize,
+    pub instruction: Instruction,
+    pub registers: [u16; 8],
+}
+
+/// A copy of everything needed to resume execution later.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    memory: Vec<u16>,
+    registers: [u16; 8],
+    stack: Vec<u16>,
+    pc: usize,
+    stopped: bool,
+    buffer: String,
+    steps: u64,
+}
+
+impl Snapshot {
+    pub fn memory(&self) -> &[u16] {
+        &self.memory
+    }
+
+    pub fn registers(&self) -> [u16; 8] {
+        self.registers
+    }
+
+    pub fn stack(&self) -> &[u16] {
+        &self.stack
+    }
+
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+}
+
+/// Why the VM stopped running.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StopReason {
+    Paused, // By whoever is driving it
+    Stepped, // Ran all the steps it was given
+    Breakpoint(usize),
+    Violation(usize, Violation), // Where the policy stopped on it
+    Halted,
+    Input, // Waiting on IN with nothing to read
+}
+
 #[derive(Debug, Clone)]
 pub struct Vm {
     blueprint: Vec<u16>,    // Max 2**15
     memory: Vec<u16>,    // Max 2**15
-    registers: Vec<u16>, // 8
+    registers: [u16; 8],
     stack: Vec<u16>,     // Resizeable
     pc: usize,
-    stopped: Arc<AtomicBool>,
+    stopped: bool,
     buffer: String,
-    breakpoints: Vec<usize>,
-    paused: Arc<AtomicBool>,
-    step_delay: u64,
+    capture_output: bool, // Collect OUT into `output` instead of printing
+    output: String,
+    steps: u64, // Instructions executed since the last reset
+    recording: Option<Box<Recording>>,
+    cache: DecodeCache,
+    #[cfg(feature = "superblocks")]
+    blocks: BlockCache,
+    policy: ExecutionPolicy,
+    violation: Option<Violation>, // What stopped the VM, if it broke the spec
 }
 
 impl Vm {
@@ -266,14 +398,20 @@ impl Vm {
         let mut vm = Vm {
             blueprint: Vec::new(),
             memory: Vec::new(),
-            registers: vec![0; 8],
+            registers: [0; 8],
             stack: Vec::new(),
             pc: 0,
-            stopped: Arc::new(AtomicBool::new(false)),
+            stopped: false,
             buffer: String::new(),
-            breakpoints: Vec::new(),
-            paused: Arc::new(AtomicBool::new(false)),
-            step_delay: 1000,
+            capture_output: false,
+            output: String::new(),
+            steps: 0,
+            recording: None,
+            cache: DecodeCache::new(0),
+            #[cfg(feature = "superblocks")]
+            blocks: BlockCache::new(0),
+            policy: ExecutionPolicy::default(),
+            violation: None,
         };
         for i in 0..input.len()/2 {
             let op: u16 = ((input[i*2+1] as u16) << 8) + (input[i*2] as u16);
@@ -283,433 +421,462 @@ impl Vm {
             vm.blueprint.push(0u16);
         }
         vm.reset();
-
-        // let p = vm.stopped.clone();
-        // ctrlc::set_handler(move || {
-        //     warn!("SIGINT caught!");
-        //     p.store(true, Ordering::SeqCst);
-        // }).expect("Error setting up SIGINT handler.");
-
         vm
     }
 
+    /// A VM whose memory starts out as `words`.
+    pub fn from_words(words: &[u16]) -> Vm {
+        let bytes = words.iter().flat_map(|w| vec![(w & 0xff) as u8, (w >> 8) as u8]).collect();
+        Vm::new(bytes, words.len())
+    }
+
     pub fn reset(&mut self) {
-        self.memory = self.blueprint.clone();
-        self.registers = vec![0; 8];
-        self.stack = Vec::new();
+        self.memory.clone_from(&self.blueprint);
+        self.cache.resize(self.memory.len());
+        #[cfg(feature = "superblocks")]
+        {
+            self.blocks = BlockCache::new(self.memory.len());
+        }
+        self.registers = [0; 8];
+        self.stack.clear();
         self.pc = 0;
-        self.stopped.store(false, Ordering::SeqCst);
-        self.buffer = String::new();
-        self.paused.store(false, Ordering::SeqCst);
+        self.stopped = false;
+        self.buffer.clear();
+        self.output.clear();
+        self.steps = 0;
+        self.violation = None;
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            memory: self.memory.clone(),
+            registers: self.registers,
+            stack: self.stack.clone(),
+            pc: self.pc,
+            stopped: self.stopped,
+            buffer: self.buffer.clone(),
+            steps: self.steps,
+        }
+    }
+
+    pub fn memory(&self) -> &[u16] {
+        &self.memory
+    }
+
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    pub fn registers(&self) -> [u16; 8] {
+        self.registers
     }
 
-    pub fn add_breakpoint(&mut self, bp: usize) {
-        self.breakpoints.push(bp);
+    pub fn stack(&self) -> &[u16] {
+        &self.stack
     }
 
-    pub fn handle_breakpoint(&self) {
-        info!("=== BREAKPOINT ===");
-        info!("PC        => {:?}", self.pc);
-        info!("MEM[PC]   => {:?}", self.memory[self.pc]);
-        info!("REGISTERS => {:?}", self.registers);
-        info!("STACK     => {:?}", self.stack);
+    /// Instructions executed since the last reset.
+    pub fn steps(&self) -> u64 {
+        self.steps
     }
 
+    /// Starts recording input and output, dropping any recording already
+    /// going.
+    pub fn start_recording(&mut self) {
+        self.recording = Some(Box::new(Recording::new()));
+    }
+
+    /// What was recorded since `start_recording`, if anything was.
+    pub fn stop_recording(&mut self) -> Option<Vec<Entry>> {
+        self.recording.take().map(|r| r.finish())
+    }
+
+    pub fn set_policy(&mut self, policy: ExecutionPolicy) {
+        self.policy = policy;
+    }
+
+    pub fn policy(&self) -> ExecutionPolicy {
+        self.policy
+    }
+
+    /// The spec violation that stopped the VM, if one did.
+    pub fn violation(&self) -> Option<&Violation> {
+        self.violation.as_ref()
+    }
+
+    pub fn set_pc(&mut self, pc: usize) {
+        self.pc = pc;
+    }
+
+    pub fn set_register(&mut self, r: usize, v: u16) {
+        self.registers[r] = v;
+    }
+
+    /// Writes a word the way WMEM does, dropping any decoded code there.
+    pub fn write_memory(&mut self, addr: usize, v: u16) {
+        self.memory[addr] = v;
+        self.cache.invalidate(addr);
+        #[cfg(feature = "superblocks")]
+        self.blocks.invalidate(addr);
+    }
+
+    pub fn restore(&mut self, snapshot: &Snapshot) {
+        self.memory = snapshot.memory.clone();
+        self.registers = snapshot.registers;
+        self.stack = snapshot.stack.clone();
+        self.pc = snapshot.pc;
+        self.stopped = snapshot.stopped;
+        self.buffer = snapshot.buffer.clone();
+        self.output = String::new();
+        self.steps = snapshot.steps;
+        self.violation = None;
+        self.cache.clear();
+        #[cfg(feature = "superblocks")]
+        self.blocks.clear();
+    }
+
+    /// Runs the routine at `addr` with `registers` until it returns, then
+    /// puts the VM back as it was.  Returns what the routine printed, or
+    /// None if it halted, wanted input, hit a bad instruction or ran for
+    /// more than `max_steps`.
+    pub fn call(&mut self, addr: usize, registers: [u16; 8], max_steps: usize) -> Option<String> {
+        let origin = self.snapshot();
+        let captured = self.capture_output;
+        let output = self.take_output();
+        let recording = self.recording.take(); // Not part of the session
+        self.capture_output = true;
+        self.registers = registers;
+        self.stack = vec![0];
+        self.pc = addr;
+
+        let mut returned = false;
+        for _ in 0..max_steps {
+            if self.stack.is_empty() {
+                returned = true;
+                break;
+            }
+            if self.pc >= self.memory.len() || self.stopped || self.needs_input() ||
+               self.cache.fetch(&self.memory, self.pc).is_none() {
+                break;
+            }
+            self.step();
+        }
+
+        let printed = self.take_output();
+        self.restore(&origin);
+        self.capture_output = captured;
+        self.output = output;
+        self.recording = recording;
+        if returned { Some(printed) } else { None }
+    }
+
+    /// Runs one instruction, decoded afresh rather than from the cache.
     pub fn execute_once(&mut self) {
-        if self.breakpoints.contains(&self.pc) {
-            self.handle_breakpoint();
-            pause();
+        let d = match Decoded::decode(&self.memory, self.pc) {
+            Some(d) => d,
+            None => match self.undecodable() {
+                Some(d) => d,
+                None => return,
+            },
+        };
+        debug!("== {:?} at {} ==", d, self.pc);
+        debug!("== REGISTERS: {:?}", self.registers);
+        self.execute_decoded(d);
+    }
+
+    /// Same as `execute_once`, but runs from the decode cache.
+    pub fn step(&mut self) {
+        if let Some(d) = self.fetch() {
+            self.execute_decoded(d);
         }
-        match Instruction::parse(&self.memory, self.pc) {
-            Ok(i) => {
-                debug!("=> {:?}", self.memory[self.pc]);
-                debug!("== {:?} ==", i);
-                debug!("== REGISTERS: {:?}", self.registers);
-                match i.operator.clone() {
-                    InstructionCode::NOOP => {
-                        self.pc += 1;
-                    },
-                    InstructionCode::HALT => {
-                        self.stopped.store(true, Ordering::SeqCst);
-                    },
-                    InstructionCode::OUT => {
-                        let a = i.operands.0;
-                        if a >= MAX_VAL as u16 {
-                            if self.registers[a as usize % MAX_VAL] > 255 {
-                                error!("Invalid character to print! {}", self.registers[a as usize % MAX_VAL]);
-                                self.report_error(i, true);
-                                pause();
-                            } else {
-                                print!("{}", self.registers[a as usize % MAX_VAL] as u8 as char);
-                            }
-                        } else {
-                            if a > 255 {
-                                error!("Invalid character to print! {}", a);
-                                self.report_error(i, true);
-                                pause();
-                            } else {
-                                print!("{}", a as u8 as char);
-                            }
-                        }
-                        self.pc += 2;
-                    },
-                    InstructionCode::IN => {
-                        let a = i.operands.0;
-                        while self.buffer.len() == 0 {
-                            // std::io::stdin().read_line(&mut self.buffer).unwrap();
-                            // Other thread will insert into buffer
-                        }
-                        if a >= MAX_VAL as u16 {                 
-                            self.registers[a as usize % MAX_VAL] = self.buffer.remove(0) as u16;
-                        } else {
-                            self.memory[a as usize % MAX_VAL] = self.buffer.remove(0) as u16;
-                        }
-                        self.pc += 2;
-                    },
-                    InstructionCode::JMP => {
-                        let a = i.operands.0;
-                        if a >= MAX_VAL as u16 {
-                            self.pc = self.registers[a as usize % MAX_VAL] as usize;
-                        } else {
-                            self.pc = a as usize;
-                        }
-                    },
-                    InstructionCode::CALL => {
-                        let a = i.operands.0;
-                        self.stack.push(self.pc as u16 + 2);
-                        if a >= MAX_VAL as u16 {
-                            self.pc = self.registers[a as usize % MAX_VAL] as usize;
-                        } else {
-                            self.pc = a as usize;
-                        }
-                    },
-                    InstructionCode::RET => {
-                        self.pc = self.stack.pop().unwrap() as usize;
-                    },
-                    InstructionCode::JT => {
-                        let a = i.operands.0;
-                        let b = i.operands.1;
-                        if a >= MAX_VAL as u16 {
-                            if self.registers[a as usize % MAX_VAL] != 0 {
-                                self.pc = b as usize;
-                            } else {
-                                self.pc += 3;
-                            }
-                        }
-                        else if a != 0 {
-                            self.pc = b as usize;
-                        } else {
-                            self.pc += 3
-                        }
-                    },
-                    InstructionCode::JF => {
-                        let a = i.operands.0;
-                        let b = i.operands.1;
-                        if a >= MAX_VAL as u16 {
-                            if self.registers[a as usize % MAX_VAL] == 0 {
-                                self.pc = b as usize;
-                            } else {
-                                self.pc += 3;
-                            }
-                        }
-                        else if a == 0 {
-                            self.pc = b as usize;
-                        } else {
-                            self.pc += 3;
-                        }
-                    },
-                    InstructionCode::SET => {
-                        let a = i.operands.0;
-                        let b = i.operands.1;
-                        if b >= MAX_VAL as u16 {
-                            self.registers[a as usize % MAX_VAL] = self.registers[b as usize % MAX_VAL];
-                        } else {
-                            self.registers[a as usize % MAX_VAL] = b % MAX_VAL as u16;
-                        }
-                        self.pc += 3;
-                    },
-                    InstructionCode::ADD => {
-                        let a = i.operands.0;
-                        let b = i.operands.1;
-                        let c = i.operands.2;
-                        if a >= MAX_VAL as u16 {
-                            self.registers[a as usize % MAX_VAL] = (
-                                if b >= MAX_VAL as u16 {self.registers[b as usize % MAX_VAL]} else {b} 
-                              + if c >= MAX_VAL as u16 {self.registers[c as usize % MAX_VAL]} else {c}
-                            ) % MAX_VAL as u16;
-                        } else {
-                            error!("Operand [a] doesn't resolve into a register?");
-                        }
-                        self.pc += 4;
-                    },
-                    InstructionCode::MULT => {
-                        let a = i.operands.0;
-                        let b = i.operands.1;
-                        let c = i.operands.2;
-                        if a >= MAX_VAL as u16 {
-                            self.registers[a as usize % MAX_VAL] = ((
-                                if b >= MAX_VAL as u16 {self.registers[b as usize % MAX_VAL] as u64} else {b as u64} 
-                              * if c >= MAX_VAL as u16 {self.registers[c as usize % MAX_VAL] as u64} else {c as u64}
-                            ) % MAX_VAL as u64) as u16;
-                        } else {
-                            error!("Operand [a] doesn't resolve into a register?");
-                        }
-                        self.pc += 4;
-                    },
-                    InstructionCode::MOD => {
-                        let a = i.operands.0;
-                        let b = i.operands.1;
-                        let c = i.operands.2;
-                        if a >= MAX_VAL as u16 {
-                            self.registers[a as usize % MAX_VAL] = (
-                                if b >= MAX_VAL as u16 {self.registers[b as usize % MAX_VAL]} else {b} 
-                              % if c >= MAX_VAL as u16 {self.registers[c as usize % MAX_VAL]} else {c}
-                            ) % MAX_VAL as u16;
-                        } else {
-                            error!("Operand [a] doesn't resolve into a register?");
-                        }
-                        self.pc += 4;
-                    },
-                    InstructionCode::AND => {
-                        let a = i.operands.0;
-                        let b = i.operands.1;
-                        let c = i.operands.2;
-                        if a >= MAX_VAL as u16 {
-                            self.registers[a as usize % MAX_VAL] = (
-                                if b >= MAX_VAL as u16 {self.registers[b as usize % MAX_VAL]} else {b} 
-                              & if c >= MAX_VAL as u16 {self.registers[c as usize % MAX_VAL]} else {c}
-                            ) % MAX_VAL as u16;
-                        } else {
-                            error!("Operand [a] doesn't resolve into a register?");
-                        }
-                        self.pc += 4;
-                    },
-                    InstructionCode::OR => {
-                        let a = i.operands.0;
-                        let b = i.operands.1;
-                        let c = i.operands.2;
-                        if a >= MAX_VAL as u16 {
-                            self.registers[a as usize % MAX_VAL] = (
-                                if b >= MAX_VAL as u16 {self.registers[b as usize % MAX_VAL]} else {b} 
-                              | if c >= MAX_VAL as u16 {self.registers[c as usize % MAX_VAL]} else {c}
-                            ) % MAX_VAL as u16;
-                        } else {
-                            error!("Operand [a] doesn't resolve into a register?");
-                        }
-                        self.pc += 4;
-                    },
-                    InstructionCode::EQ => {
-                        let a = i.operands.0;
-                        let b = i.operands.1;
-                        let c = i.operands.2;
-                        if a >= MAX_VAL as u16 {
-                            self.registers[a as usize % MAX_VAL] = {
-                                if (
-                                    if b >= MAX_VAL as u16 {self.registers[b as usize % MAX_VAL]} else {b}
-                                ) == (
-                                    if c >= MAX_VAL as u16 {self.registers[c as usize % MAX_VAL]} else {c}
-                                ) {1} else {0}
-                            };
-                        } else {
-                            error!("Operand [a] doesn't resolve into a register?");
-                        }
-                        self.pc += 4;
-                    },
-                    InstructionCode::GT => {
-                        let a = i.operands.0;
-                        let b = i.operands.1;
-                        let c = i.operands.2;
-                        if a >= MAX_VAL as u16 {
-                            self.registers[a as usize % MAX_VAL] = {
-                                if (
-                                    if b >= MAX_VAL as u16 {self.registers[b as usize % MAX_VAL]} else {b}
-                                ) > (
-                                    if c >= MAX_VAL as u16 {self.registers[c as usize % MAX_VAL]} else {c}
-                                ) {1} else {0}
-                            };
-                        } else {
-                            error!("Operand [a] doesn't resolve into a register?");
-                        }
-                        self.pc += 4;
-                    },
-                    InstructionCode::NOT => {
-                        let a = i.operands.0;
-                        let b = i.operands.1;
-                        if a >= MAX_VAL as u16 {
-                            self.registers[a as usize % MAX_VAL] = !(
-                                if b >= MAX_VAL as u16 {self.registers[b as usize % MAX_VAL]} else {b}
-                            ) & 0x7fff;
-                        } else {
-                            error!("Operand [a] doesn't resolve into a register?");
-                        }
-                        self.pc += 3;
-                    },
-                    InstructionCode::PUSH => {
-                        let a = i.operands.0;
-                        if a >= MAX_VAL as u16 {
-                            self.stack.push(self.registers[a as usize % MAX_VAL]);
-                        } else {
-                            self.stack.push(a);
-                        }
-                        self.pc += 2;
-                    },
-                    InstructionCode::POP => {
-                        let a = i.operands.0;
-                        if a >= MAX_VAL as u16 {
-                            self.registers[a as usize % MAX_VAL] = self.stack.pop().unwrap();
-                        } else {
-                            error!("Operand [a] doesn't resolve into a register?");
-                        }
-                        self.pc += 2;
-                    },
-                    InstructionCode::RMEM => {
-                        let a = i.operands.0;
-                        let b = i.operands.1;
-                        if a >= MAX_VAL as u16 {
-                            self.registers[a as usize % MAX_VAL] =
-                                if b >= MAX_VAL as u16 {
-                                    self.memory[self.registers[b as usize % MAX_VAL] as usize]
-                                } else {
-                                    self.memory[b as usize]
-                                }
-                            ;
-                        } else {
-                            error!("Operand [a] doesn't resolve into a register?");
-                        }
-                        self.pc += 3;
-                    },
-                    InstructionCode::WMEM => {
-                        let a = i.operands.0;
-                        let b = i.operands.1;
-                        if a >= MAX_VAL as u16 {
-                            self.memory[self.registers[a as usize % MAX_VAL] as usize] =
-                                if b >= MAX_VAL as u16 {
-                                    self.registers[b as usize % MAX_VAL]
-                                } else {
-                                    b
-                                }
-                            ;
-                        } else {
-                            self.memory[a as usize] = 
-                                if b >= MAX_VAL as u16 {
-                                    self.registers[b as usize % MAX_VAL]
-                                } else {
-                                    b
-                                }                    
-                            ;
-                        }
-                        self.pc += 3;
-                    },
+    }
+
+    /// Steps like `run_until_input`, recording each instruction as it goes.
+    /// Also stops before a bad instruction.
+    pub fn trace(&mut self, max_steps: usize) -> Vec<TraceEntry> {
+        let mut entries = Vec::new();
+        for _ in 0..max_steps {
+            if self.is_stopped() || self.needs_input() {
+                break;
+            }
+            let instruction = match Instruction::at(&self.memory, self.pc) {
+                Some(i) => i,
+                None => break,
+            };
+            entries.push(TraceEntry { pc: self.pc, instruction, registers: self.registers });
+            self.step();
+        }
+        entries
+    }
+
+    #[inline(always)]
+    fn fetch(&mut self) -> Option<Decoded> {
+        match self.cache.fetch(&self.memory, self.pc) {
+            Some(d) => Some(d),
+            None => self.undecodable(),
+        }
+    }
+
+    /// What to run at a pc that doesn't decode, if the policy runs anything.
+    fn undecodable(&mut self) -> Option<Decoded> {
+        let pc = self.pc;
+        let i = match Instruction::at(&self.memory, pc) {
+            Some(i) => i,
+            None => {
+                let v = match self.memory.get(pc) {
+                    Some(&op) if Instruction::parse(&[op, 0, 0, 0], 0).is_err() => Violation::BadOpcode(op),
+                    _ => Violation::RanOffMemory(pc),
+                };
+                self.violate(v)?;
+                // Only lenient carries on past a bad opcode
+                return Some(Decoded::noop(pc));
+            },
+        };
+        let (a, b, c) = i.operands;
+        let bad = [a, b, c].iter().copied().find(|&v| v as usize >= MAX_VAL + 8).unwrap_or_default();
+        self.violate(Violation::BadOperand(bad))?;
+        let policy = self.policy;
+        Decoded::decode_with(&self.memory, pc, |v| match policy {
+            ExecutionPolicy::Compat => Some(Operand::Reg((v & 7) as u8)),
+            _ => Some(Operand::Lit(v & 0x7fff)),
+        })
+    }
+
+    #[inline(always)]
+    fn val(&self, o: Operand) -> u16 {
+        match o {
+            Operand::Lit(v) => v,
+            Operand::Reg(r) => self.registers[r as usize & 7],
+        }
+    }
+
+    /// Where a result written to `o` goes.
+    #[inline(always)]
+    fn destination(&mut self, o: Operand) -> Option<Destination> {
+        match o {
+            Operand::Reg(r) => Some(Destination::Register(r as usize & 7)),
+            Operand::Lit(a) => {
+                self.violate(Violation::NotARegister(a))?;
+                match self.policy {
+                    ExecutionPolicy::Compat => self.address(a).map(Destination::Memory),
+                    _ => Some(Destination::Nowhere),
                 }
             },
-            Err(_i) => {
-                error!("Unknown instruction code. = {}", self.memory[self.pc]);
-                std::process::exit(0);
-            }
         }
-        debug!("PC: {}", self.pc);
-    }
-
-    pub fn disassemble(&mut self, filename: String) {
-        info!("disassemble()");
-        let f = File::create(filename).unwrap();
-        let mut file = LineWriter::new(f);
-        let mut pc: usize = 0;
-        while pc < self.memory.len() {
-            match Instruction::parse(&self.memory, pc) {
-                Ok(i) => {
-                    match i.operator.clone() {
-                        InstructionCode::NOOP | InstructionCode::HALT | InstructionCode::RET => {
-                            writeln!(file, "{:<5} {:<5?}", pc, i.operator).unwrap();
-                            pc += 1;
-                        },
-                        InstructionCode::OUT => {
-                            writeln!(file, "{:<5} {:<5?}  {:<5}", pc, i.operator, 
-                                if i.operands.0 >= MAX_VAL as u16 {
-                                    let mut s = "R".to_string();
-                                    s.push_str(&(i.operands.0 % MAX_VAL as u16).to_string());
-                                    s
-                                } else {
-                                    if i.operands.0 == 10 {
-                                        "\\n".to_string()
-                                    } else {
-                                        (i.operands.0 as u8 as char).to_string()
-                                    }
-                                }).unwrap();
-                            pc += 2;
-                        },
-                        InstructionCode::IN | InstructionCode::JMP | InstructionCode::CALL | InstructionCode::PUSH | InstructionCode::POP => {
-                            writeln!(file, "{:<5} {:<5?}  {:<5}", pc, i.operator, 
-                                if i.operands.0 >= MAX_VAL as u16 {
-                                    let mut s = "R".to_string();
-                                    s.push_str(&(i.operands.0 % MAX_VAL as u16).to_string());
-                                    s
-                                } else {i.operands.0.to_string()}
-                            ).unwrap();
-                            pc += 2;
-                        },
-                        InstructionCode::JT | InstructionCode::JF | InstructionCode::SET | InstructionCode::NOT | 
-                        InstructionCode::RMEM | InstructionCode::WMEM => {
-                            writeln!(file, "{:<5} {:<5?}  {:<5} {:<5}", pc, i.operator, 
-                                if i.operands.0 >= MAX_VAL as u16 {
-                                    let mut s = "R".to_string();
-                                    s.push_str(&(i.operands.0 % MAX_VAL as u16).to_string());
-                                    s
-                                } else {i.operands.0.to_string()},
-                                if i.operands.1 >= MAX_VAL as u16 {
-                                    let mut s = "R".to_string();
-                                    s.push_str(&(i.operands.1 % MAX_VAL as u16).to_string());
-                                    s
-                                } else {i.operands.1.to_string()}
-                            ).unwrap();
-                            pc += 3;
-                        },
-                        InstructionCode::ADD | InstructionCode::MULT | InstructionCode::MOD | 
-                        InstructionCode::AND | InstructionCode::OR | InstructionCode::EQ | InstructionCode::GT => {
-                            writeln!(file, "{:<5} {:<5?}  {:<5} {:<5} {:<5}", pc, i.operator, 
-                                if i.operands.0 >= MAX_VAL as u16 {
-                                    let mut s = "R".to_string();
-                                    s.push_str(&(i.operands.0 % MAX_VAL as u16).to_string());
-                                    s
-                                } else {i.operands.0.to_string()},
-                                if i.operands.1 >= MAX_VAL as u16 {
-                                    let mut s = "R".to_string();
-                                    s.push_str(&(i.operands.1 % MAX_VAL as u16).to_string());
-                                    s
-                                } else {i.operands.1.to_string()},
-                                if i.operands.2 >= MAX_VAL as u16 {
-                                    let mut s = "R".to_string();
-                                    s.push_str(&(i.operands.2 % MAX_VAL as u16).to_string());
-                                    s
-                                } else {i.operands.2.to_string()}
-                            ).unwrap();
-
-                            pc += 4;
-                        },
+    }
+
+    #[inline(always)]
+    fn put(&mut self, d: Destination, v: u16) {
+        match d {
+            Destination::Register(r) => self.registers[r] = v,
+            Destination::Memory(addr) => self.write_memory(addr, v),
+            Destination::Nowhere => {},
+        }
+    }
+
+    /// `addr` as an index into memory, wrapped if it's past the end.
+    #[inline(always)]
+    fn address(&mut self, addr: u16) -> Option<usize> {
+        let addr = addr as usize;
+        if addr < self.memory.len() {
+            return Some(addr);
+        }
+        self.violate(Violation::OutOfMemory(addr))?;
+        Some(addr % self.memory.len())
+    }
+
+    #[inline(always)]
+    fn execute_decoded(&mut self, d: Decoded) {
+        self.steps += 1;
+        self.execute(d);
+    }
+
+    /// Runs `d`, returning None if a violation stopped it.
+    #[inline(always)]
+    fn execute(&mut self, d: Decoded) -> Option<()> {
+        let next = d.next as usize;
+        match d.op {
+            InstructionCode::NOOP => {},
+            InstructionCode::HALT => {
+                self.stopped = true;
+                return Some(());
+            },
+            InstructionCode::OUT => {
+                let a = self.val(d.a);
+                if a <= 255 {
+                    self.emit(a as u8 as char);
+                } else {
+                    self.violate(Violation::BadCharacter(a))?;
+                    if self.policy == ExecutionPolicy::Compat {
+                        self.emit(a as u8 as char);
                     }
-                },
-                Err(_) => {
-                    // warn!("Unknown instruction: {:?}", i);
-                    writeln!(file, "{:<5} {:<5}", pc, self.memory[pc]).unwrap();
-                    pc += 1;
                 }
-            }
+            },
+            InstructionCode::IN => {
+                if self.buffer.is_empty() {
+                    // Waits on the IN, unrun, until there's input
+                    self.steps -= 1;
+                    return Some(());
+                }
+                let a = self.destination(d.a)?;
+                let c = self.read();
+                self.put(a, c);
+            },
+            InstructionCode::JMP => {
+                self.pc = self.val(d.a) as usize;
+                return Some(());
+            },
+            InstructionCode::CALL => {
+                self.stack.push(d.next);
+                self.pc = self.val(d.a) as usize;
+                return Some(());
+            },
+            InstructionCode::RET => {
+                match self.stack.pop() {
+                    Some(addr) => self.pc = addr as usize,
+                    None => self.stopped = true,
+                }
+                return Some(());
+            },
+            InstructionCode::JT => {
+                self.pc = if self.val(d.a) != 0 { self.val(d.b) as usize } else { next };
+                return Some(());
+            },
+            InstructionCode::JF => {
+                self.pc = if self.val(d.a) == 0 { self.val(d.b) as usize } else { next };
+                return Some(());
+            },
+            InstructionCode::SET => {
+                let a = self.destination(d.a)?;
+                let b = self.val(d.b);
+                self.put(a, b);
+            },
+            InstructionCode::ADD => {
+                let a = self.destination(d.a)?;
+                let v = ((self.val(d.b) as u32 + self.val(d.c) as u32) % MAX_VAL as u32) as u16;
+                self.put(a, v);
+            },
+            InstructionCode::MULT => {
+                let a = self.destination(d.a)?;
+                let v = ((self.val(d.b) as u32 * self.val(d.c) as u32) % MAX_VAL as u32) as u16;
+                self.put(a, v);
+            },
+            InstructionCode::MOD => {
+                let a = self.destination(d.a)?;
+                let c = self.val(d.c);
+                if c == 0 {
+                    self.violate(Violation::DivideByZero)?;
+                } else {
+                    let v = self.val(d.b) % c;
+                    self.put(a, v);
+                }
+            },
+            InstructionCode::AND => {
+                let a = self.destination(d.a)?;
+                let v = self.val(d.b) & self.val(d.c);
+                self.put(a, v);
+            },
+            InstructionCode::OR => {
+                let a = self.destination(d.a)?;
+                let v = self.val(d.b) | self.val(d.c);
+                self.put(a, v);
+            },
+            InstructionCode::EQ => {
+                let a = self.destination(d.a)?;
+                let v = (self.val(d.b) == self.val(d.c)) as u16;
+                self.put(a, v);
+            },
+            InstructionCode::GT => {
+                let a = self.destination(d.a)?;
+                let v = (self.val(d.b) > self.val(d.c)) as u16;
+                self.put(a, v);
+            },
+            InstructionCode::NOT => {
+                let a = self.destination(d.a)?;
+                let v = !self.val(d.b) & 0x7fff;
+                self.put(a, v);
+            },
+            InstructionCode::PUSH => {
+                let a = self.val(d.a);
+                self.stack.push(a);
+            },
+            InstructionCode::POP => {
+                let a = self.destination(d.a)?;
+                match self.stack.pop() {
+                    Some(v) => self.put(a, v),
+                    None => self.violate(Violation::EmptyStack)?,
+                }
+            },
+            InstructionCode::RMEM => {
+                let a = self.destination(d.a)?;
+                let addr = self.address(self.val(d.b))?;
+                let v = self.memory[addr];
+                self.put(a, v);
+            },
+            InstructionCode::WMEM => {
+                let addr = self.address(self.val(d.a))?;
+                let v = self.val(d.b);
+                self.write_memory(addr, v);
+            },
         }
+        self.pc = next;
+        Some(())
     }
 
-    pub fn execute_until_done(&mut self) {
-        info!("execute_until_done()");
-
-        self.pc = 0;
-        while !self.stopped.load(Ordering::SeqCst) {
-            if !self.paused.load(Ordering::SeqCst) {
-                self.execute_once();
+    /// Steps up to `max_steps`, stopping early for halts, violations the
+    /// policy stops on, input and any of `breakpoints` but the one it starts
+    /// on.  Returns why it stopped and the steps it ran.
+    pub fn run_until(&mut self, breakpoints: &BTreeSet<usize>, max_steps: usize) -> (StopReason, usize) {
+        let mut steps = 0;
+        loop {
+            let reason = if let Some(v) = &self.violation {
+                StopReason::Violation(self.pc, v.clone())
+            } else if self.stopped {
+                StopReason::Halted
+            } else if self.needs_input() {
+                StopReason::Input
+            } else if steps > 0 && breakpoints.contains(&self.pc) {
+                StopReason::Breakpoint(self.pc)
+            } else if steps == max_steps {
+                StopReason::Stepped
             } else {
-                thread::sleep(Duration::from_millis(250));
+                self.step();
+                steps += 1;
+                continue;
+            };
+            return (reason, steps);
+        }
+    }
+
+    /// Runs without any step delay until the VM halts or blocks on `IN`
+    /// with an empty input buffer.  Returns false if `max_steps` ran out first.
+    pub fn run_until_input(&mut self, max_steps: usize) -> bool {
+        #[cfg(feature = "superblocks")]
+        return self.run_superblocks(max_steps);
+        #[cfg(not(feature = "superblocks"))]
+        self.run_decoded(max_steps)
+    }
+
+    /// `run_until_input` on the decode cache one instruction at a time.
+    pub fn run_decoded(&mut self, max_steps: usize) -> bool {
+        for _ in 0..max_steps {
+            if self.stopped {
+                return true;
+            }
+            let d = match self.fetch() {
+                Some(d) => d,
+                None => return true,
+            };
+            if d.op == InstructionCode::IN && self.buffer.is_empty() {
+                return true;
             }
-            thread::sleep(Duration::from_millis(self.step_delay));
+            self.execute_decoded(d);
         }
-        debug!("Execution has stopped.");
+        self.is_stopped() || self.needs_input()
+    }
+
+    pub fn needs_input(&self) -> bool {
+        self.memory.get(self.pc) == Some(&20) && self.buffer.is_empty()
     }
 
     #[allow(dead_code)]
@@ -725,33 +892,57 @@ impl Vm {
 
     #[allow(dead_code)]
     pub fn insert_buffer(&mut self, s: String) {
-        self.buffer.push_str(&*s);
+        self.buffer.push_str(&s);
     }
 
-    #[allow(dead_code)]
-    pub fn pause(&mut self) {
-        self.paused.store(true, Ordering::SeqCst);
+    pub fn captures_output(&self) -> bool {
+        self.capture_output
     }
 
-    #[allow(dead_code)]
-    pub fn unpause(&mut self) {
-        self.paused.store(false, Ordering::SeqCst);
+    pub fn set_capture_output(&mut self, capture: bool) {
+        self.capture_output = capture;
     }
 
-    #[allow(dead_code)]
-    pub fn is_stopped(&mut self) -> bool {
-        self.stopped.load(Ordering::SeqCst)
-    }
-
-    fn report_error(&mut self, i: Instruction, disassemble: bool) {
-        error!("PC        => {:?}", self.pc);
-        error!("MEM[PC]   => {:?}", self.memory[self.pc]);
-        error!("I         => {:?}", i);
-        error!("REGISTERS => {:?}", self.registers);
-        error!("STACK     => {:?}", self.stack);
-        if disassemble {
-            self.disassemble("dump1.asm".to_string());        
+    /// Drains everything written by OUT since the last call.
+    pub fn take_output(&mut self) -> String {
+        std::mem::take(&mut self.output)
+    }
+
+    /// Takes the next input character for IN.
+    fn read(&mut self) -> u16 {
+        let c = self.buffer.remove(0);
+        if let Some(r) = &mut self.recording {
+            r.read(self.steps, c, &self.buffer);
+        }
+        c as u16
+    }
+
+    fn emit(&mut self, c: char) {
+        if let Some(r) = &mut self.recording {
+            r.write(self.steps, c);
+        }
+        if self.capture_output {
+            self.output.push(c);
+        } else {
+            print!("{}", c);
+        }
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+
+    /// Deals with `v` as the policy says.  None if the VM stops on it,
+    /// which leaves the pc on the instruction.
+    fn violate(&mut self, v: Violation) -> Option<()> {
+        if self.policy.carries_on(&v) {
+            warn!("{} at {}", v, self.pc);
+            return Some(());
         }
+        error!("{} at {}", v, self.pc);
+        self.violation = Some(v);
+        self.stopped = true;
+        None
     }
 }
 
@@ -760,13 +951,8 @@ mod tests {
     use super::*;
 
     fn init() {
-        match env_logger::try_init() {
-            Ok(_) => {
-                info!("Initializing logging...");
-            },
-            Err(_) => {
-
-            }
+        if env_logger::try_init().is_ok() {
+            info!("Initializing logging...");
         }
     }
 
@@ -815,6 +1001,14 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_instruction_at_and_before() {
+        let code = vec![21, 19, 65, 1, 32768, 5, 21, 9, 32768];
+        assert_eq!(Instruction::at(&code, 3).map(|i| i.operator), Some(InstructionCode::SET));
+        assert_eq!(Instruction::at(&code, 7), None); // Runs off the end
+        assert_eq!(Instruction::before(&code, 6, 2), vec![1, 3]);
+    }
+
     #[test]
     fn test_vm_creation() {
         init();
@@ -823,15 +1017,112 @@ mod tests {
 
         let mut vm = Vm::new(code, 4);
 
-        assert_false!(
-            vm.is_stopped()
+        assert!(
+            !vm.is_stopped()
         );
 
         vm.execute_once();
 
-        assert_true!(
+        assert!(
             vm.is_stopped()
         );
 
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_snapshot_restore() {
+        init();
+
+        // out 'A'; in R0; halt
+        let code = vec![19u8, 0, 65, 0, 20, 0, 0, 128, 0, 0];
+
+        let mut vm = Vm::new(code, 8);
+        vm.set_capture_output(true);
+
+        assert!(vm.run_until_input(100));
+        assert!(vm.needs_input());
+        assert_eq!(vm.take_output(), "A");
+
+        let snapshot = vm.snapshot();
+        vm.insert_buffer("x".to_string());
+        vm.run_until_input(100);
+        assert!(vm.is_stopped());
+        assert_eq!(vm.registers[0], 'x' as u16);
+
+        vm.restore(&snapshot);
+        assert!(!vm.is_stopped());
+        assert!(vm.needs_input());
+        assert_eq!(vm.registers[0], 0);
+    }
+
+    fn program(words: &[u16]) -> Vec<u8> {
+        words.iter().flat_map(|w| vec![(w & 0xff) as u8, (w >> 8) as u8]).collect()
+    }
+
+    #[test]
+    fn test_decoded_matches_execute_once() {
+        init();
+
+        let mut old = Vm::new(crate::util::get_file_as_byte_vec("challenge.bin"), 32768);
+        old.set_capture_output(true);
+        let mut new = old.clone();
+
+        while !old.is_stopped() && !old.needs_input() {
+            old.execute_once();
+        }
+        assert!(new.run_decoded(10_000_000));
+
+        assert_eq!(new.take_output(), old.take_output());
+        assert_eq!(new.snapshot(), old.snapshot());
+    }
+
+    #[test]
+    fn test_wmem_invalidates_decoded() {
+        init();
+
+        let code = program(&[
+            17, 20,       // call 20
+            16, 20, 19,   // wmem 20 OUT
+            16, 21, 65,   // wmem 21 'A'
+            17, 20,       // call 20
+            0,            // halt
+            0, 0, 0, 0, 0, 0, 0, 0, 0,
+            6, 22,        // jmp 22, becomes out 'A'
+            18,           // ret
+        ]);
+
+        let mut vm = Vm::new(code, 32);
+        vm.set_capture_output(true);
+        vm.run_until_input(100);
+
+        assert!(vm.is_stopped());
+        assert_eq!(vm.take_output(), "A");
+    }
+
+    #[test]
+    fn test_run_until() {
+        // 0: noop; 1: jt 1 6; 4: noop; 5: noop; 6: in R0; 8: pop R0
+        let mut vm = Vm::from_words(&[21, 7, 1, 6, 21, 21, 20, 32768, 3, 32768]);
+        vm.set_policy(ExecutionPolicy::Strict);
+        let breakpoints: BTreeSet<usize> = [0, 1].iter().cloned().collect();
+        assert_eq!(vm.run_until(&breakpoints, 10), (StopReason::Breakpoint(1), 1));
+        assert_eq!(vm.run_until(&breakpoints, 10), (StopReason::Input, 1));
+        vm.insert_buffer("x".to_string());
+        assert_eq!(vm.run_until(&breakpoints, 1), (StopReason::Stepped, 1));
+        assert_eq!(vm.run_until(&breakpoints, 10), (StopReason::Violation(8, Violation::EmptyStack), 1));
+        assert_eq!(Vm::from_words(&[21, 0]).run_until(&BTreeSet::new(), 10), (StopReason::Halted, 2));
+    }
+
+    #[test]
+    fn test_in_waits_for_input() {
+        let mut vm = Vm::from_words(&[20, 32768, 0]); // in R0; halt
+        for step in [Vm::execute_once, Vm::step].iter() {
+            vm.reset();
+            step(&mut vm);
+            assert_eq!((vm.pc(), vm.steps(), vm.is_stopped()), (0, 0, false));
+            vm.insert_buffer("x".to_string());
+            step(&mut vm);
+            assert_eq!((vm.pc(), vm.steps(), vm.registers()[0]), (2, 1, 'x' as u16));
+        }
+    }
+}
@@ -0,0 +1,171 @@
+use log::{trace, debug, info, warn, error};
+use std::fmt;
+use std::str::FromStr;
+
+#[allow(dead_code)]
+fn _get_rid_of_log_unused_import_warnings() {
+    trace!("Example trace.");
+    debug!("Example debug.");
+    info!("Example info.");
+    warn!("Example warn.");
+    error!("Example error.");
+}
+
+/// What the VM does with programs that break the spec.
+///
+/// | Violation                | Strict | Lenient          | Compat               |
+/// |--------------------------|--------|------------------|----------------------|
+/// | unknown opcode           | stop   | skip it as NOOP  | stop                 |
+/// | operand 32776 and up     | stop   | wrap to 15 bits  | register `v & 7`     |
+/// | writing to a literal     | stop   | skip the write   | write memory there   |
+/// | OUT above 255            | stop   | skip it          | print the low byte   |
+/// | POP on an empty stack    | stop   | skip it          | stop                 |
+/// | MOD by zero              | stop   | skip it          | stop                 |
+/// | address past memory      | stop   | wrap             | wrap                 |
+/// | running off memory       | stop   | stop             | stop                 |
+///
+/// Compat follows what most other implementations end up doing: a write
+/// helper that sends literals to memory, registers indexed by the low
+/// bits, `putchar` on the value, and a crash on a bad opcode, an empty
+/// stack or a zero divisor.  Stopping leaves the pc on the instruction
+/// and the reason in `Vm::violation`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExecutionPolicy {
+    Strict,
+    #[default]
+    Lenient,
+    Compat,
+}
+
+impl ExecutionPolicy {
+    /// Whether the VM carries on past `v` rather than stopping on it.
+    pub fn carries_on(self, v: &Violation) -> bool {
+        match (self, v) {
+            (ExecutionPolicy::Strict, _) | (_, Violation::RanOffMemory(_)) => false,
+            (ExecutionPolicy::Lenient, _) => true,
+            (ExecutionPolicy::Compat, v) => !matches!(v,
+                Violation::BadOpcode(_) | Violation::EmptyStack | Violation::DivideByZero),
+        }
+    }
+}
+
+impl FromStr for ExecutionPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ExecutionPolicy, String> {
+        match s {
+            "strict" => Ok(ExecutionPolicy::Strict),
+            "lenient" => Ok(ExecutionPolicy::Lenient),
+            "compat" => Ok(ExecutionPolicy::Compat),
+            _ => Err(format!("No policy {}; try strict, lenient or compat", s)),
+        }
+    }
+}
+
+/// Something a program did that the spec doesn't allow.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Violation {
+    BadOpcode(u16),
+    BadOperand(u16),
+    NotARegister(u16), // A literal where the result should go
+    BadCharacter(u16),
+    EmptyStack,
+    DivideByZero,
+    OutOfMemory(usize), // Read or written
+    RanOffMemory(usize), // An instruction at or running past the end
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Violation::BadOpcode(op) => write!(f, "unknown opcode {}", op),
+            Violation::BadOperand(v) => write!(f, "invalid operand {}", v),
+            Violation::NotARegister(v) => write!(f, "result written to the literal {}", v),
+            Violation::BadCharacter(v) => write!(f, "OUT of {}, which isn't a character", v),
+            Violation::EmptyStack => write!(f, "POP from an empty stack"),
+            Violation::DivideByZero => write!(f, "MOD by zero"),
+            Violation::OutOfMemory(a) => write!(f, "address {} is outside memory", a),
+            Violation::RanOffMemory(a) => write!(f, "no whole instruction at {}", a),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::reference::Stepper;
+    use crate::vm::Vm;
+
+    fn run(words: &[u16], policy: ExecutionPolicy, step: Stepper) -> Vm {
+        let mut vm = Vm::from_words(words);
+        vm.set_policy(policy);
+        vm.set_capture_output(true);
+        for _ in 0..100 {
+            if vm.is_stopped() {
+                break;
+            }
+            step(&mut vm);
+        }
+        vm
+    }
+
+    #[test]
+    fn test_policies() {
+        use ExecutionPolicy::*;
+        let cases: &[(&[u16], ExecutionPolicy, Option<Violation>, &str)] = &[
+            // OUT 300; halt
+            (&[19, 300, 0], Strict, Some(Violation::BadCharacter(300)), "pc 0"),
+            (&[19, 300, 0], Lenient, None, "pc 2"),
+            (&[19, 300, 0], Compat, None, "pc 2 out ,"),
+            // set 4 'A'; halt; 0
+            (&[1, 4, 65, 0, 0], Strict, Some(Violation::NotARegister(4)), "pc 0"),
+            (&[1, 4, 65, 0, 0], Lenient, None, "pc 3"),
+            (&[1, 4, 65, 0, 0], Compat, None, "pc 3 mem[4] 65"),
+            // An unknown opcode, then halt
+            (&[30, 0], Strict, Some(Violation::BadOpcode(30)), "pc 0"),
+            (&[30, 0], Lenient, None, "pc 1"),
+            (&[30, 0], Compat, Some(Violation::BadOpcode(30)), "pc 0"),
+            // add R0 40000 1; halt
+            (&[9, 32768, 40000, 1, 0], Strict, Some(Violation::BadOperand(40000)), "pc 0"),
+            (&[9, 32768, 40000, 1, 0], Lenient, None, "pc 4 R0 7233"),
+            (&[9, 32768, 40000, 1, 0], Compat, None, "pc 4 R0 1"),
+            // pop R0; halt
+            (&[3, 32768, 0], Strict, Some(Violation::EmptyStack), "pc 0"),
+            (&[3, 32768, 0], Lenient, None, "pc 2"),
+            (&[3, 32768, 0], Compat, Some(Violation::EmptyStack), "pc 0"),
+            // mod R0 5 0; halt
+            (&[11, 32768, 5, 0, 0], Strict, Some(Violation::DivideByZero), "pc 0"),
+            (&[11, 32768, 5, 0, 0], Lenient, None, "pc 4"),
+            (&[11, 32768, 5, 0, 0], Compat, Some(Violation::DivideByZero), "pc 0"),
+            // set R1 100; rmem R0 R1; halt
+            (&[1, 32769, 100, 15, 32768, 32769, 0], Strict, Some(Violation::OutOfMemory(100)), "pc 3"),
+            (&[1, 32769, 100, 15, 32768, 32769, 0], Lenient, None, "pc 6 R0 100"),
+            (&[1, 32769, 100, 15, 32768, 32769, 0], Compat, None, "pc 6 R0 100"),
+            // jmp 10, past the end
+            (&[6, 10], Lenient, Some(Violation::RanOffMemory(10)), "pc 10"),
+        ];
+        let steppers: [(&str, Stepper); 3] = [
+            ("execute_once", Vm::execute_once),
+            ("step", Vm::step),
+            ("run_until_input", |vm| { vm.run_until_input(1); }),
+        ];
+        for (name, step) in steppers.iter() {
+            for (words, policy, violation, expected) in cases {
+                let mut vm = run(words, *policy, *step);
+                let mut seen = format!("pc {}", vm.pc());
+                let output = vm.take_output();
+                if !output.is_empty() {
+                    seen += &format!(" out {}", output);
+                }
+                if vm.registers()[0] != 0 {
+                    seen += &format!(" R0 {}", vm.registers()[0]);
+                }
+                if words.len() > 4 && vm.memory()[4] != words[4] {
+                    seen += &format!(" mem[4] {}", vm.memory()[4]);
+                }
+                assert!(vm.is_stopped(), "{} {:?} on {:?}", name, policy, words);
+                assert_eq!((vm.violation(), seen.as_str()), (violation.as_ref(), *expected), "{} {:?} on {:?}", name, policy, words);
+            }
+        }
+    }
+}
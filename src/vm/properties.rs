@@ -0,0 +1,199 @@
+use log::{trace, debug, info, warn, error};
+use super::reference::{lockstep, Reference, Stepper};
+use super::{asm, ExecutionPolicy, Instruction, Vm, MAX_VAL};
+use crate::transcript;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+#[allow(dead_code)]
+fn _get_rid_of_log_unused_import_warnings() {
+    trace!("Example trace.");
+    debug!("Example debug.");
+    info!("Example info.");
+    warn!("Example warn.");
+    error!("Example error.");
+}
+
+const STEPPERS: [(&str, Stepper); 2] = [("execute_once", Vm::execute_once), ("step", Vm::step)];
+
+/// A random program that stays inside the spec however it runs: its
+/// code, a HALT to stop it running off the end, then data.  Registers
+/// only ever hold 15-bit numbers, jumps and the stack only hold
+/// instruction addresses, memory is only read and written in the data,
+/// and OUT only prints characters.
+#[derive(Debug, Clone)]
+pub struct Program {
+    pub words: Vec<u16>,
+    pub code: usize, // Words of code, before the HALT
+}
+
+impl Program {
+    pub fn generate(rng: &mut StdRng, instructions: usize, data: usize) -> Program {
+        let ops: Vec<u16> = (0..instructions)
+            .map(|_| if rng.gen_bool(0.02) { 0 } else { rng.gen_range(1, 22) })
+            .collect();
+        let mut starts = Vec::new();
+        let mut code = 0;
+        for &op in &ops {
+            starts.push(code as u16);
+            code += Instruction::parse(&[op, 0, 0, 0], 0).unwrap().operator.size();
+        }
+        let data_start = code as u16 + 1;
+
+        let mut words = Vec::new();
+        for &op in &ops {
+            words.push(op);
+            let reg = |rng: &mut StdRng| MAX_VAL as u16 + rng.gen_range(0, 8);
+            let value = |rng: &mut StdRng| if rng.gen_bool(0.5) { reg(rng) } else { rng.gen_range(0, MAX_VAL as u16) };
+            let target = |rng: &mut StdRng| starts[rng.gen_range(0, starts.len())];
+            let datum = |rng: &mut StdRng| data_start + rng.gen_range(0, data as u16);
+            let operands = match op {
+                1 | 14 => vec![reg(rng), value(rng)],
+                2 | 6 | 17 => vec![target(rng)],
+                3 | 20 => vec![reg(rng)],
+                4 | 5 | 9 | 10 | 12 | 13 => vec![reg(rng), value(rng), value(rng)],
+                7 | 8 => vec![value(rng), target(rng)],
+                11 => vec![reg(rng), value(rng), rng.gen_range(1, MAX_VAL as u16)],
+                15 => vec![reg(rng), datum(rng)],
+                16 => vec![datum(rng), value(rng)],
+                19 => vec![rng.gen_range(32, 127)],
+                _ => vec![],
+            };
+            words.extend(operands);
+        }
+        words.push(0);
+        words.extend((0..data).map(|_| rng.gen_range(0, MAX_VAL as u16)));
+        Program { words, code }
+    }
+}
+
+fn input(rng: &mut StdRng, lines: usize) -> Vec<String> {
+    (0..lines).map(|_| {
+        let len = rng.gen_range(0, 8);
+        let line: String = (0..len).map(|_| rng.gen_range(b'a', b'z' + 1) as char).collect();
+        format!("{}\n", line)
+    }).collect()
+}
+
+#[test]
+fn test_registers_stay_15_bit() {
+    let mut rng = StdRng::seed_from_u64(49);
+    for _ in 0..300 {
+        let program = Program::generate(&mut rng, 40, 16);
+        let text = input(&mut rng, 4).concat();
+        for (name, step) in STEPPERS.iter() {
+            let mut vm = Vm::from_words(&program.words);
+            vm.set_capture_output(true);
+            vm.insert_buffer(text.clone());
+            for _ in 0..2000 {
+                if vm.is_stopped() || vm.needs_input() {
+                    break;
+                }
+                step(&mut vm);
+                let (registers, stack) = (vm.registers(), vm.stack());
+                assert!(registers.iter().chain(stack).all(|&v| (v as usize) < MAX_VAL),
+                    "{} on {:?}: registers {:?}, stack {:?}", name, program.words, registers, stack);
+                assert!(vm.pc() < vm.memory().len(), "{} on {:?}: pc {}", name, program.words, vm.pc());
+                assert!(vm.memory()[..program.code] == program.words[..program.code], "{} on {:?}: code changed", name, program.words);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_no_panics_on_any_words() {
+    let mut rng = StdRng::seed_from_u64(4949);
+    for _ in 0..500 {
+        let words: Vec<u16> = (0..48).map(|_| match rng.gen_range(0, 4) {
+            0 | 1 => rng.gen_range(0, 23),
+            2 => MAX_VAL as u16 + rng.gen_range(0, 9),
+            _ => rng.gen(),
+        }).collect();
+
+        // Decoding anywhere, even past the end
+        for pc in 0..words.len() + 4 {
+            if let Some(i) = Instruction::at(&words, pc) {
+                i.to_string();
+            }
+            super::decode::Decoded::decode(&words, pc);
+        }
+        let _ = asm::disassemble(&words);
+
+        // Running wherever the spec says what should happen
+        let text = input(&mut rng, 2).concat();
+        for (name, step) in STEPPERS.iter() {
+            let mut vm = Vm::from_words(&words);
+            vm.insert_buffer(text.clone());
+            let result = lockstep(&mut vm, &mut Reference::new(&words, &text), 500, *step);
+            assert!(result.is_ok(), "{} on {:?}: {:?}", name, words, result);
+        }
+
+        // And everywhere else, with no reference, under every policy
+        for policy in [ExecutionPolicy::Strict, ExecutionPolicy::Lenient, ExecutionPolicy::Compat].iter() {
+            for (_, step) in STEPPERS.iter() {
+                let mut vm = Vm::from_words(&words);
+                vm.set_policy(*policy);
+                vm.set_capture_output(true);
+                vm.insert_buffer(text.clone());
+                for _ in 0..500 {
+                    if vm.is_stopped() || vm.needs_input() {
+                        break;
+                    }
+                    step(&mut vm);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_deterministic_replays() {
+    let mut rng = StdRng::seed_from_u64(494949);
+    let mut replays = 0;
+    for _ in 0..100 {
+        let program = Program::generate(&mut rng, 60, 16);
+        let mut vm = Vm::from_words(&program.words);
+        vm.set_capture_output(true);
+        vm.start_recording();
+        // Programs that loop without reading are left out, as replay
+        // would run them to its own, much higher, step limit
+        let mut settled = true;
+        for line in input(&mut rng, 6) {
+            settled &= vm.run_until_input(5000);
+            if vm.is_stopped() {
+                break;
+            }
+            vm.insert_buffer(line);
+        }
+        settled &= vm.run_until_input(5000);
+        let recorded = transcript::Transcript { program: String::new(), entries: vm.stop_recording().unwrap() };
+        if !settled {
+            continue;
+        }
+
+        let mut again = Vm::from_words(&program.words);
+        let replayed = transcript::replay(&mut again, &recorded);
+        assert_eq!(recorded.diff(&replayed), None, "{:?}", program.words);
+        assert_eq!(again.snapshot(), vm.snapshot(), "{:?}", program.words);
+        replays += 1;
+    }
+    assert!(replays > 25, "Only {} programs replayed", replays);
+}
+
+#[test]
+fn test_disassemble_assemble_round_trip() {
+    let mut rng = StdRng::seed_from_u64(49494949);
+    for _ in 0..300 {
+        let program = Program::generate(&mut rng, 40, 1);
+        let code = &program.words[..program.code];
+        let text = asm::disassemble(code).unwrap();
+        assert_eq!(asm::assemble(&text).as_deref(), Ok(code), "{}", text);
+    }
+    // Every valid instruction, including the ones generated programs avoid
+    for _ in 0..3000 {
+        let mut words = vec![rng.gen_range(0, 22)];
+        words.extend((0..3).map(|_| rng.gen_range(0, MAX_VAL as u16 + 8)));
+        let i = Instruction::at(&words, 0).unwrap();
+        assert_eq!(i.to_string().parse::<Instruction>(), Ok(i), "{}", i);
+    }
+}
@@ -0,0 +1,90 @@
+use log::{trace, debug, info, warn, error};
+use serde::{Deserialize, Serialize};
+use std::time::Instant;
+
+#[allow(dead_code)]
+fn _get_rid_of_log_unused_import_warnings() {
+    trace!("Example trace.");
+    debug!("Example debug.");
+    info!("Example info.");
+    warn!("Example warn.");
+    error!("Example error.");
+}
+
+/// One line of a transcript.  `step` is the VM's step count when the
+/// entry began and `ms` the time since recording started, which replays
+/// aren't expected to match.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum Entry {
+    Input { step: u64, ms: u64, text: String },  // A line read by IN
+    Output { step: u64, ms: u64, text: String }, // Everything OUT wrote between two inputs
+}
+
+impl Entry {
+    /// The entry without its time, which is all replays are compared on.
+    pub fn untimed(&self) -> (&str, u64, &str) {
+        match self {
+            Entry::Input { step, text, .. } => ("input", *step, text),
+            Entry::Output { step, text, .. } => ("output", *step, text),
+        }
+    }
+}
+
+/// What a VM is recording.  Output is gathered until the next line of
+/// input is started, and a line is recorded whole when its first
+/// character is read.
+#[derive(Debug, Clone)]
+pub struct Recording {
+    start: Instant,
+    entries: Vec<Entry>,
+    output: Option<(u64, u64, String)>, // step, ms, text
+    unread: usize, // Characters of the last recorded line still to be read
+}
+
+impl Default for Recording {
+    fn default() -> Recording {
+        Recording::new()
+    }
+}
+
+impl Recording {
+    pub fn new() -> Recording {
+        Recording { start: Instant::now(), entries: Vec::new(), output: None, unread: 0 }
+    }
+
+    fn ms(&self) -> u64 {
+        self.start.elapsed().as_millis() as u64
+    }
+
+    pub fn write(&mut self, step: u64, c: char) {
+        let ms = self.ms();
+        self.output.get_or_insert((step, ms, String::new())).2.push(c);
+    }
+
+    /// `c` has just been read, with `rest` still in the input buffer.
+    pub fn read(&mut self, step: u64, c: char, rest: &str) {
+        if self.unread == 0 {
+            self.flush_output();
+            let mut text = c.to_string();
+            if c != '\n' {
+                text.push_str(rest.split_inclusive('\n').next().unwrap_or_default());
+            }
+            self.unread = text.chars().count();
+            let ms = self.ms();
+            self.entries.push(Entry::Input { step, ms, text });
+        }
+        self.unread -= 1;
+    }
+
+    fn flush_output(&mut self) {
+        if let Some((step, ms, text)) = self.output.take() {
+            self.entries.push(Entry::Output { step, ms, text });
+        }
+    }
+
+    pub fn finish(mut self) -> Vec<Entry> {
+        self.flush_output();
+        self.entries
+    }
+}
@@ -0,0 +1,316 @@
+use log::{trace, debug, info, warn, error};
+use super::Vm;
+use std::collections::VecDeque;
+
+#[allow(dead_code)]
+fn _get_rid_of_log_unused_import_warnings() {
+    trace!("Example trace.");
+    debug!("Example debug.");
+    info!("Example info.");
+    warn!("Example warn.");
+    error!("Example error.");
+}
+
+/// Operands each opcode takes, by opcode.
+const OPERANDS: [usize; 22] = [0, 2, 1, 1, 3, 3, 1, 2, 2, 3, 3, 3, 3, 3, 2, 2, 2, 1, 0, 1, 1, 0];
+
+/// One way of running a single instruction on a `Vm`.
+pub type Stepper = fn(&mut Vm);
+
+/// Something the spec doesn't allow.  The reference stops on these, so
+/// `lockstep` only ever compares runs the spec defines.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Fault {
+    BadOpcode(u16),
+    BadOperand(u16),
+    NotARegister(u16),
+    OutOfMemory(usize),
+    EmptyStack,
+    DivideByZero,
+    BadCharacter(u16),
+    NoInput,
+}
+
+/// The spec, as plainly as it can be written: no decoding ahead, no
+/// caches, and every operand checked.
+#[derive(Debug, Clone)]
+pub struct Reference {
+    pub memory: Vec<u16>,
+    pub registers: [u16; 8],
+    pub stack: Vec<u16>,
+    pub pc: usize,
+    pub halted: bool,
+    pub input: VecDeque<u16>,
+    pub output: String,
+}
+
+impl Reference {
+    pub fn new(memory: &[u16], input: &str) -> Reference {
+        Reference {
+            memory: memory.to_vec(),
+            registers: [0; 8],
+            stack: Vec::new(),
+            pc: 0,
+            halted: false,
+            input: input.chars().map(|c| c as u16).collect(),
+            output: String::new(),
+        }
+    }
+
+    fn word(&self, addr: usize) -> Result<u16, Fault> {
+        self.memory.get(addr).copied().ok_or(Fault::OutOfMemory(addr))
+    }
+
+    /// Operand `k`, counting from 1, as a value.
+    fn value(&self, k: usize) -> Result<u16, Fault> {
+        match self.word(self.pc + k)? {
+            v @ 0..=32767 => Ok(v),
+            v @ 32768..=32775 => Ok(self.registers[v as usize - 32768]),
+            v => Err(Fault::BadOperand(v)),
+        }
+    }
+
+    /// Operand `k` as a register to write.
+    fn register(&self, k: usize) -> Result<usize, Fault> {
+        match self.word(self.pc + k)? {
+            v @ 32768..=32775 => Ok(v as usize - 32768),
+            v => Err(Fault::NotARegister(v)),
+        }
+    }
+
+    fn address(&self, k: usize) -> Result<usize, Fault> {
+        let addr = self.value(k)? as usize;
+        self.word(addr).map(|_| addr)
+    }
+
+    /// Runs one instruction, returning the memory address it wrote, if any.
+    /// Nothing changes when it faults.
+    pub fn step(&mut self) -> Result<Option<usize>, Fault> {
+        let op = self.word(self.pc)?;
+        let operands = *OPERANDS.get(op as usize).ok_or(Fault::BadOpcode(op))?;
+        let next = self.pc + 1 + operands;
+        self.word(next - 1)?;
+        let mut written = None;
+        match op {
+            0 => self.halted = true,
+            1 => {
+                let (a, b) = (self.register(1)?, self.value(2)?);
+                self.registers[a] = b;
+            },
+            2 => self.stack.push(self.value(1)?),
+            3 => {
+                let a = self.register(1)?;
+                self.registers[a] = self.stack.pop().ok_or(Fault::EmptyStack)?;
+            },
+            4 => self.compute(|b, c| Ok((b == c) as u32))?,
+            5 => self.compute(|b, c| Ok((b > c) as u32))?,
+            6 => {
+                self.pc = self.value(1)? as usize;
+                return Ok(None);
+            },
+            7 | 8 => {
+                let (a, b) = (self.value(1)?, self.value(2)?);
+                if (a != 0) == (op == 7) {
+                    self.pc = b as usize;
+                    return Ok(None);
+                }
+            },
+            9 => self.compute(|b, c| Ok((b + c) % 32768))?,
+            10 => self.compute(|b, c| Ok((b * c) % 32768))?,
+            11 => self.compute(|b, c| if c == 0 { Err(Fault::DivideByZero) } else { Ok(b % c) })?,
+            12 => self.compute(|b, c| Ok(b & c))?,
+            13 => self.compute(|b, c| Ok(b | c))?,
+            14 => {
+                let (a, b) = (self.register(1)?, self.value(2)?);
+                self.registers[a] = !b & 0x7fff;
+            },
+            15 => {
+                let (a, b) = (self.register(1)?, self.address(2)?);
+                self.registers[a] = self.memory[b];
+            },
+            16 => {
+                let (a, b) = (self.address(1)?, self.value(2)?);
+                self.memory[a] = b;
+                written = Some(a);
+            },
+            17 => {
+                let a = self.value(1)?;
+                self.stack.push(next as u16);
+                self.pc = a as usize;
+                return Ok(None);
+            },
+            18 => {
+                match self.stack.pop() {
+                    Some(a) => self.pc = a as usize,
+                    None => self.halted = true,
+                }
+                return Ok(None);
+            },
+            19 => {
+                let a = self.value(1)?;
+                if a > 255 {
+                    return Err(Fault::BadCharacter(a));
+                }
+                self.output.push(a as u8 as char);
+            },
+            20 => {
+                let a = self.register(1)?;
+                self.registers[a] = self.input.pop_front().ok_or(Fault::NoInput)?;
+            },
+            _ => {},
+        }
+        if !self.halted {
+            self.pc = next;
+        }
+        Ok(written)
+    }
+
+    /// `a = b <op> c` for the three-operand arithmetic and tests.
+    fn compute(&mut self, f: impl Fn(u32, u32) -> Result<u32, Fault>) -> Result<(), Fault> {
+        let (a, b, c) = (self.register(1)?, self.value(2)?, self.value(3)?);
+        self.registers[a] = f(b as u32, c as u32)? as u16;
+        Ok(())
+    }
+}
+
+/// Steps `vm` with `step` alongside the reference until the reference
+/// halts or faults, or `max_steps` run out.  Returns the steps run, or
+/// where the two first differ.
+pub fn lockstep(vm: &mut Vm, reference: &mut Reference, max_steps: usize, step: Stepper) -> Result<usize, String> {
+    vm.set_capture_output(true);
+    for n in 0..max_steps {
+        if reference.halted {
+            return Ok(n);
+        }
+        let pc = reference.pc;
+        let code = reference.memory.iter().skip(pc).take(4).copied().collect::<Vec<_>>();
+        let printed = reference.output.len();
+        let written = match reference.step() {
+            Ok(written) => written,
+            Err(fault) => {
+                debug!("Reference stopped at {}: {:?}", pc, fault);
+                return Ok(n);
+            },
+        };
+        step(vm);
+        let what = |thing: &str, vm: String, reference: String| {
+            Err(format!("Step {} at {} ({:?}): {} was {}, expected {}",
+                n + 1, pc, code, thing, vm, reference))
+        };
+        if vm.pc() != reference.pc {
+            return what("pc", vm.pc().to_string(), reference.pc.to_string());
+        }
+        if vm.registers() != reference.registers {
+            return what("registers", format!("{:?}", vm.registers()), format!("{:?}", reference.registers));
+        }
+        if vm.stack() != &reference.stack[..] {
+            return what("stack", format!("{:?}", vm.stack()), format!("{:?}", reference.stack));
+        }
+        if vm.is_stopped() != reference.halted {
+            return what("halted", vm.is_stopped().to_string(), reference.halted.to_string());
+        }
+        if let Some(a) = written {
+            if vm.memory()[a] != reference.memory[a] {
+                return what(&format!("memory[{}]", a), vm.memory()[a].to_string(), reference.memory[a].to_string());
+            }
+        }
+        let output = vm.take_output();
+        let expected = &reference.output[printed..];
+        if output != expected {
+            return what("output", format!("{:?}", output), format!("{:?}", expected));
+        }
+    }
+    if vm.memory() != &reference.memory[..] {
+        return Err("Memory differs at the end".to_string());
+    }
+    Ok(max_steps)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    const STEPPERS: [(&str, Stepper); 3] = [
+        ("execute_once", Vm::execute_once),
+        ("step", Vm::step),
+        // Both share memory, so what one writes the other must see
+        ("alternating", |vm| if vm.steps() & 1 == 0 { vm.execute_once() } else { vm.step() }),
+    ];
+
+    fn vm(memory: &[u16], input: &str) -> Vm {
+        let mut vm = Vm::from_words(memory);
+        vm.insert_buffer(input.to_string());
+        vm
+    }
+
+    #[test]
+    fn test_jumps_through_registers() {
+        let program = [
+            1, 32768, 1,           // 0: set R0 1
+            1, 32769, 12,          // 3: set R1 12
+            7, 32768, 32769,       // 6: jt R0 R1
+            0,                     // 9: halt
+            0, 0,
+            8, 32770, 32769,       // 12: jf R2 R1, looping back here
+        ];
+        for (name, step) in STEPPERS.iter() {
+            let mut reference = Reference::new(&program, "");
+            assert_eq!(lockstep(&mut vm(&program, ""), &mut reference, 20, *step), Ok(20), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_challenge_lockstep() {
+        let mut challenge = Vm::new(crate::util::get_file_as_byte_vec("challenge.bin"), 32768);
+        let memory = challenge.memory().to_vec();
+        let input = "take tablet\nuse tablet\ngo doorway\ngo north\ngo north\ngo bridge\n";
+        for (name, step) in STEPPERS.iter() {
+            challenge.reset();
+            challenge.insert_buffer(input.to_string());
+            let mut reference = Reference::new(&memory, input);
+            let steps = lockstep(&mut challenge, &mut reference, 5_000_000, *step);
+            assert!(steps.is_ok(), "{}: {:?}", name, steps);
+            assert!(reference.output.contains("You find yourself standing at the base of an enormous mountain"));
+            assert!(reference.input.is_empty());
+        }
+    }
+
+    /// A program of well-formed instructions, mostly on registers, with
+    /// literals kept to printable characters and addresses in the program.
+    fn random_program(rng: &mut StdRng, len: usize) -> Vec<u16> {
+        let mut program = Vec::new();
+        while program.len() < len {
+            let op = rng.gen_range(0, 22);
+            program.push(op);
+            for k in 0..OPERANDS[op as usize] {
+                let destination = k == 0 && matches!(op, 1 | 3..=5 | 9..=15 | 20);
+                let w = if destination || rng.gen_bool(0.5) {
+                    32768 + rng.gen_range(0, 8)
+                } else if rng.gen_bool(0.5) {
+                    rng.gen_range(0, len as u16)
+                } else {
+                    rng.gen_range(32, 127)
+                };
+                program.push(w);
+            }
+        }
+        program.resize(len + 4, 0);
+        program
+    }
+
+    #[test]
+    fn test_random_programs() {
+        let mut rng = StdRng::seed_from_u64(48);
+        for _ in 0..500 {
+            let program = random_program(&mut rng, 64);
+            let input: String = (0..16).map(|_| rng.gen_range(b'a', b'z') as char).collect();
+            for (name, step) in STEPPERS.iter() {
+                let mut reference = Reference::new(&program, &input);
+                let result = lockstep(&mut vm(&program, &input), &mut reference, 1000, *step);
+                assert!(result.is_ok(), "{} on {:?}: {:?}", name, program, result);
+            }
+        }
+    }
+}
@@ -0,0 +1,295 @@
+use log::{trace, debug, info, warn, error};
+use super::decode::{Decoded, Operand};
+use super::{InstructionCode, Vm, MAX_VAL};
+
+#[allow(dead_code)]
+fn _get_rid_of_log_unused_import_warnings() {
+    trace!("Example trace.");
+    debug!("Example debug.");
+    info!("Example info.");
+    warn!("Example warn.");
+    error!("Example error.");
+}
+
+/// Longest block formed, in instructions.  Also bounds how far back a write
+/// has to look for blocks covering it.
+const MAX_BLOCK_LEN: usize = 64;
+
+/// One or more fused instructions.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) enum Super {
+    Single(Decoded),
+    // EQ/GT into `dst` followed by JT/JF on `dst`
+    CmpJump { gt: bool, dst: u8, b: Operand, c: Operand, if_set: bool, target: Operand, next: u16 },
+    // PUSH `src` followed by POP into `dst`
+    Move { src: Operand, dst: u8, next: u16 },
+    // ADD with one literal operand
+    AddLit { dst: u8, src: Operand, lit: u16, next: u16 },
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Block {
+    ops: Vec<Super>,
+    end: usize,
+    steps: usize, // Instructions covered, counting fused ones separately
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct BlockCache {
+    slots: Vec<Option<Block>>,
+    covered: Vec<bool>, // Words that are, or once were, part of a cached block
+    dirty: bool,        // A cached block was dropped since the flag was cleared
+    running: (usize, usize), // Span of the block being executed, out of `slots`
+}
+
+impl BlockCache {
+    pub fn new(size: usize) -> BlockCache {
+        BlockCache {
+            slots: vec![None; size],
+            covered: vec![false; size],
+            dirty: false,
+            running: (0, 0),
+        }
+    }
+
+    /// Drops every block containing `addr`.
+    #[inline(always)]
+    pub fn invalidate(&mut self, addr: usize) {
+        if !self.covered[addr] {
+            return;
+        }
+        if self.running.0 <= addr && addr < self.running.1 {
+            self.dirty = true;
+        }
+        for start in addr.saturating_sub(MAX_BLOCK_LEN * 4)..=addr {
+            if matches!(&self.slots[start], Some(b) if b.end > addr) {
+                trace!("WMEM to {} dropped block at {}", addr, start);
+                self.slots[start] = None;
+                self.dirty = true;
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        for slot in self.slots.iter_mut() {
+            *slot = None;
+        }
+        for c in self.covered.iter_mut() {
+            *c = false;
+        }
+    }
+}
+
+fn fuse(ops: &[Decoded]) -> Vec<Super> {
+    let mut fused = Vec::with_capacity(ops.len());
+    let mut k = 0;
+    while k < ops.len() {
+        let d = ops[k];
+        let pair = ops.get(k + 1).map(|&e| (d, e));
+        let op = match pair {
+            Some((d, e)) if matches!(d.op, InstructionCode::EQ | InstructionCode::GT) &&
+                            matches!(e.op, InstructionCode::JT | InstructionCode::JF) &&
+                            matches!(d.a, Operand::Reg(_)) && e.a == d.a => {
+                k += 1;
+                let dst = match d.a { Operand::Reg(r) => r, Operand::Lit(_) => unreachable!() };
+                Super::CmpJump {
+                    gt: d.op == InstructionCode::GT,
+                    dst,
+                    b: d.b,
+                    c: d.c,
+                    if_set: e.op == InstructionCode::JT,
+                    target: e.b,
+                    next: e.next,
+                }
+            },
+            Some((d, e)) if d.op == InstructionCode::PUSH && e.op == InstructionCode::POP => {
+                match e.a {
+                    Operand::Reg(dst) => {
+                        k += 1;
+                        Super::Move { src: d.a, dst, next: e.next }
+                    },
+                    Operand::Lit(_) => Super::Single(d),
+                }
+            },
+            _ => match (d.op, d.a, d.b, d.c) {
+                (InstructionCode::ADD, Operand::Reg(dst), src, Operand::Lit(lit)) |
+                (InstructionCode::ADD, Operand::Reg(dst), Operand::Lit(lit), src) => {
+                    Super::AddLit { dst, src, lit, next: d.next }
+                },
+                _ => Super::Single(d),
+            },
+        };
+        fused.push(op);
+        k += 1;
+    }
+    fused
+}
+
+impl Vm {
+    /// Decodes straight-line code from pc up to the next control transfer.
+    /// IN is left out so the input check stays in one place.
+    fn form_block(&mut self) -> Option<Block> {
+        let mut ops = Vec::new();
+        let mut pc = self.pc;
+        while ops.len() < MAX_BLOCK_LEN {
+            let d = match self.cache.fetch(&self.memory, pc) {
+                Some(d) if d.op != InstructionCode::IN => d,
+                _ => break,
+            };
+            ops.push(d);
+            pc = d.next as usize;
+            if matches!(d.op, InstructionCode::HALT | InstructionCode::JMP | InstructionCode::JT |
+                              InstructionCode::JF | InstructionCode::CALL | InstructionCode::RET) {
+                break;
+            }
+        }
+        if ops.is_empty() {
+            return None;
+        }
+        for c in &mut self.blocks.covered[self.pc..pc] {
+            *c = true;
+        }
+        Some(Block {
+            steps: ops.len(),
+            ops: fuse(&ops),
+            end: pc,
+        })
+    }
+
+    /// Runs the block's ops, stopping early if one of them drops a block
+    /// or stops the VM.
+    fn execute_block(&mut self, block: &Block) {
+        for &op in &block.ops {
+            match op {
+                Super::Single(d) => {
+                    self.execute_decoded(d);
+                    if self.blocks.dirty || self.stopped {
+                        return;
+                    }
+                },
+                Super::CmpJump { gt, dst, b, c, if_set, target, next } => {
+                    let (b, c) = (self.val(b), self.val(c));
+                    let v = if gt { b > c } else { b == c };
+                    self.steps += 2;
+                    self.registers[dst as usize & 7] = v as u16;
+                    self.pc = if v == if_set { self.val(target) as usize } else { next as usize };
+                },
+                Super::Move { src, dst, next } => {
+                    self.steps += 2;
+                    self.registers[dst as usize & 7] = self.val(src);
+                    self.pc = next as usize;
+                },
+                Super::AddLit { dst, src, lit, next } => {
+                    self.steps += 1;
+                    self.registers[dst as usize & 7] = ((self.val(src) as u32 + lit as u32) % MAX_VAL as u32) as u16;
+                    self.pc = next as usize;
+                },
+            }
+        }
+    }
+
+    /// `run_until_input` on superinstruction blocks.  Steps are counted a
+    /// block at a time, so `max_steps` may be overshot by one block.
+    pub fn run_superblocks(&mut self, max_steps: usize) -> bool {
+        let mut steps = 0;
+        while steps < max_steps {
+            if self.stopped {
+                return true;
+            }
+            let pc = self.pc;
+            let block = self.blocks.slots.get_mut(pc).and_then(Option::take).or_else(|| self.form_block());
+            match block {
+                Some(block) => {
+                    self.blocks.dirty = false;
+                    self.blocks.running = (pc, block.end);
+                    self.execute_block(&block);
+                    steps += block.steps;
+                    self.blocks.running = (0, 0);
+                    if !self.blocks.dirty {
+                        self.blocks.slots[pc] = Some(block);
+                    }
+                },
+                None => {
+                    let d = match self.fetch() {
+                        Some(d) => d,
+                        None => return true,
+                    };
+                    if d.op == InstructionCode::IN && self.buffer.is_empty() {
+                        return true;
+                    }
+                    self.execute_decoded(d);
+                    steps += 1;
+                },
+            }
+        }
+        self.is_stopped() || self.needs_input()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(words: &[u16]) -> Vec<Decoded> {
+        let mut ops = Vec::new();
+        let mut pc = 0;
+        while pc < words.len() {
+            let d = Decoded::decode(words, pc).unwrap();
+            pc = d.next as usize;
+            ops.push(d);
+        }
+        ops
+    }
+
+    #[test]
+    fn test_fuse() {
+        let ops = decode(&[
+            4, 32769, 32768, 5,     // eq R1 R0 5
+            8, 32769, 20,           // jf R1 20
+            2, 32770,               // push R2
+            3, 32771,               // pop R3
+            9, 32768, 32768, 1,     // add R0 R0 1
+            9, 32768, 32768, 32769, // add R0 R0 R1
+        ]);
+
+        assert_eq!(fuse(&ops)[..3], [
+            Super::CmpJump {
+                gt: false, dst: 1, b: Operand::Reg(0), c: Operand::Lit(5),
+                if_set: false, target: Operand::Lit(20), next: 7,
+            },
+            Super::Move { src: Operand::Reg(2), dst: 3, next: 11 },
+            Super::AddLit { dst: 0, src: Operand::Reg(0), lit: 1, next: 15 },
+        ]);
+        assert!(matches!(fuse(&ops)[3], Super::Single(_)));
+    }
+
+    #[test]
+    fn test_superblocks_match_execute_once() {
+        let mut old = Vm::new(crate::util::get_file_as_byte_vec("challenge.bin"), 32768);
+        old.set_capture_output(true);
+        let mut new = old.clone();
+
+        while !old.is_stopped() && !old.needs_input() {
+            old.execute_once();
+        }
+        assert!(new.run_superblocks(10_000_000));
+
+        assert_eq!(new.take_output(), old.take_output());
+        assert_eq!(new.snapshot(), old.snapshot());
+    }
+
+    #[test]
+    fn test_block_stops_with_the_vm() {
+        let mut vm = Vm::from_words(&[
+            3, 32768,   // 0: pop R0, on an empty stack
+            19, 120,    // 2: out 'x'
+            0,          // 4: halt
+        ]);
+        vm.set_policy(crate::vm::ExecutionPolicy::Strict);
+        vm.set_capture_output(true);
+        assert!(vm.run_superblocks(100));
+
+        assert_eq!(vm.take_output(), "");
+        assert_eq!((vm.pc(), vm.violation()), (0, Some(&crate::vm::Violation::EmptyStack)));
+    }
+}